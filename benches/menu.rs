@@ -0,0 +1,68 @@
+//! Compares the tree-walking interpreter with the VM on `programs/menu.hd`.
+//!
+//! Run with `cargo bench --bench menu`.
+
+use std::{
+  hint::black_box,
+  time::{Duration, Instant},
+};
+
+use harald::compile_script;
+use rand::{rngs::StdRng, SeedableRng};
+
+const RUNS: u32 = 200_000;
+
+fn measure(name: &str, mut run: impl FnMut()) -> Duration {
+  // Warm up caches and the allocator before measuring
+  for _ in 0..RUNS / 10 {
+    run();
+  }
+
+  let start = Instant::now();
+
+  for _ in 0..RUNS {
+    run();
+  }
+
+  let elapsed = start.elapsed();
+  println!(
+    "{name:>12}: {:>8.0} ns/run ({RUNS} runs in {elapsed:.2?})",
+    elapsed.as_nanos() as f64 / RUNS as f64
+  );
+  elapsed
+}
+
+fn main() {
+  let script = compile_script(include_str!("../programs/menu.hd")).unwrap();
+  let program = script.lower();
+
+  let mut rng = StdRng::seed_from_u64(0);
+  let interpreter = measure("interpreter", || {
+    black_box(script.run_with_rng(&mut rng).unwrap());
+  });
+
+  let mut rng = StdRng::seed_from_u64(0);
+  let mut output = String::new();
+  measure("streamed", || {
+    output.clear();
+    script
+      .run_entry_into_with_rng("result", &mut rng, &mut output)
+      .unwrap();
+    black_box(&output);
+  });
+
+  let mut rng = StdRng::seed_from_u64(0);
+  let vm = measure("vm", || {
+    output.clear();
+    program
+      .run_entry_into("result", &mut rng, &mut output)
+      .unwrap();
+    black_box(&output);
+  });
+
+  println!(
+    "{:>12}: {:.2}x",
+    "speedup",
+    interpreter.as_secs_f64() / vm.as_secs_f64()
+  );
+}
@@ -0,0 +1,209 @@
+//! Choosing the English indefinite article, "a" or "an", for a word.
+//!
+//! Spelling only roughly predicts pronunciation, so words are first looked up in a list of
+//! exceptions: words which start with one of the listed prefixes take the listed article. Scripts
+//! extend the list by defining a table called [`EXCEPTIONS_VARIABLE`]. Other words take "an" if
+//! they start with a vowel, and acronyms and numbers take the article of their spoken form.
+
+/// Name of the global variable which scripts define to add exceptions.
+pub const EXCEPTIONS_VARIABLE: &str = "articleExceptions";
+
+/// Word prefixes whose article can't be guessed from their first letter.
+const DEFAULT_EXCEPTIONS: &[(&str, Article)] = &[
+  ("hour", Article::An),
+  ("honest", Article::An),
+  ("honor", Article::An),
+  ("honour", Article::An),
+  ("heir", Article::An),
+  ("uni", Article::A),
+  ("unid", Article::An),
+  ("unim", Article::An),
+  ("unin", Article::An),
+  ("use", Article::A),
+  ("usu", Article::A),
+  ("uti", Article::A),
+  ("ura", Article::A),
+  ("ure", Article::A),
+  ("uri", Article::A),
+  ("uro", Article::A),
+  ("ubiq", Article::A),
+  ("eu", Article::A),
+  ("ewe", Article::A),
+  ("one", Article::A),
+  ("once", Article::A),
+  ("oner", Article::An),
+];
+
+/// Letters whose names start with a vowel sound, like "eff" and "ess".
+const VOWEL_SOUND_LETTERS: &str = "AEFHILMNORSX";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Article {
+  A,
+  An,
+}
+
+impl Article {
+  pub fn try_parse(s: &str) -> Option<Article> {
+    match s {
+      "a" => Some(Article::A),
+      "an" => Some(Article::An),
+      _ => None,
+    }
+  }
+
+  pub fn as_str(self) -> &'static str {
+    match self {
+      Article::A => "a",
+      Article::An => "an",
+    }
+  }
+}
+
+/// Exceptions added by a script, which take precedence over the default ones.
+#[derive(Debug, Clone, Default)]
+pub struct Articles {
+  exceptions: Vec<(String, Article)>,
+}
+
+impl Articles {
+  /// Adds an exception for words starting with `prefix`, ignoring case.
+  pub fn add_exception(&mut self, prefix: &str, article: Article) {
+    self.exceptions.push((prefix.to_lowercase(), article));
+  }
+
+  /// The article to use before `text`. Only the first word is considered.
+  pub fn article(&self, text: &str) -> Article {
+    let word = text
+      .trim_start_matches(|ch: char| !ch.is_alphanumeric())
+      .split(|ch: char| !ch.is_alphanumeric())
+      .next()
+      .unwrap_or_default();
+    let lowercase = word.to_lowercase();
+
+    // The longest matching prefix wins, and the script's own exceptions win ties
+    let exception = self
+      .exceptions
+      .iter()
+      .map(|(prefix, article)| (prefix.as_str(), *article))
+      .chain(DEFAULT_EXCEPTIONS.iter().copied())
+      .filter(|(prefix, _)| lowercase.starts_with(prefix))
+      .fold(
+        None,
+        |longest: Option<(&str, Article)>, candidate| match longest {
+          Some(longest) if longest.0.len() >= candidate.0.len() => Some(longest),
+          _ => Some(candidate),
+        },
+      );
+
+    if let Some((_, article)) = exception {
+      return article;
+    }
+
+    let first = word.chars().next().unwrap_or_default();
+
+    let vowel_sound = if first.is_ascii_digit() {
+      spoken_number_starts_with_vowel(word)
+    } else if is_acronym(word) {
+      VOWEL_SOUND_LETTERS.contains(first)
+    } else {
+      lowercase.starts_with(['a', 'e', 'i', 'o', 'u'])
+    };
+
+    match vowel_sound {
+      true => Article::An,
+      false => Article::A,
+    }
+  }
+
+  /// Prefixes `text` with its article, like "an apple". Text without any words is returned as it
+  /// is.
+  pub fn with_article(&self, text: &str) -> String {
+    if !text.contains(char::is_alphanumeric) {
+      return text.to_string();
+    }
+
+    format!("{} {text}", self.article(text).as_str())
+  }
+}
+
+/// Words like "FBI", "R2" and the "X" of "X-wing", which are spelled out letter by letter.
+fn is_acronym(word: &str) -> bool {
+  word.starts_with(|ch: char| ch.is_ascii_uppercase())
+    && word
+      .chars()
+      .all(|ch| ch.is_ascii_uppercase() || ch.is_ascii_digit())
+}
+
+/// Whether a number is read starting with a vowel sound: "eight", "eleven" and "eighteen", also as
+/// the first group of a larger number like "eleven thousand".
+fn spoken_number_starts_with_vowel(word: &str) -> bool {
+  let digits: String = word.chars().take_while(char::is_ascii_digit).collect();
+
+  digits.starts_with('8')
+    || (digits.len() % 3 == 2 && (digits.starts_with("11") || digits.starts_with("18")))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Article, Articles};
+
+  fn with_article(text: &str) -> String {
+    Articles::default().with_article(text)
+  }
+
+  #[test]
+  fn vowels_and_consonants() {
+    assert_eq!(with_article("apple"), "an apple");
+    assert_eq!(with_article("Ewok"), "an Ewok");
+    assert_eq!(with_article("droid"), "a droid");
+    assert_eq!(with_article("umbrella"), "an umbrella");
+    assert_eq!(with_article("yellow sun"), "a yellow sun");
+  }
+
+  #[test]
+  fn default_exceptions() {
+    assert_eq!(with_article("hour"), "an hour");
+    assert_eq!(with_article("honest pilot"), "an honest pilot");
+    assert_eq!(with_article("university"), "a university");
+    assert_eq!(with_article("unimportant moon"), "an unimportant moon");
+    assert_eq!(with_article("European"), "a European");
+    assert_eq!(with_article("one-eyed smuggler"), "a one-eyed smuggler");
+    assert_eq!(with_article("onerous task"), "an onerous task");
+  }
+
+  #[test]
+  fn acronyms_and_numbers() {
+    assert_eq!(with_article("FBI agent"), "an FBI agent");
+    assert_eq!(with_article("UFO"), "a UFO");
+    assert_eq!(with_article("R2 unit"), "an R2 unit");
+    assert_eq!(with_article("X-wing"), "an X-wing");
+    assert_eq!(with_article("B-wing"), "a B-wing");
+    assert_eq!(with_article("8-bit droid"), "an 8-bit droid");
+    assert_eq!(with_article("11 year old"), "an 11 year old");
+    assert_eq!(
+      with_article("18000 credit bounty"),
+      "an 18000 credit bounty"
+    );
+    assert_eq!(with_article("180 credit bounty"), "a 180 credit bounty");
+    assert_eq!(with_article("1 credit bounty"), "a 1 credit bounty");
+  }
+
+  #[test]
+  fn script_exceptions_take_precedence() {
+    let mut articles = Articles::default();
+    articles.add_exception("Unicorn", Article::An);
+    articles.add_exception("herb", Article::An);
+
+    assert_eq!(articles.with_article("unicorn"), "an unicorn");
+    assert_eq!(articles.with_article("herbal tea"), "an herbal tea");
+    assert_eq!(articles.with_article("university"), "a university");
+  }
+
+  #[test]
+  fn text_without_words() {
+    assert_eq!(with_article(""), "");
+    assert_eq!(with_article(" - "), " - ");
+    assert_eq!(with_article("\"orange\""), "an \"orange\"");
+  }
+}
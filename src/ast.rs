@@ -1,14 +1,18 @@
+use crate::source::Span;
+
 #[derive(PartialEq, Debug, Clone)]
 pub struct BagEntry {
   pub weight: Option<f32>,
   pub value: Box<Expression>,
+  pub span: Span,
 }
 
 impl BagEntry {
   pub fn from_string(x: impl Into<String>) -> BagEntry {
     BagEntry {
       weight: None,
-      value: Box::new(Expression::LiteralE(x.into())),
+      value: Box::new(Expression::literal(x)),
+      span: Span::default(),
     }
   }
 
@@ -23,15 +27,43 @@ pub struct Bag {
   pub items: Vec<BagEntry>,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct Assignment {
   pub name: String,
+  pub sticky: bool,
+  /// Private variables can't be used by scripts which import the file they are defined in
+  pub private: bool,
   pub value: Box<Expression>,
+  /// Span of the variable name
+  pub span: Span,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct FunctionDefinition {
+  pub name: String,
+  pub parameters: Vec<String>,
+  /// Private functions can't be called by scripts which import the file they are defined in
+  pub private: bool,
+  pub body: Box<Expression>,
+  /// Span of the function name and parameter list
+  pub span: Span,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Import {
+  /// Path of the imported file, relative to the importing file
+  pub path: String,
+  /// Namespace the imported variables and functions are accessed through, if any
+  pub alias: Option<String>,
+  /// Span of the whole statement, without the semicolon
+  pub span: Span,
 }
 
 #[derive(PartialEq, Debug)]
 pub enum Statement {
   AssignmentS(Assignment),
+  FunctionS(FunctionDefinition),
+  ImportS(Import),
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -40,7 +72,33 @@ pub struct Pattern {
 }
 
 #[derive(PartialEq, Debug, Clone)]
-pub enum Expression {
+pub struct Expression {
+  pub kind: ExpressionKind,
+  pub span: Span,
+}
+
+impl Expression {
+  pub fn new(kind: ExpressionKind, span: Span) -> Expression {
+    Expression { kind, span }
+  }
+
+  pub fn literal(x: impl Into<String>) -> Expression {
+    Expression::new(ExpressionKind::LiteralE(x.into()), Span::default())
+  }
+
+  pub fn variable(x: impl Into<String>) -> Expression {
+    Expression::new(ExpressionKind::VariableE(x.into()), Span::default())
+  }
+}
+
+impl From<ExpressionKind> for Expression {
+  fn from(kind: ExpressionKind) -> Expression {
+    Expression::new(kind, Span::default())
+  }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum ExpressionKind {
   LiteralE(String),
   VariableE(String),
   BagE(Bag),
@@ -48,6 +106,7 @@ pub enum Expression {
   PropertyAccessE(Box<Expression>, String),
   TableE(Table),
   CallE(String, Vec<Expression>),
+  WithE(Vec<Assignment>, Box<Expression>),
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -61,10 +120,123 @@ pub enum TableEntry {
 pub struct TableRow {
   pub weight: Option<f32>,
   pub items: Vec<TableEntry>,
+  pub span: Span,
 }
 
 #[derive(PartialEq, Debug, Clone)]
 pub struct Table {
   pub columns: Vec<String>,
   pub rows: Vec<TableRow>,
+  /// Span of the header row, which holds the column names
+  pub header_span: Span,
+}
+
+/// Gives mutable access to every span in a syntax tree.
+pub trait VisitSpans {
+  fn visit_spans(&mut self, f: &mut dyn FnMut(&mut Span));
+}
+
+impl<T: VisitSpans> VisitSpans for Vec<T> {
+  fn visit_spans(&mut self, f: &mut dyn FnMut(&mut Span)) {
+    for item in self {
+      item.visit_spans(f);
+    }
+  }
+}
+
+impl<T: VisitSpans> VisitSpans for Box<T> {
+  fn visit_spans(&mut self, f: &mut dyn FnMut(&mut Span)) {
+    self.as_mut().visit_spans(f);
+  }
+}
+
+impl VisitSpans for Statement {
+  fn visit_spans(&mut self, f: &mut dyn FnMut(&mut Span)) {
+    match self {
+      Statement::AssignmentS(assignment) => assignment.visit_spans(f),
+      Statement::FunctionS(definition) => definition.visit_spans(f),
+      Statement::ImportS(import) => import.visit_spans(f),
+    }
+  }
+}
+
+impl VisitSpans for Assignment {
+  fn visit_spans(&mut self, f: &mut dyn FnMut(&mut Span)) {
+    f(&mut self.span);
+    self.value.visit_spans(f);
+  }
+}
+
+impl VisitSpans for FunctionDefinition {
+  fn visit_spans(&mut self, f: &mut dyn FnMut(&mut Span)) {
+    f(&mut self.span);
+    self.body.visit_spans(f);
+  }
+}
+
+impl VisitSpans for Import {
+  fn visit_spans(&mut self, f: &mut dyn FnMut(&mut Span)) {
+    f(&mut self.span);
+  }
+}
+
+impl VisitSpans for BagEntry {
+  fn visit_spans(&mut self, f: &mut dyn FnMut(&mut Span)) {
+    f(&mut self.span);
+    self.value.visit_spans(f);
+  }
+}
+
+impl VisitSpans for Bag {
+  fn visit_spans(&mut self, f: &mut dyn FnMut(&mut Span)) {
+    self.items.visit_spans(f);
+  }
+}
+
+impl VisitSpans for Pattern {
+  fn visit_spans(&mut self, f: &mut dyn FnMut(&mut Span)) {
+    self.parts.visit_spans(f);
+  }
+}
+
+impl VisitSpans for TableEntry {
+  fn visit_spans(&mut self, f: &mut dyn FnMut(&mut Span)) {
+    match self {
+      TableEntry::Hole => {}
+      TableEntry::Literal(expression) | TableEntry::Append(expression) => expression.visit_spans(f),
+    }
+  }
+}
+
+impl VisitSpans for TableRow {
+  fn visit_spans(&mut self, f: &mut dyn FnMut(&mut Span)) {
+    f(&mut self.span);
+    self.items.visit_spans(f);
+  }
+}
+
+impl VisitSpans for Table {
+  fn visit_spans(&mut self, f: &mut dyn FnMut(&mut Span)) {
+    f(&mut self.header_span);
+    self.rows.visit_spans(f);
+  }
+}
+
+impl VisitSpans for Expression {
+  fn visit_spans(&mut self, f: &mut dyn FnMut(&mut Span)) {
+    f(&mut self.span);
+
+    match &mut self.kind {
+      ExpressionKind::LiteralE(_) | ExpressionKind::VariableE(_) => {}
+      ExpressionKind::BagE(bag) => bag.visit_spans(f),
+      ExpressionKind::PatternE(pattern) => pattern.visit_spans(f),
+      ExpressionKind::PropertyAccessE(expression, _) => expression.visit_spans(f),
+      ExpressionKind::TableE(table) => table.visit_spans(f),
+      ExpressionKind::CallE(_, arguments) => arguments.visit_spans(f),
+      ExpressionKind::WithE(bindings, body) => {
+        bindings.visit_spans(f);
+        body.visit_spans(f);
+      }
+    }
+  }
 }
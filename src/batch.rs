@@ -0,0 +1,343 @@
+//! Generating many outputs at once, split across threads.
+//!
+//! A batch is divided into chunks of [`CHUNK_SIZE`] outputs, and every chunk gets its own random
+//! number generator seeded from the batch seed. Worker threads take chunks in any order, but each
+//! chunk always produces the same outputs, so a seeded batch is the same no matter how many threads
+//! generate it. The first chunk is seeded with the batch seed itself, so the first output of a
+//! batch is the output of [`CompiledScript::run_with_seed`] with the same seed.
+
+use std::{
+  fmt,
+  sync::atomic::{AtomicUsize, Ordering},
+  thread,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+  eval::{CompiledScript, InterpreterError},
+  trace::Trace,
+  vm::Program,
+};
+
+/// Number of outputs generated with a single random number generator.
+pub const CHUNK_SIZE: usize = 1024;
+
+impl Program {
+  /// Generates `n` outputs of `result` using up to `threads` threads.
+  pub fn generate_batch(&self, n: usize, threads: usize) -> Result<Vec<String>, InterpreterError> {
+    self.generate_entry_batch("result", n, threads, None)
+  }
+
+  /// Generates `n` outputs of `result` using up to `threads` threads. The outputs depend only on
+  /// the seed, not on the number of threads.
+  pub fn generate_batch_with_seed(
+    &self,
+    n: usize,
+    threads: usize,
+    seed: u64,
+  ) -> Result<Vec<String>, InterpreterError> {
+    self.generate_entry_batch("result", n, threads, Some(seed))
+  }
+
+  /// Generates `n` outputs of the entry point `name` using up to `threads` threads, seeded from
+  /// entropy when `seed` is `None`. If any run fails, the error of the earliest failing chunk is
+  /// returned, and chunks after it aren't generated.
+  pub fn generate_entry_batch(
+    &self,
+    name: &str,
+    n: usize,
+    threads: usize,
+    seed: Option<u64>,
+  ) -> Result<Vec<String>, InterpreterError> {
+    let chunks = chunks(n, seed);
+    let next = AtomicUsize::new(0);
+    // Chunks are taken in order, so every chunk before the first failure is still generated and
+    // the same error is returned no matter how many threads there are
+    let first_failure = AtomicUsize::new(usize::MAX);
+    let generate_chunks = || {
+      let mut results = Vec::new();
+
+      loop {
+        let index = next.fetch_add(1, Ordering::Relaxed);
+        let Some((count, seed)) = chunks.get(index) else {
+          return results;
+        };
+
+        if index > first_failure.load(Ordering::Relaxed) {
+          return results;
+        }
+
+        let chunk = self.generate_chunk(name, *count, *seed);
+
+        if chunk.is_err() {
+          first_failure.fetch_min(index, Ordering::Relaxed);
+        }
+
+        results.push((index, chunk));
+      }
+    };
+
+    let threads = threads.clamp(1, chunks.len().max(1));
+    let mut results = if threads == 1 {
+      generate_chunks()
+    } else {
+      thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads).map(|_| scope.spawn(generate_chunks)).collect();
+
+        workers
+          .into_iter()
+          .flat_map(|worker| worker.join().expect("batch worker panicked"))
+          .collect()
+      })
+    };
+
+    results.sort_by_key(|(index, _)| *index);
+
+    let mut outputs = Vec::with_capacity(n);
+
+    for (_, chunk) in results {
+      outputs.extend(chunk?);
+    }
+
+    Ok(outputs)
+  }
+
+  /// Writes `n` outputs of the entry point `name` into `out` as they are generated, each followed
+  /// by a newline. Produces the same outputs as [`Program::generate_entry_batch`] with the same
+  /// seed, but on a single thread and without holding them in memory.
+  pub fn write_entry_batch(
+    &self,
+    name: &str,
+    n: usize,
+    seed: Option<u64>,
+    out: &mut impl fmt::Write,
+  ) -> Result<(), InterpreterError> {
+    for (count, seed) in chunks(n, seed) {
+      let mut rng = StdRng::seed_from_u64(seed);
+
+      for _ in 0..count {
+        self.run_entry_into(name, &mut rng, out)?;
+        out.write_char('\n')?;
+      }
+    }
+
+    Ok(())
+  }
+
+  fn generate_chunk(
+    &self,
+    name: &str,
+    count: usize,
+    seed: u64,
+  ) -> Result<Vec<String>, InterpreterError> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..count)
+      .map(|_| self.run_entry_with_rng(name, &mut rng))
+      .collect()
+  }
+}
+
+/// Splits a batch of `n` outputs into chunks, returning the size and seed of each one. The first
+/// chunk uses the batch seed, and the others use seeds drawn from a generator seeded with it.
+fn chunks(n: usize, seed: Option<u64>) -> Vec<(usize, u64)> {
+  let seed = seed.unwrap_or_else(|| StdRng::from_entropy().gen());
+  let mut seeds = StdRng::seed_from_u64(seed);
+
+  (0..n)
+    .step_by(CHUNK_SIZE)
+    .map(|start| {
+      let chunk_seed = match start {
+        0 => seed,
+        _ => seeds.gen(),
+      };
+
+      (CHUNK_SIZE.min(n - start), chunk_seed)
+    })
+    .collect()
+}
+
+impl CompiledScript {
+  /// Generates `n` outputs of `result` using up to `threads` threads. See
+  /// [`Program::generate_batch`].
+  pub fn generate_batch(&self, n: usize, threads: usize) -> Result<Vec<String>, InterpreterError> {
+    self.program().generate_batch(n, threads)
+  }
+
+  /// Generates `n` outputs of `result` using up to `threads` threads. The outputs depend only on
+  /// the seed, not on the number of threads.
+  pub fn generate_batch_with_seed(
+    &self,
+    n: usize,
+    threads: usize,
+    seed: u64,
+  ) -> Result<Vec<String>, InterpreterError> {
+    self.program().generate_batch_with_seed(n, threads, seed)
+  }
+
+  /// Runs the entry point `name` `n` times on a single thread, recording a trace of each run. The
+  /// outputs are the same as those of [`Program::generate_entry_batch`] with the same seed.
+  pub fn trace_entry_batch(
+    &self,
+    name: &str,
+    n: usize,
+    seed: Option<u64>,
+  ) -> Result<Vec<Trace>, InterpreterError> {
+    let mut traces = Vec::with_capacity(n);
+
+    for (count, seed) in chunks(n, seed) {
+      let mut rng = StdRng::seed_from_u64(seed);
+
+      for _ in 0..count {
+        traces.push(self.run_entry_traced_with_rng(name, &mut rng)?);
+      }
+    }
+
+    Ok(traces)
+  }
+
+  /// Generates `n` outputs of the entry point `name` using up to `threads` threads. See
+  /// [`Program::generate_entry_batch`].
+  pub fn generate_entry_batch(
+    &self,
+    name: &str,
+    n: usize,
+    threads: usize,
+    seed: Option<u64>,
+  ) -> Result<Vec<String>, InterpreterError> {
+    self.program().generate_entry_batch(name, n, threads, seed)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::*;
+  use crate::compile_script;
+
+  fn assert_send_sync<T: Send + Sync>() {}
+
+  #[test]
+  fn scripts_are_thread_safe() {
+    assert_send_sync::<CompiledScript>();
+    assert_send_sync::<Program>();
+    assert_send_sync::<InterpreterError>();
+  }
+
+  #[test]
+  fn seeded_batches_ignore_thread_count() {
+    let script = compile_script(r#"result = { bag ["a", "b", "c"] bag ["1", "2"] };"#).unwrap();
+    let n = CHUNK_SIZE * 3 + 17;
+
+    let single = script.generate_batch_with_seed(n, 1, 5).unwrap();
+    let parallel = script.generate_batch_with_seed(n, 4, 5).unwrap();
+
+    assert_eq!(single.len(), n);
+    assert_eq!(single, parallel);
+    assert_ne!(single, script.generate_batch_with_seed(n, 4, 6).unwrap());
+  }
+
+  #[test]
+  fn shared_script_runs_on_many_threads() {
+    let script = Arc::new(compile_script(r#"result = bag ["a", "b"];"#).unwrap());
+
+    let workers: Vec<_> = (0..4)
+      .map(|seed| {
+        let script = Arc::clone(&script);
+        thread::spawn(move || script.run_with_seed(seed).unwrap())
+      })
+      .collect();
+
+    for worker in workers {
+      assert!(["a", "b"].contains(&worker.join().unwrap().as_str()));
+    }
+  }
+
+  #[test]
+  fn written_batch_matches_generated_batch() {
+    let program = compile_script(r#"result = { bag ["a", "b"] bag ["1", "2"] };"#)
+      .unwrap()
+      .lower();
+    let n = CHUNK_SIZE + 3;
+
+    let mut written = String::new();
+    program
+      .write_entry_batch("result", n, Some(9), &mut written)
+      .unwrap();
+
+    let generated = program
+      .generate_entry_batch("result", n, 2, Some(9))
+      .unwrap();
+    assert_eq!(written.lines().collect::<Vec<_>>(), generated);
+  }
+
+  #[test]
+  fn batches_start_with_the_seeded_run() {
+    let script =
+      compile_script(r#"result = { bag ["a", "b", "c"] bag ["1", "2", "3"] };"#).unwrap();
+
+    for seed in 0..10 {
+      let first = script.run_with_seed(seed).unwrap();
+      assert_eq!(
+        script.generate_batch_with_seed(3, 1, seed).unwrap()[0],
+        first
+      );
+    }
+  }
+
+  #[test]
+  fn traced_batch_matches_generated_batch() {
+    let script = compile_script(r#"result = { bag ["a", "b"] bag ["1", "2"] };"#).unwrap();
+    let n = CHUNK_SIZE + 3;
+
+    let traced: Vec<_> = script
+      .trace_entry_batch("result", n, Some(4))
+      .unwrap()
+      .into_iter()
+      .map(|trace| trace.output)
+      .collect();
+    assert_eq!(traced, script.generate_batch_with_seed(n, 2, 4).unwrap());
+  }
+
+  #[test]
+  fn scripts_are_lowered_once() {
+    let mut script = compile_script(r#"result = "a";"#).unwrap();
+    let program: *const Program = script.program();
+
+    script.generate_batch(10, 2).unwrap();
+    assert!(std::ptr::eq(program, script.program()));
+
+    let (_, statement) = crate::parser::parse_statement(r#"result = "b";"#).unwrap();
+    script.add_statement(statement).unwrap();
+    assert_eq!(script.generate_batch(3, 2).unwrap(), vec!["b"; 3]);
+  }
+
+  #[test]
+  fn failing_batches_return_the_earliest_error() {
+    // Fails in about one run in 2000 with a random message, so a few chunks succeed first and
+    // later chunks would fail differently
+    let count = r#"{ bag ["x", "y", "z"] bag ["1", "2", "3"] bag ["1", "2", "3"] }"#;
+    let source = format!(r#"result = bag [1999 "a", repeat("b", {count})];"#);
+    let program = compile_script(&source).unwrap().lower();
+    let n = CHUNK_SIZE * 64;
+
+    let expected = program
+      .write_entry_batch("result", n, Some(3), &mut String::new())
+      .unwrap_err();
+
+    for threads in [1, 8] {
+      let error = program
+        .generate_entry_batch("result", n, threads, Some(3))
+        .unwrap_err();
+      assert_eq!(format!("{expected:?}"), format!("{error:?}"));
+    }
+  }
+
+  #[test]
+  fn batch_reports_errors() {
+    let script = compile_script(r#"title(t) = t.title; result = title("Ada");"#).unwrap();
+    assert!(script.generate_batch(10, 2).is_err());
+    assert!(script.generate_batch(0, 2).unwrap().is_empty());
+  }
+}
@@ -0,0 +1,276 @@
+//! Exhaustive enumeration of every output a script can produce.
+//!
+//! Outputs are found by running the script repeatedly, making a different sequence of choices each
+//! time. The first run always picks the first entry of every bag and the first row of every table,
+//! and each following run changes the last choice which still has untried options, like an
+//! odometer. Because the choices go through the interpreter, sticky variables, `with` bindings and
+//! rows behave exactly like they do when the script is run normally.
+
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::eval::{CompiledScript, InterpreterError};
+
+/// Number of ways of producing an output [`CompiledScript::distribution`] tries before giving up.
+pub const DEFAULT_LIMIT: usize = 1_000_000;
+
+/// A string a script can produce, and how likely it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+  pub text: String,
+  pub probability: f64,
+}
+
+#[derive(Error, Debug)]
+pub enum EnumerationError {
+  #[error("{} is recursive, so it can produce infinitely many outputs: {}", .cycle[0], .cycle.join(" -> "))]
+  Recursive { cycle: Vec<String> },
+  #[error("The script has more than {limit} ways of producing an output")]
+  TooManyDerivations { limit: usize },
+  #[error(transparent)]
+  Interpreter(#[from] InterpreterError),
+}
+
+/// A choice made during a single run.
+#[derive(Debug)]
+struct Choice<'a> {
+  index: usize,
+  weights: &'a [f32],
+}
+
+/// Makes the choices of a run by replaying a prefix of choices from an earlier run, and picking the
+/// first possible option for every choice after that.
+#[derive(Debug, Default)]
+pub(crate) struct Replay<'a> {
+  prefix: Vec<usize>,
+  trace: Vec<Choice<'a>>,
+}
+
+impl<'a> Replay<'a> {
+  pub(crate) fn choose(&mut self, weights: &'a [f32]) -> usize {
+    let index = match self.prefix.get(self.trace.len()) {
+      Some(index) => *index,
+      None => next_option(weights, 0).expect("distributions have a non-zero weight"),
+    };
+
+    self.trace.push(Choice { index, weights });
+    index
+  }
+
+  /// Probability of making every choice of the last run.
+  fn probability(&self) -> f64 {
+    self
+      .trace
+      .iter()
+      .map(|choice| {
+        let total: f64 = choice.weights.iter().map(|weight| *weight as f64).sum();
+        choice.weights[choice.index] as f64 / total
+      })
+      .product()
+  }
+
+  /// Prepares the choices of the next run. Returns `false` if every combination has been tried.
+  fn advance(&mut self) -> bool {
+    let trace = std::mem::take(&mut self.trace);
+
+    for (i, choice) in trace.iter().enumerate().rev() {
+      if let Some(next) = next_option(choice.weights, choice.index + 1) {
+        self.prefix.clear();
+        self
+          .prefix
+          .extend(trace[..i].iter().map(|choice| choice.index));
+        self.prefix.push(next);
+        return true;
+      }
+    }
+
+    false
+  }
+}
+
+/// Finds the first option starting from `start` which can be chosen. Options with zero weight are
+/// never chosen.
+fn next_option(weights: &[f32], start: usize) -> Option<usize> {
+  (start..weights.len()).find(|index| weights[*index] > 0.0)
+}
+
+/// Iterator over the ways an entry point can produce an output, created by
+/// [`CompiledScript::derivations`].
+pub struct Derivations<'a> {
+  script: &'a CompiledScript,
+  entry: String,
+  replay: Replay<'a>,
+  done: bool,
+}
+
+impl<'a> Iterator for Derivations<'a> {
+  type Item = Result<Outcome, InterpreterError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.done {
+      return None;
+    }
+
+    let text = match self
+      .script
+      .run_entry_with_replay(&self.entry, &mut self.replay)
+    {
+      Ok(text) => text,
+      Err(error) => {
+        self.done = true;
+        return Some(Err(error));
+      }
+    };
+
+    let probability = self.replay.probability();
+    self.done = !self.replay.advance();
+
+    Some(Ok(Outcome { text, probability }))
+  }
+}
+
+impl CompiledScript {
+  /// Lazily enumerates every way the global variable `name` can produce an output, along with its
+  /// exact probability. A string which can be produced in several ways (such as `"ab"` from
+  /// `{ bag ["a", "ab"] bag ["b", ""] }`) is returned once for each of them. Use
+  /// [`CompiledScript::distribution`] to merge them.
+  ///
+  /// Entries which refer to themselves, directly or through other variables or functions, are
+  /// refused because they can produce infinitely many outputs.
+  pub fn derivations(&self, name: &str) -> Result<Derivations<'_>, EnumerationError> {
+    if let Some(cycle) = self.find_recursion(name) {
+      return Err(EnumerationError::Recursive { cycle });
+    }
+
+    Ok(Derivations {
+      script: self,
+      entry: name.to_string(),
+      replay: Replay::default(),
+      done: false,
+    })
+  }
+
+  /// Every distinct output of the global variable `name` with its exact probability, the most likely
+  /// first. Outputs with the same probability are ordered alphabetically. Gives up if there are
+  /// more than [`DEFAULT_LIMIT`] ways of producing an output.
+  pub fn distribution(&self, name: &str) -> Result<Vec<Outcome>, EnumerationError> {
+    self.distribution_with_limit(name, DEFAULT_LIMIT)
+  }
+
+  /// Like [`CompiledScript::distribution`], but gives up once there are more than `limit` ways of
+  /// producing an output. Every one of them has to be tried before any probability is known,
+  /// since the last one could produce the same string as the first.
+  pub fn distribution_with_limit(
+    &self,
+    name: &str,
+    limit: usize,
+  ) -> Result<Vec<Outcome>, EnumerationError> {
+    let mut probabilities: HashMap<String, f64> = HashMap::new();
+
+    for (i, outcome) in self.derivations(name)?.enumerate() {
+      if i == limit {
+        return Err(EnumerationError::TooManyDerivations { limit });
+      }
+
+      let outcome = outcome?;
+      *probabilities.entry(outcome.text).or_default() += outcome.probability;
+    }
+
+    let mut outcomes: Vec<_> = probabilities
+      .into_iter()
+      .map(|(text, probability)| Outcome { text, probability })
+      .collect();
+
+    outcomes.sort_by(|a, b| {
+      b.probability
+        .total_cmp(&a.probability)
+        .then_with(|| a.text.cmp(&b.text))
+    });
+
+    Ok(outcomes)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::compile_script;
+
+  fn distribution(source: &str) -> Vec<(String, f64)> {
+    compile_script(source)
+      .unwrap()
+      .distribution("result")
+      .unwrap()
+      .into_iter()
+      .map(|outcome| (outcome.text, outcome.probability))
+      .collect()
+  }
+
+  #[test]
+  fn enumerates_weighted_bag() {
+    assert_eq!(
+      distribution(r#"result = bag [ 3 "a", "b", 0 "never" ];"#),
+      vec![("a".to_string(), 0.75), ("b".to_string(), 0.25)]
+    );
+  }
+
+  #[test]
+  fn merges_identical_outputs() {
+    assert_eq!(
+      distribution(r#"result = { bag ["a", "ab"] bag ["b", ""] };"#),
+      vec![
+        ("ab".to_string(), 0.5),
+        ("a".to_string(), 0.25),
+        ("abb".to_string(), 0.25)
+      ]
+    );
+  }
+
+  #[test]
+  fn derivations_keep_identical_outputs_apart() {
+    let script = compile_script(r#"result = { bag ["a", "ab"] bag ["b", ""] };"#).unwrap();
+    let texts: Vec<_> = script
+      .derivations("result")
+      .unwrap()
+      .map(|outcome| outcome.unwrap().text)
+      .collect();
+
+    assert_eq!(texts, vec!["ab", "a", "abb", "ab"]);
+  }
+
+  #[test]
+  fn gives_up_after_the_limit() {
+    let script = compile_script(r#"result = { bag ["a", "b"] bag ["c", "d"] };"#).unwrap();
+
+    assert_eq!(
+      script.distribution_with_limit("result", 4).unwrap().len(),
+      4
+    );
+    assert!(matches!(
+      script.distribution_with_limit("result", 3),
+      Err(EnumerationError::TooManyDerivations { limit: 3 })
+    ));
+  }
+
+  #[test]
+  fn sticky_variables_are_chosen_once() {
+    let outcomes = distribution(r#"sticky x = bag ["a", "b"]; result = { x x };"#);
+    assert_eq!(
+      outcomes,
+      vec![("aa".to_string(), 0.5), ("bb".to_string(), 0.5)]
+    );
+  }
+
+  #[test]
+  fn refuses_recursion() {
+    let script = compile_script(r#"result = { "a" more }; more = bag [ "", result ];"#).unwrap();
+
+    match script.derivations("result") {
+      Err(EnumerationError::Recursive { cycle }) => {
+        assert_eq!(cycle, vec!["result", "more", "result"])
+      }
+      _ => panic!("expected recursion to be refused"),
+    }
+  }
+}
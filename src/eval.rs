@@ -1,22 +1,41 @@
-use crate::{ast, string_utils};
-use rand::distributions::WeightedError;
+use crate::{
+  articles::{self, Article, Articles},
+  ast,
+  enumerate::Replay,
+  loader::ImportError,
+  parser::ParseError,
+  source::{render_message, Span},
+  string_utils,
+  trace::{ChoiceKind, Trace, TraceBuilder, TraceNode},
+  vm::Program,
+};
+use rand::{distributions::WeightedError, rngs::StdRng, Rng, RngCore, SeedableRng};
 use rand_distr::{Distribution, WeightedIndex};
-use std::{borrow::Cow, collections::HashMap, fmt::Display};
+use std::{
+  borrow::Cow,
+  collections::{hash_map::Entry, HashMap, HashSet},
+  fmt,
+  fmt::Display,
+  rc::Rc,
+  sync::OnceLock,
+};
 use thiserror::Error;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Value<'a> {
   StringV(Cow<'a, str>),
-  BagV(&'a Bag),
-  TableV(&'a Table),
+  BagV(&'a Bag, Scope<'a>),
+  TableV(&'a Table, Scope<'a>),
+  RowV(&'a Table, usize, Scope<'a>),
 }
 
 impl<'a> Value<'a> {
   pub fn get_type_name(&self) -> &'static str {
     match self {
       Value::StringV(_) => "string",
-      Value::BagV(_) => "bag",
-      Value::TableV(_) => "table",
+      Value::BagV(..) => "bag",
+      Value::TableV(..) => "table",
+      Value::RowV(..) => "row",
     }
   }
 
@@ -35,88 +54,441 @@ impl<'a> Display for Value<'a> {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
       Value::StringV(v) => f.write_str(v),
-      Value::BagV(bag) => {
+      Value::BagV(bag, _) => {
         if let Some(name_hint) = &bag.name_hint {
           write!(f, "bag ({:?})", name_hint)
         } else {
           write!(f, "bag (anonymous)")
         }
       }
-      Value::TableV(table) => {
+      Value::TableV(table, _) => {
         if let Some(name_hint) = &table.name_hint {
           write!(f, "table ({:?})", name_hint)
         } else {
           write!(f, "table (anonymous)")
         }
       }
+      Value::RowV(table, row, _) => {
+        if let Some(name_hint) = &table.name_hint {
+          write!(f, "row #{} of table ({:?})", row, name_hint)
+        } else {
+          write!(f, "row #{} of table (anonymous)", row)
+        }
+      }
+    }
+  }
+}
+
+/// Local variables introduced by `with` expressions. Bags and tables capture
+/// the scope they were created in, so that their items can refer to locals
+/// even when they are sampled somewhere else.
+#[derive(Debug, Clone, Default)]
+pub struct Scope<'a>(Option<Rc<Binding<'a>>>);
+
+#[derive(Debug)]
+struct Binding<'a> {
+  name: &'a str,
+  value: Value<'a>,
+  parent: Scope<'a>,
+}
+
+impl<'a> Scope<'a> {
+  fn bind(&self, name: &'a str, value: Value<'a>) -> Scope<'a> {
+    Scope(Some(Rc::new(Binding {
+      name,
+      value,
+      parent: self.clone(),
+    })))
+  }
+
+  fn lookup(&self, name: &str) -> Option<&Value<'a>> {
+    let mut current = self.0.as_deref();
+
+    while let Some(binding) = current {
+      if binding.name == name {
+        return Some(&binding.value);
+      }
+
+      current = binding.parent.0.as_deref();
+    }
+
+    None
+  }
+}
+
+/// Evaluation state which lives for the duration of a single run.
+struct RunState<'a, 'r> {
+  /// Values of sticky global variables that have been evaluated during this run
+  sticky: HashMap<&'a str, Value<'a>>,
+  /// Makes every choice during this run
+  chooser: Chooser<'a, 'r>,
+  /// Records the choices, if the run is traced
+  trace: Option<TraceBuilder>,
+  /// Number of user defined functions being called
+  depth: usize,
+}
+
+/// How a run picks entries from bags and rows from tables.
+enum Chooser<'a, 'r> {
+  Random(&'r mut dyn RngCore),
+  Replay(&'r mut Replay<'a>),
+}
+
+impl<'a, 'r> RunState<'a, 'r> {
+  fn new(rng: &'r mut dyn RngCore) -> Self {
+    Self::with_chooser(Chooser::Random(rng))
+  }
+
+  fn with_chooser(chooser: Chooser<'a, 'r>) -> Self {
+    RunState {
+      sticky: HashMap::new(),
+      chooser,
+      trace: None,
+      depth: 0,
+    }
+  }
+
+  /// Counts a call to the function `name`, failing if too many calls are already in progress.
+  /// Every successful call must be followed by [`RunState::leave_call`].
+  fn enter_call(&mut self, name: &str) -> Result<(), InterpreterError> {
+    if self.depth >= MAX_CALL_DEPTH {
+      return Err(InterpreterError::RecursionLimit {
+        function: name.to_string(),
+        limit: MAX_CALL_DEPTH,
+      });
+    }
+
+    self.depth += 1;
+    Ok(())
+  }
+
+  fn leave_call(&mut self) {
+    self.depth -= 1;
+  }
+
+  fn open_choice(
+    &mut self,
+    name_hint: &Option<NameHint>,
+    column: &Option<String>,
+    kind: ChoiceKind,
+    index: usize,
+    weight: f32,
+  ) {
+    if let Some(trace) = &mut self.trace {
+      trace.open(TraceNode {
+        variable: name_hint.get_name_or_default(),
+        column: column.clone(),
+        kind,
+        index,
+        weight,
+        text: String::new(),
+        children: Vec::new(),
+      });
+    }
+  }
+
+  fn close_choice(&mut self, text: &str) {
+    if let Some(trace) = &mut self.trace {
+      trace.close(text);
+    }
+  }
+
+  fn choose(&mut self, distribution: &WeightedIndex<f32>, weights: &'a [f32]) -> usize {
+    match &mut self.chooser {
+      Chooser::Random(rng) => distribution.sample(rng),
+      Chooser::Replay(replay) => replay.choose(weights),
     }
   }
 }
 
 #[derive(Debug, Clone)]
 pub struct Pattern {
-  parts: Vec<Expression>,
+  pub(crate) parts: Vec<Expression>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Table {
   name_hint: Option<NameHint>,
-  bags: HashMap<String, Bag>,
+  pub(crate) columns: Vec<String>,
+  pub(crate) bags: HashMap<String, Bag>,
+  /// Row-major copy of the table, where holes are `None`. Used for sampling
+  /// whole rows.
+  pub(crate) rows: Vec<Vec<Option<Expression>>>,
+  /// First cell of each row which has append entries, unless it's a literal. It's evaluated once
+  /// when the row is sampled, and the cells of the row refer to the result as [`ROW_BASE`].
+  pub(crate) bases: Vec<Option<Expression>>,
+  pub(crate) distribution: WeightedIndex<f32>,
+  /// Row weights the distribution was built from
+  pub(crate) weights: Vec<f32>,
 }
 
+/// Name of the local variable holding the base of a sampled row. It isn't a valid identifier, so
+/// scripts can't refer to it.
+pub(crate) const ROW_BASE: &str = "@base";
+
 #[derive(Debug, Clone)]
 pub struct Bag {
   #[allow(dead_code)]
   id: usize,
   name_hint: Option<NameHint>,
-  items: Vec<Expression>,
-  distribution: WeightedIndex<f32>,
+  /// Column the bag was extracted from, if it belongs to a table
+  column: Option<String>,
+  pub(crate) items: Vec<Expression>,
+  pub(crate) distribution: WeightedIndex<f32>,
+  /// Item weights the distribution was built from
+  pub(crate) weights: Vec<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Expression {
+  pub(crate) kind: ExpressionKind,
+  pub(crate) span: Span,
 }
 
 #[derive(Debug, Clone)]
-pub enum Expression {
+pub enum ExpressionKind {
   LiteralE(String),
   VariableE(String),
   PatternE(Pattern),
   BagE(Bag),
   TableE(Table),
   PropertyAccessE(Box<Expression>, String),
-  CallE(BuiltInFunction, Vec<Expression>),
+  CallE(FunctionLike, Vec<Expression>),
+  WithE(Vec<LocalVariable>, Box<Expression>),
 }
 
 #[derive(Debug, Clone)]
+pub struct LocalVariable {
+  pub(crate) name: String,
+  pub(crate) expression: Expression,
+  pub(crate) sticky: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BuiltInFunction {
   UpperFirst,
   MaybePrepend,
   MaybeAppend,
+  Row,
+  Upper,
+  Lower,
+  TitleCase,
+  Trim,
+  Replace,
+  Repeat,
+  Join,
+  Truncate,
+  Reverse,
+  Length,
+  IfShorter,
+  IfLonger,
+  Article,
+  Harmonise,
 }
 
+/// Most calls to user defined functions that can be in progress at once. Deeper recursion is an
+/// error instead of overflowing the stack, even on the 2 MiB stacks threads get by default.
+pub const MAX_CALL_DEPTH: usize = 64;
+
+/// Longest string `repeat`, `replace` and `join` produce, in characters.
+pub const MAX_OUTPUT_LENGTH: usize = 1 << 20;
+
 impl BuiltInFunction {
   pub fn try_parse(s: &str) -> Option<BuiltInFunction> {
     match s {
       "capitalise" => Some(BuiltInFunction::UpperFirst),
       "maybePrepend" => Some(BuiltInFunction::MaybePrepend),
       "maybeAppend" => Some(BuiltInFunction::MaybeAppend),
+      "row" => Some(BuiltInFunction::Row),
+      "upper" => Some(BuiltInFunction::Upper),
+      "lower" => Some(BuiltInFunction::Lower),
+      "titleCase" => Some(BuiltInFunction::TitleCase),
+      "trim" => Some(BuiltInFunction::Trim),
+      "replace" => Some(BuiltInFunction::Replace),
+      "repeat" => Some(BuiltInFunction::Repeat),
+      "join" => Some(BuiltInFunction::Join),
+      "truncate" => Some(BuiltInFunction::Truncate),
+      "reverse" => Some(BuiltInFunction::Reverse),
+      "length" => Some(BuiltInFunction::Length),
+      "ifShorter" => Some(BuiltInFunction::IfShorter),
+      "ifLonger" => Some(BuiltInFunction::IfLonger),
+      "article" => Some(BuiltInFunction::Article),
+      "harmonise" => Some(BuiltInFunction::Harmonise),
       _ => None,
     }
   }
+
+  /// Checks that the function can be called with `was` arguments.
+  pub fn check_arity(self, was: usize) -> Result<(), FunctionError> {
+    let expected = match self {
+      BuiltInFunction::Join if was == 0 => {
+        return Err(FunctionError::TooFewArguments {
+          expected: 1,
+          was: 0,
+        })
+      }
+      BuiltInFunction::Join => return Ok(()),
+      BuiltInFunction::UpperFirst
+      | BuiltInFunction::Row
+      | BuiltInFunction::Upper
+      | BuiltInFunction::Lower
+      | BuiltInFunction::TitleCase
+      | BuiltInFunction::Trim
+      | BuiltInFunction::Reverse
+      | BuiltInFunction::Length
+      | BuiltInFunction::Article => 1,
+      BuiltInFunction::MaybePrepend
+      | BuiltInFunction::MaybeAppend
+      | BuiltInFunction::Repeat
+      | BuiltInFunction::Truncate
+      | BuiltInFunction::Harmonise => 2,
+      BuiltInFunction::Replace | BuiltInFunction::IfShorter | BuiltInFunction::IfLonger => 3,
+    };
+
+    match expected == was {
+      true => Ok(()),
+      false => Err(FunctionError::WrongNumberOfArguments { expected, was }),
+    }
+  }
+
+  /// Whether the function only depends on the text of its arguments, which are all evaluated in
+  /// order. Such functions are implemented by [`BuiltInFunction::apply`].
+  pub fn is_text_function(self) -> bool {
+    !matches!(
+      self,
+      BuiltInFunction::UpperFirst
+        | BuiltInFunction::MaybePrepend
+        | BuiltInFunction::MaybeAppend
+        | BuiltInFunction::Row
+    )
+  }
+
+  /// Applies a text function to the text of its arguments, which must be of the right number.
+  /// `articles` holds the exceptions of the script for `article`.
+  pub fn apply(
+    self,
+    arguments: &[impl AsRef<str>],
+    articles: &Articles,
+  ) -> Result<String, FunctionError> {
+    let text = |n: usize| arguments[n - 1].as_ref();
+    let number = |n: usize| {
+      text(n)
+        .trim()
+        .parse::<usize>()
+        .map_err(|_| FunctionError::InvalidArgument {
+          n,
+          expected: "a whole number",
+          was: text(n).to_string(),
+        })
+    };
+
+    let within_limit = |length: usize| match length > MAX_OUTPUT_LENGTH {
+      true => Err(FunctionError::OutputTooLong {
+        limit: MAX_OUTPUT_LENGTH,
+      }),
+      false => Ok(()),
+    };
+
+    let output = match self {
+      BuiltInFunction::Upper => text(1).to_uppercase(),
+      BuiltInFunction::Lower => text(1).to_lowercase(),
+      BuiltInFunction::TitleCase => string_utils::title_case(text(1)),
+      BuiltInFunction::Trim => text(1).trim().to_string(),
+      BuiltInFunction::Replace => {
+        if text(2).is_empty() {
+          return Err(FunctionError::InvalidArgument {
+            n: 2,
+            expected: "a non-empty string",
+            was: String::new(),
+          });
+        }
+
+        let occurrences = text(1).matches(text(2)).count();
+        let kept = text(1).chars().count() - occurrences * text(2).chars().count();
+        within_limit(kept.saturating_add(occurrences.saturating_mul(text(3).chars().count())))?;
+
+        text(1).replace(text(2), text(3))
+      }
+      BuiltInFunction::Repeat => {
+        let count = number(2)?;
+        within_limit(text(1).chars().count().saturating_mul(count))?;
+
+        text(1).repeat(count)
+      }
+      BuiltInFunction::Join => {
+        let parts: Vec<_> = arguments[1..]
+          .iter()
+          .map(AsRef::as_ref)
+          .filter(|part| !part.is_empty())
+          .collect();
+
+        let separators = parts.len().saturating_sub(1);
+        let length = parts.iter().map(|part| part.chars().count()).sum::<usize>();
+        within_limit(length.saturating_add(separators.saturating_mul(text(1).chars().count())))?;
+
+        parts.join(text(1))
+      }
+      BuiltInFunction::Truncate => text(1).chars().take(number(2)?).collect(),
+      BuiltInFunction::Reverse => text(1).chars().rev().collect(),
+      BuiltInFunction::Length => text(1).chars().count().to_string(),
+      BuiltInFunction::Article => articles.with_article(text(1)),
+      BuiltInFunction::Harmonise => string_utils::harmonise(text(1), text(2)),
+      BuiltInFunction::IfShorter | BuiltInFunction::IfLonger => {
+        let length = text(1).chars().count();
+        let limit = number(2)?;
+
+        let keep = match self {
+          BuiltInFunction::IfShorter => length < limit,
+          _ => length > limit,
+        };
+
+        match keep {
+          true => text(1).to_string(),
+          false => text(3).to_string(),
+        }
+      }
+      BuiltInFunction::UpperFirst
+      | BuiltInFunction::MaybePrepend
+      | BuiltInFunction::MaybeAppend
+      | BuiltInFunction::Row => unreachable!("{self:?} is not a text function"),
+    };
+
+    Ok(output)
+  }
 }
 
 #[derive(Debug, Clone)]
 pub enum FunctionLike {
   BuiltIn(BuiltInFunction),
+  UserDefined(String),
 }
 
-#[derive(Error, Debug)]
+#[derive(Debug, Clone)]
+pub struct Function {
+  pub(crate) parameters: Vec<String>,
+  pub(crate) body: Expression,
+}
+
+#[derive(Error, Debug, Clone)]
 pub enum FunctionError {
   #[error("Expected argument #{n} to be {expected}, was {was}")]
   UnexpectedArgumentType {
-    n: u8,
+    n: usize,
     expected: &'static str,
     was: &'static str,
   },
   #[error("Expected {expected} arguments, received {was}")]
-  WrongNumberOfArguments { expected: u8, was: u8 },
+  WrongNumberOfArguments { expected: usize, was: usize },
+  #[error("Expected at least {expected} arguments, received {was}")]
+  TooFewArguments { expected: usize, was: usize },
+  #[error("Expected argument #{n} to be {expected}, was \"{was}\"")]
+  InvalidArgument {
+    n: usize,
+    expected: &'static str,
+    was: String,
+  },
+  #[error("The output would be longer than {limit} characters")]
+  OutputTooLong { limit: usize },
 }
 
 #[derive(Error, Debug)]
@@ -142,50 +514,305 @@ pub enum InterpreterError {
 
   #[error("Error invoking function {function:?}: {inner}")]
   FunctionError {
-    function: BuiltInFunction,
+    function: FunctionLike,
     inner: FunctionError,
   },
+
+  #[error("Function {0} is not defined")]
+  UnknownFunction(String),
+
+  #[error("Entry point {0} is not defined")]
+  UnknownEntry(String),
+
+  #[error("Calling function {function} exceeded the limit of {limit} nested calls")]
+  RecursionLimit { function: String, limit: usize },
+
+  #[error("Could not write the output")]
+  Write(#[from] fmt::Error),
+
+  /// Wraps an error with the location of the expression that caused it.
+  #[error("{inner}")]
+  At {
+    span: Span,
+    inner: Box<InterpreterError>,
+  },
+}
+
+impl InterpreterError {
+  /// Attaches a location to the error, unless it already has a more precise one.
+  pub(crate) fn at(self, span: Span) -> Self {
+    match self {
+      // Failing to write has nothing to do with the expression being written
+      InterpreterError::At { .. } | InterpreterError::Write(_) => self,
+      inner => InterpreterError::At {
+        span,
+        inner: Box::new(inner),
+      },
+    }
+  }
+
+  pub fn span(&self) -> Option<Span> {
+    match self {
+      InterpreterError::At { span, .. } => Some(*span),
+      _ => None,
+    }
+  }
 }
 
 #[derive(Error, Debug)]
 pub enum CompilerError {
   #[error("A table must have at least one column (in {name})")]
-  EmptyTable { name: String },
+  EmptyTable { name: String, span: Span },
 
-  #[error("A bag must have at least one item (in {name}])")]
-  EmptyBag { name: String },
+  #[error("A bag must have at least one item (in {name})")]
+  EmptyBag { name: String, span: Span },
 
   #[error("Column {column_name} had 0 non-hole entries (in {in_variable})")]
   EmptyTableColumn {
     column_name: String,
     in_variable: String,
+    span: Span,
   },
 
-  #[error("Error on table row {row_number}: expected {} columns ({expected_columns:?}), found {} values ({values:?})", .expected_columns.len(), values.len())]
+  #[error("Error on table row {row_number}: expected {} columns ({expected_columns:?}), found {} values", .expected_columns.len(), values.len())]
   InvalidTableRow {
     expected_columns: Vec<String>,
     values: Vec<ast::TableEntry>,
     row_number: usize,
+    span: Span,
   },
 
   #[error("The first item in table {in_variable} on row {row_number} must not be an append entry")]
   AppendInFirstColumn {
     row_number: usize,
     in_variable: String,
+    span: Span,
   },
 
-  #[error("Function {0} is not defined")]
-  UnknownFunction(String),
+  #[error("Function {name} is not defined")]
+  UnknownFunction { name: String, span: Span },
+
+  #[error("Function {name} expects {expected} arguments, but was called with {was}")]
+  WrongNumberOfArguments {
+    name: String,
+    expected: usize,
+    was: usize,
+    span: Span,
+  },
+
+  #[error("Function {name} has the same name as a built-in function")]
+  RedefinedBuiltInFunction { name: String, span: Span },
+
+  #[error("Variable {name} is already defined")]
+  RedefinedVariable {
+    name: String,
+    span: Span,
+    /// Span of the first definition
+    previous: Span,
+  },
+
+  #[error("Function {name} is already defined")]
+  RedefinedFunction {
+    name: String,
+    span: Span,
+    previous: Span,
+  },
+
+  #[error("Invalid {}: {problem}", crate::articles::EXCEPTIONS_VARIABLE)]
+  InvalidArticleExceptions { problem: String, span: Span },
+
+  #[error("Cannot import {path} here: imports are resolved when a script is loaded from a file")]
+  UnresolvedImport { path: String, span: Span },
+
+  #[error("Function {function} has multiple parameters called {parameter}")]
+  DuplicateParameter {
+    function: String,
+    parameter: String,
+    span: Span,
+  },
+
+  #[error("Variable {name} is not defined")]
+  UndefinedVariable { name: String, span: Span },
+
+  #[error("Table with columns {columns:?} has no column \"{column}\"")]
+  UnknownColumn {
+    column: String,
+    columns: Vec<String>,
+    span: Span,
+  },
+
+  #[error("Invalid weight {weight} on entry {entry_number} of bag {in_variable}: weights must be finite and not negative")]
+  InvalidBagWeight {
+    weight: f32,
+    entry_number: usize,
+    in_variable: String,
+    span: Span,
+  },
+
+  #[error("Invalid weight {weight} on row {row_number} of table {in_variable}: weights must be finite and not negative")]
+  InvalidRowWeight {
+    weight: f32,
+    row_number: usize,
+    in_variable: String,
+    span: Span,
+  },
+
+  #[error("Every entry of bag {in_variable} has weight 0")]
+  ZeroWeightBag { in_variable: String, span: Span },
+
+  #[error("Every row of table {in_variable} has weight 0")]
+  ZeroWeightTable { in_variable: String, span: Span },
+
+  #[error("Every non-hole entry in column {column_name} has weight 0 (in {in_variable})")]
+  ZeroWeightTableColumn {
+    column_name: String,
+    in_variable: String,
+    span: Span,
+  },
+
+  #[error("The weights in {in_variable} add up to more than the largest supported weight")]
+  WeightOverflow { in_variable: String, span: Span },
+
+  #[error("{in_variable} has too many entries")]
+  TooManyEntries { in_variable: String, span: Span },
+
+  #[error("The script does not define the variable result")]
+  MissingResult,
+
+  #[error("{}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"))]
+  Multiple(Vec<CompilerError>),
+}
+
+impl CompilerError {
+  pub fn span(&self) -> Option<Span> {
+    let span = match self {
+      CompilerError::EmptyTable { span, .. }
+      | CompilerError::EmptyBag { span, .. }
+      | CompilerError::EmptyTableColumn { span, .. }
+      | CompilerError::InvalidTableRow { span, .. }
+      | CompilerError::AppendInFirstColumn { span, .. }
+      | CompilerError::UnknownFunction { span, .. }
+      | CompilerError::WrongNumberOfArguments { span, .. }
+      | CompilerError::RedefinedBuiltInFunction { span, .. }
+      | CompilerError::RedefinedVariable { span, .. }
+      | CompilerError::RedefinedFunction { span, .. }
+      | CompilerError::InvalidArticleExceptions { span, .. }
+      | CompilerError::UnresolvedImport { span, .. }
+      | CompilerError::DuplicateParameter { span, .. }
+      | CompilerError::UndefinedVariable { span, .. }
+      | CompilerError::UnknownColumn { span, .. }
+      | CompilerError::InvalidBagWeight { span, .. }
+      | CompilerError::InvalidRowWeight { span, .. }
+      | CompilerError::ZeroWeightBag { span, .. }
+      | CompilerError::ZeroWeightTable { span, .. }
+      | CompilerError::ZeroWeightTableColumn { span, .. }
+      | CompilerError::WeightOverflow { span, .. }
+      | CompilerError::TooManyEntries { span, .. } => span,
+      CompilerError::MissingResult => return None,
+      CompilerError::Multiple(problems) => return problems[0].span(),
+    };
+
+    Some(*span)
+  }
+
+  /// Another location which helps to explain the error, such as the first definition of a name
+  /// which is defined twice.
+  pub fn note(&self) -> Option<(Span, String)> {
+    match self {
+      CompilerError::RedefinedVariable { name, previous, .. }
+      | CompilerError::RedefinedFunction { name, previous, .. } => {
+        Some((*previous, format!("{name} was first defined here")))
+      }
+      _ => None,
+    }
+  }
 }
 
 #[derive(Error, Debug)]
 pub enum ExecutionError {
-  /*#[error("Parser error: {0}")]
-  Parser(#[from] nom::Err<(&str, nom::error::ErrorKind)>),*/
+  #[error("Parse error: {0}")]
+  Parser(#[from] ParseError),
   #[error("Compilation error: {0}")]
   Compiler(#[from] CompilerError),
   #[error("Interpreter error: {0}")]
   Interpreter(#[from] InterpreterError),
+  #[error("Import error: {0}")]
+  Import(#[from] ImportError),
+}
+
+impl ExecutionError {
+  /// The byte range in the source that the error refers to, if known.
+  pub fn span(&self) -> Option<Span> {
+    match self {
+      ExecutionError::Parser(error) => Some(Span::new(error.offset, error.offset)),
+      ExecutionError::Compiler(error) => error.span(),
+      ExecutionError::Interpreter(error) => error.span(),
+      ExecutionError::Import(error) => error.span(),
+    }
+  }
+
+  /// Formats the error like a compiler would: `file:line:column: message`, followed by the
+  /// offending line of the source. Multiple compiler errors are rendered one after another.
+  pub fn render(&self, file_name: &str, source: &str) -> String {
+    self
+      .messages()
+      .into_iter()
+      .map(|(span, message)| render_message(file_name, source, span, &message))
+      .collect::<Vec<_>>()
+      .join("\n")
+  }
+
+  /// Every problem this error consists of, along with its location. Multiple compiler errors are
+  /// listed separately, each followed by its note if it has one.
+  pub fn messages(&self) -> Vec<(Option<Span>, String)> {
+    let with_note = |problem: &CompilerError| {
+      let mut messages = vec![(problem.span(), format!("Compilation error: {problem}"))];
+      messages.extend(
+        problem
+          .note()
+          .map(|(span, note)| (Some(span), format!("Note: {note}"))),
+      );
+      messages
+    };
+
+    match self {
+      ExecutionError::Compiler(CompilerError::Multiple(problems)) => {
+        problems.iter().flat_map(with_note).collect()
+      }
+      ExecutionError::Compiler(problem) => with_note(problem),
+      ExecutionError::Parser(error) => {
+        vec![(self.span(), format!("Parse error: {}", error.message()))]
+      }
+      otherwise => vec![(otherwise.span(), otherwise.to_string())],
+    }
+  }
+}
+
+/// Problems which don't prevent a script from running, but probably aren't intentional.
+#[derive(Error, Debug)]
+pub enum CompilerWarning {
+  #[error("Entry {entry_number} of bag {in_variable} has weight 0, so it can never be chosen")]
+  ZeroWeightBagEntry {
+    entry_number: usize,
+    in_variable: String,
+    span: Span,
+  },
+
+  #[error("Row {row_number} of table {in_variable} has weight 0, so it can never be chosen")]
+  ZeroWeightRow {
+    row_number: usize,
+    in_variable: String,
+    span: Span,
+  },
+}
+
+impl CompilerWarning {
+  pub fn span(&self) -> Span {
+    match self {
+      CompilerWarning::ZeroWeightBagEntry { span, .. }
+      | CompilerWarning::ZeroWeightRow { span, .. } => *span,
+    }
+  }
 }
 
 #[derive(Debug, Clone)]
@@ -218,8 +845,15 @@ impl NameHintUtil for Option<NameHint> {
 
 #[derive(Debug)]
 pub struct CompiledScript {
-  variables: HashMap<String, Expression>,
+  pub(crate) variables: HashMap<String, Expression>,
+  pub(crate) functions: HashMap<String, Function>,
+  pub(crate) sticky_variables: HashSet<String>,
+  /// Article exceptions defined by the script
+  pub(crate) articles: Articles,
+  warnings: Vec<CompilerWarning>,
   id_counter: usize,
+  /// The script lowered into bytecode, kept for batches until the script changes
+  pub(crate) program: OnceLock<Program>,
 }
 
 impl Default for CompiledScript {
@@ -232,7 +866,12 @@ impl CompiledScript {
   pub fn new() -> Self {
     CompiledScript {
       variables: HashMap::new(),
+      functions: HashMap::new(),
+      sticky_variables: HashSet::new(),
+      articles: Articles::default(),
+      warnings: Vec::new(),
       id_counter: 0,
+      program: OnceLock::new(),
     }
   }
 
@@ -241,231 +880,644 @@ impl CompiledScript {
     expression: ast::Expression,
     name_hint: &Option<NameHint>,
   ) -> Result<Expression, CompilerError> {
-    match expression {
-      ast::Expression::LiteralE(literal) => Ok(Expression::LiteralE(literal)),
-      ast::Expression::VariableE(variable) => Ok(Expression::VariableE(variable)),
-      ast::Expression::PatternE(pattern) => {
-        let parts: Result<Vec<_>, _> = pattern
-          .parts
-          .into_iter()
-          .map(|part| self.transform_expression(part, name_hint))
-          .collect();
-
-        let parts = parts?;
-
-        Ok(Expression::PatternE(Pattern { parts }))
+    let span = expression.span;
+
+    let kind = match expression.kind {
+      ast::ExpressionKind::LiteralE(literal) => ExpressionKind::LiteralE(literal),
+      ast::ExpressionKind::VariableE(variable) => ExpressionKind::VariableE(variable),
+      ast::ExpressionKind::PatternE(pattern) => {
+        let parts = collect_all(
+          pattern
+            .parts
+            .into_iter()
+            .map(|part| self.transform_expression(part, name_hint)),
+        )?;
+
+        ExpressionKind::PatternE(Pattern { parts })
       }
-      ast::Expression::BagE(bag) => {
+      ast::ExpressionKind::BagE(bag) => {
         self.id_counter += 1;
         let id = self.id_counter;
 
         let mut weights = Vec::new();
         let mut items = Vec::new();
+        let mut item_spans = Vec::new();
+        let mut problems = Vec::new();
 
-        for (weight, expression) in bag.items.into_iter().map(|item| {
-          (
-            item.weight,
-            self.transform_expression(*item.value, name_hint),
-          )
-        }) {
-          let expression = expression?;
-
-          let weight = weight.unwrap_or(1.0);
+        for item in bag.items {
+          match self.transform_expression(*item.value, name_hint) {
+            Ok(expression) => items.push(expression),
+            Err(problem) => push_problem(&mut problems, problem),
+          }
 
-          weights.push(weight);
-          items.push(expression);
+          weights.push(item.weight.unwrap_or(1.0));
+          item_spans.push(item.span);
         }
 
-        let distribution = WeightedIndex::new(weights).map_err(|err| match err {
-          WeightedError::NoItem => CompilerError::EmptyBag {
+        let distribution = weighted_distribution(&weights).map_err(|problem| match problem {
+          WeightProblem::NoItem => CompilerError::EmptyBag {
             name: name_hint.get_name_or_default(),
+            span,
+          },
+          WeightProblem::Invalid { index, weight } => CompilerError::InvalidBagWeight {
+            weight,
+            entry_number: index,
+            in_variable: name_hint.get_name_or_default(),
+            span: item_spans[index],
+          },
+          WeightProblem::AllZero => CompilerError::ZeroWeightBag {
+            in_variable: name_hint.get_name_or_default(),
+            span,
+          },
+          WeightProblem::Overflow => CompilerError::WeightOverflow {
+            in_variable: name_hint.get_name_or_default(),
+            span,
           },
-          _ => panic!("Unhandled WeightedIndex error: {}", err),
-        })?;
+          WeightProblem::TooMany => CompilerError::TooManyEntries {
+            in_variable: name_hint.get_name_or_default(),
+            span,
+          },
+        });
+
+        let distribution = match distribution {
+          Ok(distribution) => distribution,
+          Err(problem) => {
+            problems.push(problem);
+            return Err(combine_problems(problems).unwrap_err());
+          }
+        };
+
+        combine_problems(problems)?;
+
+        for (entry_number, weight) in weights.iter().enumerate() {
+          if *weight == 0.0 {
+            self.warnings.push(CompilerWarning::ZeroWeightBagEntry {
+              entry_number,
+              in_variable: name_hint.get_name_or_default(),
+              span: item_spans[entry_number],
+            });
+          }
+        }
 
         let bag = Bag {
           id,
           items,
           name_hint: name_hint.clone(),
+          column: None,
           distribution,
+          weights,
         };
 
-        Ok(Expression::BagE(bag))
+        ExpressionKind::BagE(bag)
       }
-      ast::Expression::TableE(table) => {
+      ast::ExpressionKind::TableE(table) => {
         if table.columns.is_empty() {
           return Err(CompilerError::EmptyTable {
             name: name_hint.get_name_or_default(),
+            span,
           });
         }
 
         let mut items_per_column = vec![Vec::new(); table.columns.len()];
+        let mut rows = Vec::new();
+        let mut row_weights = Vec::new();
+        let mut row_spans = Vec::new();
+        let mut bases = Vec::new();
+        let mut problems = Vec::new();
 
         for (row_number, row) in table.rows.into_iter().enumerate() {
           if row.items.len() != table.columns.len() {
-            return Err(CompilerError::InvalidTableRow {
+            problems.push(CompilerError::InvalidTableRow {
               row_number,
-              expected_columns: table.columns,
+              expected_columns: table.columns.clone(),
               values: row.items,
+              span: row.span,
             });
+            continue;
           }
 
           let base_item = row.items[0].clone();
 
           let base_item = match base_item {
-            ast::TableEntry::Literal(s) => self.transform_expression(*s, name_hint)?,
-            ast::TableEntry::Hole => Expression::LiteralE(String::new()),
-            ast::TableEntry::Append(_) => Err(CompilerError::AppendInFirstColumn {
-              row_number,
-              in_variable: name_hint.get_name_or_default(),
-            })?,
+            ast::TableEntry::Literal(s) => match self.transform_expression(*s, name_hint) {
+              Ok(expression) => expression,
+              Err(problem) => {
+                push_problem(&mut problems, problem);
+                continue;
+              }
+            },
+            ast::TableEntry::Hole => Expression {
+              kind: ExpressionKind::LiteralE(String::new()),
+              span: row.span,
+            },
+            ast::TableEntry::Append(_) => {
+              problems.push(CompilerError::AppendInFirstColumn {
+                row_number,
+                in_variable: name_hint.get_name_or_default(),
+                span: row.span,
+              });
+              continue;
+            }
           };
 
+          // A base which isn't a literal could produce a different text for every column, so
+          // whole rows share it. Columns are sampled independently anyway.
+          let has_appends = row
+            .items
+            .iter()
+            .any(|item| matches!(item, ast::TableEntry::Append(_)));
+          let shared_base = match &base_item.kind {
+            ExpressionKind::LiteralE(_) => None,
+            _ if has_appends => Some(Expression {
+              kind: ExpressionKind::VariableE(ROW_BASE.to_string()),
+              span: base_item.span,
+            }),
+            _ => None,
+          };
+
+          let mut row_items = Vec::with_capacity(row.items.len());
+
           for (column_number, item) in row.items.into_iter().enumerate() {
-            let maybe_expr = match item {
-              ast::TableEntry::Hole => None,
-              ast::TableEntry::Literal(expr) => Some(self.transform_expression(*expr, name_hint)?),
+            let (column_item, row_item) = match item {
+              ast::TableEntry::Hole => (None, None),
+              ast::TableEntry::Literal(expr) => {
+                let expr = match self.transform_expression(*expr, name_hint) {
+                  Ok(expr) => expr,
+                  Err(problem) => {
+                    push_problem(&mut problems, problem);
+                    continue;
+                  }
+                };
+
+                match (column_number, &shared_base) {
+                  (0, Some(shared_base)) => (Some(expr), Some(shared_base.clone())),
+                  _ => (Some(expr.clone()), Some(expr)),
+                }
+              }
               ast::TableEntry::Append(expr) => {
-                let expr = self.transform_expression(*expr, name_hint)?;
-
-                Some(Expression::PatternE(Pattern {
-                  // TODO: Avoid this clone?
-                  parts: vec![base_item.clone(), expr],
-                }))
+                let expr = match self.transform_expression(*expr, name_hint) {
+                  Ok(expr) => expr,
+                  Err(problem) => {
+                    push_problem(&mut problems, problem);
+                    continue;
+                  }
+                };
+                let row_item = shared_base
+                  .as_ref()
+                  .map(|shared_base| append(shared_base.clone(), expr.clone()));
+
+                let column_item = append(base_item.clone(), expr);
+                let row_item = row_item.unwrap_or_else(|| column_item.clone());
+                (Some(column_item), Some(row_item))
               }
             };
 
-            if let Some(expr) = maybe_expr {
-              items_per_column[column_number].push((row.weight, expr));
+            if let Some(expr) = column_item {
+              items_per_column[column_number].push((row_number, expr));
             }
+
+            row_items.push(row_item);
+          }
+
+          bases.push(shared_base.map(|_| base_item));
+          rows.push(row_items);
+          row_weights.push(row.weight.unwrap_or(1.0));
+          row_spans.push(row.span);
+        }
+
+        // Weights of rows which failed to compile are missing, so they can't be checked
+        combine_problems(problems)?;
+
+        let invalid_row_weight = |row_number: usize, weight: f32| CompilerError::InvalidRowWeight {
+          weight,
+          row_number,
+          in_variable: name_hint.get_name_or_default(),
+          span: row_spans[row_number],
+        };
+
+        // Row weights are checked first, because the columns share them
+        let distribution =
+          weighted_distribution(&row_weights).map_err(|problem| match problem {
+            WeightProblem::NoItem => CompilerError::EmptyTable {
+              name: name_hint.get_name_or_default(),
+              span,
+            },
+            WeightProblem::Invalid { index, weight } => invalid_row_weight(index, weight),
+            WeightProblem::AllZero => CompilerError::ZeroWeightTable {
+              in_variable: name_hint.get_name_or_default(),
+              span,
+            },
+            WeightProblem::Overflow => CompilerError::WeightOverflow {
+              in_variable: name_hint.get_name_or_default(),
+              span,
+            },
+            WeightProblem::TooMany => CompilerError::TooManyEntries {
+              in_variable: name_hint.get_name_or_default(),
+              span,
+            },
+          })?;
+
+        for (row_number, weight) in row_weights.iter().enumerate() {
+          if *weight == 0.0 {
+            self.warnings.push(CompilerWarning::ZeroWeightRow {
+              row_number,
+              in_variable: name_hint.get_name_or_default(),
+              span: row_spans[row_number],
+            });
           }
         }
 
         let bags = items_per_column
           .into_iter()
-          .zip(table.columns)
+          .zip(table.columns.iter().cloned())
           .map(|(items, column)| {
             if items.is_empty() {
               return Err(CompilerError::EmptyTableColumn {
                 column_name: column,
                 in_variable: name_hint.get_name_or_default(),
+                span,
               });
             }
 
             self.id_counter += 1;
             let id = self.id_counter;
 
-            let distribution: WeightedIndex<f32> = WeightedIndex::new(
-              items
-                .iter()
-                .map(|(weight, _)| weight.unwrap_or(1.0))
-                .collect::<Vec<_>>(),
-            )
-            .unwrap();
+            let weights: Vec<_> = items
+              .iter()
+              .map(|(row_number, _)| row_weights[*row_number])
+              .collect();
+
+            let distribution =
+              weighted_distribution(&weights).map_err(|problem| match problem {
+                WeightProblem::Invalid { index, weight } => {
+                  invalid_row_weight(items[index].0, weight)
+                }
+                WeightProblem::AllZero => CompilerError::ZeroWeightTableColumn {
+                  column_name: column.clone(),
+                  in_variable: name_hint.get_name_or_default(),
+                  span,
+                },
+                // The column has at least one item and its weights are a subset of the row weights,
+                // which have already been checked
+                WeightProblem::NoItem | WeightProblem::Overflow | WeightProblem::TooMany => {
+                  unreachable!("row weights were already validated")
+                }
+              })?;
 
             let bag = Bag {
               id,
               distribution,
+              weights,
               items: items.into_iter().map(|(_, item)| item).collect(),
               name_hint: name_hint.clone(),
+              column: Some(column.clone()),
             };
 
             Ok((column, bag))
           })
           .collect::<Result<HashMap<_, _>, CompilerError>>()?;
 
-        Ok(Expression::TableE(Table {
+        ExpressionKind::TableE(Table {
           name_hint: name_hint.clone(),
+          columns: table.columns,
           bags,
-        }))
+          rows,
+          bases,
+          distribution,
+          weights: row_weights,
+        })
       }
-      ast::Expression::PropertyAccessE(expression, property) => {
+      ast::ExpressionKind::PropertyAccessE(expression, property) => {
         let expression = self.transform_expression(*expression, name_hint)?;
-        Ok(Expression::PropertyAccessE(Box::new(expression), property))
+        ExpressionKind::PropertyAccessE(Box::new(expression), property)
       }
-      ast::Expression::CallE(name, arguments) => {
-        // TODO: Support user defined functions / parameterised patterns
-        let function =
-          BuiltInFunction::try_parse(&name).ok_or_else(|| CompilerError::UnknownFunction(name))?;
+      ast::ExpressionKind::CallE(name, arguments) => {
+        // User defined functions can be declared after they're used, so they're resolved at
+        // run time. compile_script checks that the calls are valid once all statements are known.
+        let function = match BuiltInFunction::try_parse(&name) {
+          Some(function) => FunctionLike::BuiltIn(function),
+          None => FunctionLike::UserDefined(name),
+        };
 
-        let arguments = arguments
-          .into_iter()
-          .map(|expr| self.transform_expression(expr, name_hint))
-          .collect::<Result<Vec<_>, _>>()?;
+        let arguments = collect_all(
+          arguments
+            .into_iter()
+            .map(|expr| self.transform_expression(expr, name_hint)),
+        )?;
 
-        Ok(Expression::CallE(function, arguments))
+        ExpressionKind::CallE(function, arguments)
       }
-    }
-  }
+      ast::ExpressionKind::WithE(bindings, body) => {
+        let bindings = collect_all(bindings.into_iter().map(|binding| {
+          let expression = self.transform_expression(*binding.value, name_hint)?;
+
+          Ok(LocalVariable {
+            name: binding.name,
+            expression,
+            sticky: binding.sticky,
+          })
+        }));
+        let body = self.transform_expression(*body, name_hint);
 
-  pub fn run(&self) -> Result<String, InterpreterError> {
-    let entry = self
-      .variables
-      .get("result")
-      .expect("Expected result to be defined.");
+        let (bindings, body) = match (bindings, body) {
+          (Ok(bindings), Ok(body)) => (bindings, body),
+          (bindings, body) => {
+            let mut problems = Vec::new();
 
-    self
-      .eval_expression(entry)?
-      .try_as_string()
-      .map(Cow::into_owned)
-  }
+            for problem in [bindings.err(), body.err()].into_iter().flatten() {
+              push_problem(&mut problems, problem);
+            }
 
-  pub fn try_coerce_to_string<'a>(
-    &'a self,
-    value: Value<'a>,
-  ) -> Result<Cow<'a, str>, InterpreterError> {
-    match value {
-      Value::StringV(v) => Ok(v),
-      Value::BagV(bag) => {
-        let value = self.sample_bag(bag)?;
-        self.try_coerce_to_string(value)
+            return Err(combine_problems(problems).unwrap_err());
+          }
+        };
+
+        ExpressionKind::WithE(bindings, Box::new(body))
       }
-      otherwise => Err(InterpreterError::CoercionError {
-        target: "string",
-        was: otherwise.get_type_name(),
-      }),
-    }
+    };
+
+    Ok(Expression { kind, span })
   }
 
-  fn sample_bag<'a>(&'a self, bag: &'a Bag) -> Result<Value<'a>, InterpreterError> {
-    let mut rng = rand::thread_rng();
-    let i = bag.distribution.sample(&mut rng);
-    let expression = &bag.items[i];
-    self.eval_expression(expression)
+  /// Warnings found while compiling the statements added so far.
+  pub fn warnings(&self) -> &[CompilerWarning] {
+    &self.warnings
   }
 
-  pub fn eval_expression<'a>(
-    &'a self,
-    expression: &'a Expression,
+  pub fn into_warnings(self) -> Vec<CompilerWarning> {
+    self.warnings
+  }
+
+  pub fn run(&self) -> Result<String, InterpreterError> {
+    self.run_entry("result")
+  }
+
+  /// Like [`CompiledScript::run`], but makes every random choice using `rng`. Running the same
+  /// script with identically seeded generators produces the same output.
+  pub fn run_with_rng(&self, rng: &mut impl Rng) -> Result<String, InterpreterError> {
+    self.run_entry_with_rng("result", rng)
+  }
+
+  /// Runs the script with a generator seeded from `seed`.
+  pub fn run_with_seed(&self, seed: u64) -> Result<String, InterpreterError> {
+    self.run_with_rng(&mut StdRng::seed_from_u64(seed))
+  }
+
+  /// Evaluates the global variable `name` instead of `result`, so one script can contain several
+  /// generators.
+  pub fn run_entry(&self, name: &str) -> Result<String, InterpreterError> {
+    self.run_entry_with_rng(name, &mut rand::thread_rng())
+  }
+
+  pub fn run_entry_with_rng(
+    &self,
+    name: &str,
+    rng: &mut impl Rng,
+  ) -> Result<String, InterpreterError> {
+    self.run_entry_with_chooser(name, Chooser::Random(rng))
+  }
+
+  /// Runs the entry point `name`, making every choice according to `replay`. Used for enumerating
+  /// every possible output.
+  pub(crate) fn run_entry_with_replay<'a>(
+    &'a self,
+    name: &str,
+    replay: &mut Replay<'a>,
+  ) -> Result<String, InterpreterError> {
+    self.run_entry_with_chooser(name, Chooser::Replay(replay))
+  }
+
+  /// Like [`CompiledScript::run`], but also returns every choice made to produce the output.
+  pub fn run_traced(&self) -> Result<Trace, InterpreterError> {
+    self.run_entry_traced_with_rng("result", &mut rand::thread_rng())
+  }
+
+  pub fn run_entry_traced_with_rng(
+    &self,
+    name: &str,
+    rng: &mut impl Rng,
+  ) -> Result<Trace, InterpreterError> {
+    let mut state = RunState::new(rng);
+    state.trace = Some(TraceBuilder::default());
+
+    let output = self.run_entry_in_state(name, &mut state)?;
+    let trace = state.trace.take().unwrap_or_default();
+    Ok(trace.finish(output))
+  }
+
+  fn run_entry_with_chooser<'a>(
+    &'a self,
+    name: &str,
+    chooser: Chooser<'a, '_>,
+  ) -> Result<String, InterpreterError> {
+    self.run_entry_in_state(name, &mut RunState::with_chooser(chooser))
+  }
+
+  fn run_entry_in_state<'a>(
+    &'a self,
+    name: &str,
+    state: &mut RunState<'a, '_>,
+  ) -> Result<String, InterpreterError> {
+    let entry = self
+      .variables
+      .get(name)
+      .ok_or_else(|| InterpreterError::UnknownEntry(name.to_string()))?;
+
+    let value = self.eval_in_scope(entry, &Scope::default(), state)?;
+
+    self
+      .coerce_to_string(value, state)
+      .map(Cow::into_owned)
+      .map_err(|error| error.at(entry.span))
+  }
+
+  /// Runs the script and writes the output into `out` as it is produced, without building
+  /// intermediate strings for patterns. If the run fails, `out` keeps whatever was written before
+  /// the error.
+  pub fn run_into(&self, out: &mut impl fmt::Write) -> Result<(), InterpreterError> {
+    self.run_entry_into_with_rng("result", &mut rand::thread_rng(), out)
+  }
+
+  pub fn run_entry_into_with_rng(
+    &self,
+    name: &str,
+    rng: &mut impl Rng,
+    out: &mut impl fmt::Write,
+  ) -> Result<(), InterpreterError> {
+    let entry = self
+      .variables
+      .get(name)
+      .ok_or_else(|| InterpreterError::UnknownEntry(name.to_string()))?;
+
+    let mut state = RunState::new(rng);
+
+    match self.write_in_scope(entry, &Scope::default(), &mut state, out)? {
+      None => Ok(()),
+      Some(value) => self
+        .coerce_to_string(value, &mut state)
+        .map(|_| ())
+        .map_err(|error| error.at(entry.span)),
+    }
+  }
+
+  pub fn try_coerce_to_string<'a>(
+    &'a self,
+    value: Value<'a>,
+  ) -> Result<Cow<'a, str>, InterpreterError> {
+    self.coerce_to_string(value, &mut RunState::new(&mut rand::thread_rng()))
+  }
+
+  fn coerce_to_string<'a>(
+    &'a self,
+    value: Value<'a>,
+    state: &mut RunState<'a, '_>,
+  ) -> Result<Cow<'a, str>, InterpreterError> {
+    match value {
+      Value::StringV(v) => Ok(v),
+      Value::BagV(bag, scope) => {
+        let value = self.sample_bag(bag, &scope, state)?;
+        let text = self.coerce_to_string(value, state)?;
+        state.close_choice(&text);
+        Ok(text)
+      }
+      otherwise => Err(InterpreterError::CoercionError {
+        target: "string",
+        was: otherwise.get_type_name(),
+      }),
+    }
+  }
+
+  fn sample_bag<'a>(
+    &'a self,
+    bag: &'a Bag,
+    scope: &Scope<'a>,
+    state: &mut RunState<'a, '_>,
+  ) -> Result<Value<'a>, InterpreterError> {
+    let i = state.choose(&bag.distribution, &bag.weights);
+    let (kind, weight) = (ChoiceKind::Entry, bag.weights[i]);
+    state.open_choice(&bag.name_hint, &bag.column, kind, i, weight);
+
+    // The caller closes the choice once it knows what text the entry produced
+    let expression = &bag.items[i];
+    self.eval_in_scope(expression, scope, state)
+  }
+
+  fn sample_row<'a>(
+    &'a self,
+    table: &'a Table,
+    scope: Scope<'a>,
+    state: &mut RunState<'a, '_>,
+  ) -> Result<Value<'a>, InterpreterError> {
+    let i = state.choose(&table.distribution, &table.weights);
+    let (kind, weight) = (ChoiceKind::Row, table.weights[i]);
+    state.open_choice(&table.name_hint, &None, kind, i, weight);
+    state.close_choice("");
+
+    // The base of the row is shared by its append entries
+    let scope = match &table.bases[i] {
+      None => scope,
+      Some(base) => {
+        let value = self.eval_in_scope(base, &scope, state)?;
+        let base = self.coerce_to_string(value, state)?;
+        scope.bind(ROW_BASE, Value::StringV(base))
+      }
+    };
+
+    Ok(Value::RowV(table, i, scope))
+  }
+
+  pub fn eval_expression<'a>(
+    &'a self,
+    expression: &'a Expression,
+  ) -> Result<Value<'a>, InterpreterError> {
+    self.eval_in_scope(
+      expression,
+      &Scope::default(),
+      &mut RunState::new(&mut rand::thread_rng()),
+    )
+  }
+
+  /// Samples bags until the value is something other than a bag.
+  fn force<'a>(
+    &'a self,
+    mut value: Value<'a>,
+    state: &mut RunState<'a, '_>,
+  ) -> Result<Value<'a>, InterpreterError> {
+    while let Value::BagV(bag, scope) = value {
+      value = self.sample_bag(bag, &scope, state)?;
+
+      match &value {
+        Value::StringV(text) => state.close_choice(text),
+        _ => state.close_choice(""),
+      }
+    }
+
+    Ok(value)
+  }
+
+  fn eval_in_scope<'a>(
+    &'a self,
+    expression: &'a Expression,
+    scope: &Scope<'a>,
+    state: &mut RunState<'a, '_>,
+  ) -> Result<Value<'a>, InterpreterError> {
+    self
+      .eval_kind(&expression.kind, scope, state)
+      .map_err(|error| error.at(expression.span))
+  }
+
+  fn eval_kind<'a>(
+    &'a self,
+    expression: &'a ExpressionKind,
+    scope: &Scope<'a>,
+    state: &mut RunState<'a, '_>,
   ) -> Result<Value<'a>, InterpreterError> {
     match expression {
-      Expression::LiteralE(literal) => Ok(Value::StringV(Cow::from(literal))),
-      Expression::VariableE(variable) => {
-        let expression = self
+      ExpressionKind::LiteralE(literal) => Ok(Value::StringV(Cow::from(literal))),
+      ExpressionKind::VariableE(variable) => {
+        if let Some(value) = scope.lookup(variable) {
+          return Ok(value.clone());
+        }
+
+        if let Some(value) = state.sticky.get(variable.as_str()) {
+          return Ok(value.clone());
+        }
+
+        let (name, expression) = self
           .variables
-          .get(variable)
+          .get_key_value(variable)
           .ok_or_else(|| InterpreterError::UnknownVariable(variable.clone()))?;
 
-        self.eval_expression(expression)
+        let value = self.eval_in_scope(expression, &Scope::default(), state)?;
+
+        if self.sticky_variables.contains(variable) {
+          let value = self.force(value, state)?;
+          state.sticky.insert(name, value.clone());
+          Ok(value)
+        } else {
+          Ok(value)
+        }
       }
-      Expression::PatternE(pattern) => {
+      ExpressionKind::PatternE(pattern) => {
         let mut combined = String::new();
 
         for part in &pattern.parts {
-          let part = self.eval_expression(part)?;
-          let part_as_string = self.try_coerce_to_string(part)?;
+          let value = self.eval_in_scope(part, scope, state)?;
+          let part_as_string = self
+            .coerce_to_string(value, state)
+            .map_err(|error| error.at(part.span))?;
           combined.push_str(&part_as_string);
         }
 
         Ok(Value::StringV(Cow::from(combined)))
       }
-      Expression::BagE(bag) => Ok(Value::BagV(bag)),
-      Expression::TableE(table) => Ok(Value::TableV(table)),
-      Expression::PropertyAccessE(expression, property) => {
-        let value = self.eval_expression(expression)?;
+      ExpressionKind::BagE(bag) => Ok(Value::BagV(bag, scope.clone())),
+      ExpressionKind::TableE(table) => Ok(Value::TableV(table, scope.clone())),
+      ExpressionKind::PropertyAccessE(expression, property) => {
+        let value = self.eval_in_scope(expression, scope, state)?;
 
         match value {
-          Value::TableV(table) => {
+          Value::TableV(table, scope) => {
             let bag = table.bags.get(property);
 
             match bag {
@@ -473,7 +1525,22 @@ impl CompiledScript {
                 columns: table.bags.keys().map(String::from).collect(),
                 key: property.clone(),
               }),
-              Some(bag) => Ok(Value::BagV(bag)),
+              Some(bag) => Ok(Value::BagV(bag, scope)),
+            }
+          }
+          Value::RowV(table, row, scope) => {
+            let column = table.columns.iter().position(|column| column == property);
+
+            match column {
+              None => Err(InterpreterError::TableMissingProperty {
+                columns: table.columns.clone(),
+                key: property.clone(),
+              }),
+              Some(column) => match &table.rows[row][column] {
+                // Holes behave like empty strings, just like in append entries
+                None => Ok(Value::StringV(Cow::from(""))),
+                Some(expression) => self.eval_in_scope(expression, &scope, state),
+              },
             }
           }
           otherwise => Err(InterpreterError::CannotBeIndexed {
@@ -482,41 +1549,193 @@ impl CompiledScript {
           }),
         }
       }
-      Expression::CallE(function, arguments) => {
-        self.eval_builtin_function(function, arguments.as_slice())
+      ExpressionKind::CallE(FunctionLike::BuiltIn(function), arguments) => {
+        self.eval_builtin_function(function, arguments.as_slice(), scope, state)
+      }
+      ExpressionKind::CallE(FunctionLike::UserDefined(name), arguments) => {
+        let (function, function_scope) = self.call_scope(name, arguments, scope, state)?;
+        state.enter_call(name)?;
+        let value = self.eval_in_scope(&function.body, &function_scope, state);
+        state.leave_call();
+        value
+      }
+      ExpressionKind::WithE(bindings, body) => {
+        let scope = self.bind_locals(bindings, scope, state)?;
+        self.eval_in_scope(body, &scope, state)
+      }
+    }
+  }
+
+  /// Evaluates the arguments of a call to a user defined function, and returns the function along
+  /// with the scope its body runs in.
+  fn call_scope<'a>(
+    &'a self,
+    name: &str,
+    arguments: &'a [Expression],
+    scope: &Scope<'a>,
+    state: &mut RunState<'a, '_>,
+  ) -> Result<(&'a Function, Scope<'a>), InterpreterError> {
+    let function = self
+      .functions
+      .get(name)
+      .ok_or_else(|| InterpreterError::UnknownFunction(name.to_string()))?;
+
+    if function.parameters.len() != arguments.len() {
+      return Err(InterpreterError::FunctionError {
+        function: FunctionLike::UserDefined(name.to_string()),
+        inner: FunctionError::WrongNumberOfArguments {
+          expected: function.parameters.len(),
+          was: arguments.len(),
+        },
+      });
+    }
+
+    // Arguments are evaluated in the caller's scope, but the body can only see the parameters
+    let mut function_scope = Scope::default();
+
+    for (parameter, argument) in function.parameters.iter().zip(arguments) {
+      let value = self.eval_in_scope(argument, scope, state)?;
+      function_scope = function_scope.bind(parameter, value);
+    }
+
+    Ok((function, function_scope))
+  }
+
+  /// Evaluates the bindings of a `with` expression in order, each one seeing the ones before it.
+  fn bind_locals<'a>(
+    &'a self,
+    bindings: &'a [LocalVariable],
+    scope: &Scope<'a>,
+    state: &mut RunState<'a, '_>,
+  ) -> Result<Scope<'a>, InterpreterError> {
+    let mut scope = scope.clone();
+
+    for binding in bindings {
+      let value = self.eval_in_scope(&binding.expression, &scope, state)?;
+
+      let value = if binding.sticky {
+        self.force(value, state)?
+      } else {
+        value
+      };
+
+      scope = scope.bind(&binding.name, value);
+    }
+
+    Ok(scope)
+  }
+
+  /// Evaluates an expression and writes it into `out` as a string. Makes the same choices and
+  /// produces the same text as evaluating the expression and coercing the value, but patterns, bags,
+  /// function calls and `with` bodies write their parts directly instead of concatenating them
+  /// first. Choices made this way aren't traced.
+  ///
+  /// A value which isn't text, such as a table, is returned instead of written, so that the caller
+  /// reports it at the same place as coercing it would.
+  fn write_in_scope<'a>(
+    &'a self,
+    expression: &'a Expression,
+    scope: &Scope<'a>,
+    state: &mut RunState<'a, '_>,
+    out: &mut dyn fmt::Write,
+  ) -> Result<Option<Value<'a>>, InterpreterError> {
+    self
+      .write_kind(&expression.kind, scope, state, out)
+      .map_err(|error| error.at(expression.span))
+  }
+
+  fn write_kind<'a>(
+    &'a self,
+    expression: &'a ExpressionKind,
+    scope: &Scope<'a>,
+    state: &mut RunState<'a, '_>,
+    out: &mut dyn fmt::Write,
+  ) -> Result<Option<Value<'a>>, InterpreterError> {
+    match expression {
+      ExpressionKind::LiteralE(literal) => out.write_str(literal)?,
+      ExpressionKind::VariableE(variable)
+        if scope.lookup(variable).is_none() && !self.sticky_variables.contains(variable) =>
+      {
+        let expression = self
+          .variables
+          .get(variable)
+          .ok_or_else(|| InterpreterError::UnknownVariable(variable.clone()))?;
+
+        return self.write_in_scope(expression, &Scope::default(), state, out);
+      }
+      ExpressionKind::PatternE(pattern) => {
+        for part in &pattern.parts {
+          if let Some(value) = self.write_in_scope(part, scope, state, out)? {
+            self
+              .coerce_to_string(value, state)
+              .map_err(|error| error.at(part.span))?;
+          }
+        }
+      }
+      ExpressionKind::BagE(bag) => return self.write_bag(bag, scope, state, out),
+      ExpressionKind::CallE(FunctionLike::UserDefined(name), arguments) => {
+        let (function, function_scope) = self.call_scope(name, arguments, scope, state)?;
+        state.enter_call(name)?;
+        let written = self.write_in_scope(&function.body, &function_scope, state, out);
+        state.leave_call();
+        return written;
+      }
+      ExpressionKind::WithE(bindings, body) => {
+        let scope = self.bind_locals(bindings, scope, state)?;
+        return self.write_in_scope(body, &scope, state, out);
       }
+      _ => match self.eval_kind(expression, scope, state)? {
+        Value::StringV(text) => out.write_str(&text)?,
+        Value::BagV(bag, scope) => return self.write_bag(bag, &scope, state, out),
+        otherwise => return Ok(Some(otherwise)),
+      },
     }
+
+    Ok(None)
+  }
+
+  fn write_bag<'a>(
+    &'a self,
+    bag: &'a Bag,
+    scope: &Scope<'a>,
+    state: &mut RunState<'a, '_>,
+    out: &mut dyn fmt::Write,
+  ) -> Result<Option<Value<'a>>, InterpreterError> {
+    let i = state.choose(&bag.distribution, &bag.weights);
+    self.write_in_scope(&bag.items[i], scope, state, out)
   }
 
   fn eval_builtin_function<'a>(
     &'a self,
     function: &BuiltInFunction,
     arguments: &'a [Expression],
+    scope: &Scope<'a>,
+    state: &mut RunState<'a, '_>,
   ) -> Result<Value<'a>, InterpreterError> {
     match function {
       BuiltInFunction::UpperFirst => match arguments {
         [inner] => {
-          let inner = self.eval_expression(inner)?;
-          let inner_as_string = self.try_coerce_to_string(inner)?;
+          let inner = self.eval_in_scope(inner, scope, state)?;
+          let inner_as_string = self.coerce_to_string(inner, state)?;
           let capitalised = string_utils::capitalise_first(inner_as_string.as_ref());
           Ok(Value::StringV(Cow::from(capitalised)))
         }
         _ => Err(InterpreterError::FunctionError {
-          function: BuiltInFunction::UpperFirst,
+          function: FunctionLike::BuiltIn(BuiltInFunction::UpperFirst),
           inner: FunctionError::WrongNumberOfArguments {
             expected: 1,
-            was: arguments.len() as u8,
+            was: arguments.len(),
           },
         }),
       },
       BuiltInFunction::MaybePrepend => match arguments {
         [prefix, condition] => {
-          let inner = self.eval_expression(condition)?;
-          let inner_as_string = self.try_coerce_to_string(inner)?;
+          let inner = self.eval_in_scope(condition, scope, state)?;
+          let inner_as_string = self.coerce_to_string(inner, state)?;
 
-          if inner_as_string.len() > 0 {
-            let prefix = self.eval_expression(prefix)?;
-            let prefix_as_string = self.try_coerce_to_string(prefix)?;
+          if !inner_as_string.is_empty() {
+            let prefix = self.eval_in_scope(prefix, scope, state)?;
+            let prefix_as_string = self.coerce_to_string(prefix, state)?;
 
             let mut prefixed = prefix_as_string.to_string();
             prefixed.push_str(&inner_as_string);
@@ -526,21 +1745,21 @@ impl CompiledScript {
           }
         }
         _ => Err(InterpreterError::FunctionError {
-          function: BuiltInFunction::MaybePrepend,
+          function: FunctionLike::BuiltIn(BuiltInFunction::MaybePrepend),
           inner: FunctionError::WrongNumberOfArguments {
             expected: 2,
-            was: arguments.len() as u8,
+            was: arguments.len(),
           },
         }),
       },
       BuiltInFunction::MaybeAppend => match arguments {
         [condition, suffix] => {
-          let inner = self.eval_expression(condition)?;
-          let inner_as_string = self.try_coerce_to_string(inner)?;
+          let inner = self.eval_in_scope(condition, scope, state)?;
+          let inner_as_string = self.coerce_to_string(inner, state)?;
 
-          if inner_as_string.len() > 0 {
-            let suffix = self.eval_expression(suffix)?;
-            let suffix_as_string = self.try_coerce_to_string(suffix)?;
+          if !inner_as_string.is_empty() {
+            let suffix = self.eval_in_scope(suffix, scope, state)?;
+            let suffix_as_string = self.coerce_to_string(suffix, state)?;
 
             let mut suffixed = inner_as_string.to_string();
             suffixed.push_str(&suffix_as_string);
@@ -550,14 +1769,76 @@ impl CompiledScript {
           }
         }
         _ => Err(InterpreterError::FunctionError {
-          function: BuiltInFunction::MaybePrepend,
+          function: FunctionLike::BuiltIn(BuiltInFunction::MaybeAppend),
           inner: FunctionError::WrongNumberOfArguments {
             expected: 2,
-            was: arguments.len() as u8,
+            was: arguments.len(),
           },
         }),
       },
+      BuiltInFunction::Row => match arguments {
+        [table] => match self.eval_in_scope(table, scope, state)? {
+          Value::TableV(table, scope) => self.sample_row(table, scope, state),
+          otherwise => Err(InterpreterError::FunctionError {
+            function: FunctionLike::BuiltIn(BuiltInFunction::Row),
+            inner: FunctionError::UnexpectedArgumentType {
+              n: 1,
+              expected: "table",
+              was: otherwise.get_type_name(),
+            },
+          }),
+        },
+        _ => Err(InterpreterError::FunctionError {
+          function: FunctionLike::BuiltIn(BuiltInFunction::Row),
+          inner: FunctionError::WrongNumberOfArguments {
+            expected: 1,
+            was: arguments.len(),
+          },
+        }),
+      },
+      text_function => self.eval_text_function(*text_function, arguments, scope, state),
+    }
+  }
+
+  /// Evaluates a call to a function which works on the text of its arguments. See
+  /// [`BuiltInFunction::apply`].
+  fn eval_text_function<'a>(
+    &'a self,
+    function: BuiltInFunction,
+    arguments: &'a [Expression],
+    scope: &Scope<'a>,
+    state: &mut RunState<'a, '_>,
+  ) -> Result<Value<'a>, InterpreterError> {
+    let function_error = |inner| InterpreterError::FunctionError {
+      function: FunctionLike::BuiltIn(function),
+      inner,
+    };
+
+    function
+      .check_arity(arguments.len())
+      .map_err(function_error)?;
+
+    let mut texts = Vec::with_capacity(arguments.len());
+
+    for (i, argument) in arguments.iter().enumerate() {
+      let value = match self.eval_in_scope(argument, scope, state)? {
+        value @ (Value::StringV(_) | Value::BagV(..)) => value,
+        otherwise => {
+          return Err(function_error(FunctionError::UnexpectedArgumentType {
+            n: i + 1,
+            expected: "string",
+            was: otherwise.get_type_name(),
+          }))
+        }
+      };
+
+      texts.push(self.coerce_to_string(value, state)?);
     }
+
+    let output = function
+      .apply(&texts, &self.articles)
+      .map_err(function_error)?;
+    Ok(Value::StringV(Cow::from(output)))
   }
 
   fn define_variable(&mut self, name: String, value: Expression) {
@@ -565,37 +1846,627 @@ impl CompiledScript {
   }
 
   pub fn add_statement(&mut self, statement: ast::Statement) -> Result<(), CompilerError> {
+    self.program.take();
+
     match statement {
       ast::Statement::AssignmentS(assignment) => {
         let name_hint = Some(NameHint::InAssignment(assignment.name.clone()));
         let expression = self.transform_expression(*assignment.value, &name_hint)?;
+
+        if assignment.name == articles::EXCEPTIONS_VARIABLE {
+          self.articles = article_exceptions(&expression)?;
+        }
+
+        if assignment.sticky {
+          self.sticky_variables.insert(assignment.name.clone());
+        } else {
+          self.sticky_variables.remove(&assignment.name);
+        }
+
         self.define_variable(assignment.name, expression);
       }
+      ast::Statement::FunctionS(definition) => {
+        if BuiltInFunction::try_parse(&definition.name).is_some() {
+          return Err(CompilerError::RedefinedBuiltInFunction {
+            name: definition.name,
+            span: definition.span,
+          });
+        }
+
+        for (i, parameter) in definition.parameters.iter().enumerate() {
+          if definition.parameters[..i].contains(parameter) {
+            return Err(CompilerError::DuplicateParameter {
+              function: definition.name,
+              parameter: parameter.clone(),
+              span: definition.span,
+            });
+          }
+        }
+
+        let name_hint = Some(NameHint::InAssignment(definition.name.clone()));
+        let body = self.transform_expression(*definition.body, &name_hint)?;
+
+        self.functions.insert(
+          definition.name,
+          Function {
+            parameters: definition.parameters,
+            body,
+          },
+        );
+      }
+      ast::Statement::ImportS(import) => {
+        return Err(CompilerError::UnresolvedImport {
+          path: import.path,
+          span: import.span,
+        })
+      }
     }
 
     Ok(())
   }
+
+  /// Runs the static checks which need the whole script: every variable reference must resolve,
+  /// every call to a user defined function must refer to a function that exists with the correct
+  /// number of arguments, and every property access on a known table or row must name one of its
+  /// columns. Returns all problems found, ordered by their position in the source.
+  pub fn check(&self) -> Vec<CompilerError> {
+    let mut problems = Vec::new();
+
+    for expression in self.variables.values() {
+      self.check_expression(expression, &mut Vec::new(), &mut problems);
+    }
+
+    for function in self.functions.values() {
+      let mut locals = function
+        .parameters
+        .iter()
+        .map(|parameter| (parameter.as_str(), None))
+        .collect();
+
+      self.check_expression(&function.body, &mut locals, &mut problems);
+    }
+
+    problems.sort_by_key(|problem| problem.span().map(|span| span.start));
+    problems
+  }
+
+  fn check_expression<'s>(
+    &'s self,
+    expression: &'s Expression,
+    locals: &mut Vec<Local<'s>>,
+    problems: &mut Vec<CompilerError>,
+  ) {
+    match &expression.kind {
+      ExpressionKind::LiteralE(_) => {}
+      ExpressionKind::VariableE(name) => {
+        let is_local = locals.iter().any(|(local, _)| local == name);
+
+        if !is_local && !self.variables.contains_key(name) {
+          problems.push(CompilerError::UndefinedVariable {
+            name: name.clone(),
+            span: expression.span,
+          });
+        }
+      }
+      ExpressionKind::PatternE(pattern) => {
+        for part in &pattern.parts {
+          self.check_expression(part, locals, problems);
+        }
+      }
+      ExpressionKind::BagE(bag) => {
+        for item in &bag.items {
+          self.check_expression(item, locals, problems);
+        }
+      }
+      ExpressionKind::TableE(table) => {
+        for (row, base) in table.rows.iter().zip(&table.bases) {
+          let depth = locals.len();
+
+          if let Some(base) = base {
+            self.check_expression(base, locals, problems);
+            locals.push((ROW_BASE, None));
+          }
+
+          for item in row.iter().flatten() {
+            self.check_expression(item, locals, problems);
+          }
+
+          locals.truncate(depth);
+        }
+      }
+      ExpressionKind::PropertyAccessE(target, property) => {
+        self.check_expression(target, locals, problems);
+
+        let table = match self.static_shape(target, locals, &mut HashSet::new()) {
+          Some(StaticShape::Table(table) | StaticShape::Row(table)) => table,
+          None => return,
+        };
+
+        if !table.columns.contains(property) {
+          problems.push(CompilerError::UnknownColumn {
+            column: property.clone(),
+            columns: table.columns.clone(),
+            span: expression.span,
+          });
+        }
+      }
+      ExpressionKind::CallE(function, arguments) => {
+        if let FunctionLike::UserDefined(name) = function {
+          match self.functions.get(name) {
+            None => problems.push(CompilerError::UnknownFunction {
+              name: name.clone(),
+              span: expression.span,
+            }),
+            Some(function) if function.parameters.len() != arguments.len() => {
+              problems.push(CompilerError::WrongNumberOfArguments {
+                name: name.clone(),
+                expected: function.parameters.len(),
+                was: arguments.len(),
+                span: expression.span,
+              })
+            }
+            Some(_) => {}
+          }
+        }
+
+        for argument in arguments {
+          self.check_expression(argument, locals, problems);
+        }
+      }
+      ExpressionKind::WithE(bindings, body) => {
+        let depth = locals.len();
+
+        for binding in bindings {
+          self.check_expression(&binding.expression, locals, problems);
+          let shape = self.static_shape(&binding.expression, locals, &mut HashSet::new());
+          locals.push((binding.name.as_str(), shape));
+        }
+
+        self.check_expression(body, locals, problems);
+        locals.truncate(depth);
+      }
+    }
+  }
+
+  /// Determines whether an expression always evaluates to a particular table, or a row of one.
+  fn static_shape<'s>(
+    &'s self,
+    expression: &'s Expression,
+    locals: &[Local<'s>],
+    visited: &mut HashSet<&'s str>,
+  ) -> Option<StaticShape<'s>> {
+    match &expression.kind {
+      ExpressionKind::TableE(table) => Some(StaticShape::Table(table)),
+      ExpressionKind::VariableE(name) => {
+        if let Some((_, shape)) = locals.iter().rev().find(|(local, _)| local == name) {
+          return *shape;
+        }
+
+        // Guards against variables which are defined in terms of themselves
+        if !visited.insert(name) {
+          return None;
+        }
+
+        let expression = self.variables.get(name)?;
+        self.static_shape(expression, &[], visited)
+      }
+      ExpressionKind::CallE(FunctionLike::BuiltIn(BuiltInFunction::Row), arguments) => {
+        match arguments.as_slice() {
+          [table] => match self.static_shape(table, locals, visited)? {
+            StaticShape::Table(table) => Some(StaticShape::Row(table)),
+            StaticShape::Row(_) => None,
+          },
+          _ => None,
+        }
+      }
+      _ => None,
+    }
+  }
+
+  /// Looks for a global variable or function reachable from the variable `name` which refers back
+  /// to itself. Returns the names along the cycle, starting and ending with the same name, with
+  /// functions marked by `()`. Returns `None` if there is no such cycle, which means the entry can
+  /// only produce a finite number of outputs.
+  pub(crate) fn find_recursion(&self, name: &str) -> Option<Vec<String>> {
+    self.find_recursion_from(
+      Reference::Variable(name),
+      &mut Vec::new(),
+      &mut HashSet::new(),
+    )
+  }
+
+  fn find_recursion_from<'s>(
+    &'s self,
+    reference: Reference<'s>,
+    path: &mut Vec<Reference<'s>>,
+    finished: &mut HashSet<Reference<'s>>,
+  ) -> Option<Vec<String>> {
+    if let Some(start) = path.iter().position(|visited| *visited == reference) {
+      let mut cycle: Vec<_> = path[start..].iter().map(Reference::to_string).collect();
+      cycle.push(reference.to_string());
+      return Some(cycle);
+    }
+
+    if finished.contains(&reference) {
+      return None;
+    }
+
+    let (body, mut locals) = match reference {
+      Reference::Variable(name) => (self.variables.get(name)?, Vec::new()),
+      Reference::Function(name) => {
+        let function = self.functions.get(name)?;
+        let parameters = function.parameters.iter().map(String::as_str).collect();
+        (&function.body, parameters)
+      }
+    };
+
+    let mut references = Vec::new();
+    Self::collect_references(body, &mut locals, &mut references);
+
+    path.push(reference);
+
+    for next in references {
+      if let Some(cycle) = self.find_recursion_from(next, path, finished) {
+        return Some(cycle);
+      }
+    }
+
+    path.pop();
+    finished.insert(reference);
+    None
+  }
+
+  /// Collects every global variable and user defined function an expression refers to directly.
+  fn collect_references<'s>(
+    expression: &'s Expression,
+    locals: &mut Vec<&'s str>,
+    references: &mut Vec<Reference<'s>>,
+  ) {
+    match &expression.kind {
+      ExpressionKind::LiteralE(_) => {}
+      ExpressionKind::VariableE(name) => {
+        if !locals.contains(&name.as_str()) {
+          references.push(Reference::Variable(name));
+        }
+      }
+      ExpressionKind::PatternE(pattern) => {
+        for part in &pattern.parts {
+          Self::collect_references(part, locals, references);
+        }
+      }
+      ExpressionKind::BagE(bag) => {
+        for item in &bag.items {
+          Self::collect_references(item, locals, references);
+        }
+      }
+      ExpressionKind::TableE(table) => {
+        for (row, base) in table.rows.iter().zip(&table.bases) {
+          let depth = locals.len();
+
+          if let Some(base) = base {
+            Self::collect_references(base, locals, references);
+            locals.push(ROW_BASE);
+          }
+
+          for item in row.iter().flatten() {
+            Self::collect_references(item, locals, references);
+          }
+
+          locals.truncate(depth);
+        }
+      }
+      ExpressionKind::PropertyAccessE(target, _) => {
+        Self::collect_references(target, locals, references);
+      }
+      ExpressionKind::CallE(function, arguments) => {
+        if let FunctionLike::UserDefined(name) = function {
+          references.push(Reference::Function(name));
+        }
+
+        for argument in arguments {
+          Self::collect_references(argument, locals, references);
+        }
+      }
+      ExpressionKind::WithE(bindings, body) => {
+        let depth = locals.len();
+
+        for binding in bindings {
+          Self::collect_references(&binding.expression, locals, references);
+          locals.push(&binding.name);
+        }
+
+        Self::collect_references(body, locals, references);
+        locals.truncate(depth);
+      }
+    }
+  }
+}
+
+/// A global variable or a user defined function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Reference<'s> {
+  Variable(&'s str),
+  Function(&'s str),
+}
+
+impl Display for Reference<'_> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Reference::Variable(name) => write!(f, "{name}"),
+      Reference::Function(name) => write!(f, "{name}()"),
+    }
+  }
+}
+
+/// A variable bound by `with` or a function parameter, along with its shape if it is known.
+type Local<'s> = (&'s str, Option<StaticShape<'s>>);
+
+#[derive(Debug, Clone, Copy)]
+enum StaticShape<'s> {
+  Table(&'s Table),
+  Row(&'s Table),
+}
+
+/// Why a list of weights can't be used for sampling.
+enum WeightProblem {
+  NoItem,
+  Invalid { index: usize, weight: f32 },
+  AllZero,
+  Overflow,
+  TooMany,
+}
+
+fn weighted_distribution(weights: &[f32]) -> Result<WeightedIndex<f32>, WeightProblem> {
+  // WeightedIndex accepts infinite weights, but panics if the total weight is not finite
+  if let Some((index, weight)) = weights
+    .iter()
+    .enumerate()
+    .find(|(_, weight)| !(weight.is_finite() && **weight >= 0.0))
+  {
+    return Err(WeightProblem::Invalid {
+      index,
+      weight: *weight,
+    });
+  }
+
+  if !weights.iter().sum::<f32>().is_finite() {
+    return Err(WeightProblem::Overflow);
+  }
+
+  WeightedIndex::new(weights).map_err(|err| match err {
+    WeightedError::NoItem => WeightProblem::NoItem,
+    WeightedError::AllWeightsZero => WeightProblem::AllZero,
+    WeightedError::TooMany => WeightProblem::TooMany,
+    WeightedError::InvalidWeight => unreachable!("weights were already validated"),
+  })
+}
+
+/// Extends the base of a table row with the expression of an append entry.
+fn append(base: Expression, suffix: Expression) -> Expression {
+  let span = suffix.span;
+
+  // Suffixes with vowel placeholders agree with the base, which is only known at runtime unless
+  // both are literals
+  let kind = match (&base.kind, &suffix.kind) {
+    _ if !has_harmony_placeholder(&suffix) => ExpressionKind::PatternE(Pattern {
+      parts: vec![base, suffix],
+    }),
+    (ExpressionKind::LiteralE(base), ExpressionKind::LiteralE(suffix)) => {
+      ExpressionKind::LiteralE(string_utils::harmonise(base, suffix))
+    }
+    _ => ExpressionKind::CallE(
+      FunctionLike::BuiltIn(BuiltInFunction::Harmonise),
+      vec![base, suffix],
+    ),
+  };
+
+  Expression { kind, span }
+}
+
+/// Adds a problem to a list, flattening problems which consist of several others.
+fn push_problem(problems: &mut Vec<CompilerError>, problem: CompilerError) {
+  match problem {
+    CompilerError::Multiple(inner) => problems.extend(inner),
+    problem => problems.push(problem),
+  }
+}
+
+/// Combines the problems found so far into a single error, if there are any.
+fn combine_problems(mut problems: Vec<CompilerError>) -> Result<(), CompilerError> {
+  match problems.len() {
+    0 => Ok(()),
+    1 => Err(problems.remove(0)),
+    _ => Err(CompilerError::Multiple(problems)),
+  }
+}
+
+/// Collects every value, or every problem if there are any, so that a problem in one part of an
+/// expression doesn't hide the problems in the others.
+fn collect_all<T>(
+  results: impl IntoIterator<Item = Result<T, CompilerError>>,
+) -> Result<Vec<T>, CompilerError> {
+  let mut values = Vec::new();
+  let mut problems = Vec::new();
+
+  for result in results {
+    match result {
+      Ok(value) => values.push(value),
+      Err(problem) => push_problem(&mut problems, problem),
+    }
+  }
+
+  combine_problems(problems).map(|()| values)
+}
+
+/// Whether the text of an expression contains a vowel harmony placeholder such as `{a}`.
+fn has_harmony_placeholder(expression: &Expression) -> bool {
+  match &expression.kind {
+    ExpressionKind::LiteralE(text) => string_utils::has_harmony_placeholder(text),
+    ExpressionKind::PatternE(pattern) => pattern.parts.iter().any(has_harmony_placeholder),
+    ExpressionKind::BagE(bag) => bag.items.iter().any(has_harmony_placeholder),
+    ExpressionKind::CallE(_, arguments) => arguments.iter().any(has_harmony_placeholder),
+    ExpressionKind::WithE(_, body) => has_harmony_placeholder(body),
+    ExpressionKind::VariableE(_)
+    | ExpressionKind::TableE(_)
+    | ExpressionKind::PropertyAccessE(..) => false,
+  }
+}
+
+/// Reads the article exceptions a script defines, which must be a table of literal strings with the
+/// columns `word` and `article`.
+fn article_exceptions<'e>(expression: &'e Expression) -> Result<Articles, CompilerError> {
+  let invalid = |problem: &str, span| CompilerError::InvalidArticleExceptions {
+    problem: problem.to_string(),
+    span,
+  };
+
+  let ExpressionKind::TableE(table) = &expression.kind else {
+    return Err(invalid("expected a table", expression.span));
+  };
+
+  let column = |name| {
+    table
+      .columns
+      .iter()
+      .position(|column| column == name)
+      .ok_or_else(|| invalid("expected the columns .word and .article", expression.span))
+  };
+  let (word, article) = (column("word")?, column("article")?);
+
+  let mut articles = Articles::default();
+
+  for row in &table.rows {
+    let literal = |cell: &'e Option<Expression>| match cell {
+      Some(Expression {
+        kind: ExpressionKind::LiteralE(literal),
+        ..
+      }) => Ok(literal.as_str()),
+      Some(cell) => Err(invalid("every cell must be a string literal", cell.span)),
+      None => Err(invalid("the table must not have holes", expression.span)),
+    };
+
+    let prefix = literal(&row[word])?;
+    let cell_span = row[article]
+      .as_ref()
+      .map_or(expression.span, |cell| cell.span);
+    let article = Article::try_parse(literal(&row[article])?)
+      .ok_or_else(|| invalid("every article must be \"a\" or \"an\"", cell_span))?;
+
+    articles.add_exception(prefix, article);
+  }
+
+  Ok(articles)
 }
 
 pub fn compile_script(statements: Vec<ast::Statement>) -> Result<CompiledScript, CompilerError> {
+  compile(statements, true)
+}
+
+/// Compiles a script which is not meant to be run on its own, so it doesn't have to define
+/// `result`. Its variables can still be evaluated with [`CompiledScript::run_entry`].
+pub fn compile_library(statements: Vec<ast::Statement>) -> Result<CompiledScript, CompilerError> {
+  compile(statements, false)
+}
+
+/// Compiles every statement and runs all static checks, collecting every problem instead of
+/// stopping at the first one. Used by `haraldi check`.
+pub fn check_statements(
+  statements: Vec<ast::Statement>,
+  require_result: bool,
+) -> (CompiledScript, Vec<CompilerError>) {
   let mut script = CompiledScript::new();
+  let mut problems = Vec::new();
+  let mut failed = HashSet::new();
+  // Unlike in the REPL, defining a name twice is a mistake, so the first definition is kept
+  let mut variables: HashMap<String, Span> = HashMap::new();
+  let mut functions: HashMap<String, Span> = HashMap::new();
 
   for statement in statements {
-    script.add_statement(statement)?;
+    let name = match &statement {
+      ast::Statement::AssignmentS(assignment) => assignment.name.clone(),
+      ast::Statement::FunctionS(definition) => definition.name.clone(),
+      ast::Statement::ImportS(import) => import.path.clone(),
+    };
+
+    let redefinition = match &statement {
+      ast::Statement::AssignmentS(assignment) => match variables.entry(name.clone()) {
+        Entry::Occupied(first) => Some(CompilerError::RedefinedVariable {
+          name: name.clone(),
+          span: assignment.span,
+          previous: *first.get(),
+        }),
+        Entry::Vacant(entry) => {
+          entry.insert(assignment.span);
+          None
+        }
+      },
+      ast::Statement::FunctionS(definition) => match functions.entry(name.clone()) {
+        Entry::Occupied(first) => Some(CompilerError::RedefinedFunction {
+          name: name.clone(),
+          span: definition.span,
+          previous: *first.get(),
+        }),
+        Entry::Vacant(entry) => {
+          entry.insert(definition.span);
+          None
+        }
+      },
+      ast::Statement::ImportS(_) => None,
+    };
+
+    if let Some(problem) = redefinition {
+      problems.push(problem);
+      continue;
+    }
+
+    if let Err(problem) = script.add_statement(statement) {
+      push_problem(&mut problems, problem);
+      failed.insert(name);
+    }
   }
 
-  Ok(script)
+  // References to statements which failed to compile would only be noise
+  problems.extend(script.check().into_iter().filter(|problem| match problem {
+    CompilerError::UndefinedVariable { name, .. } | CompilerError::UnknownFunction { name, .. } => {
+      !failed.contains(name)
+    }
+    _ => true,
+  }));
+
+  if require_result && !script.variables.contains_key("result") && !failed.contains("result") {
+    problems.push(CompilerError::MissingResult);
+  }
+
+  problems.sort_by_key(|problem| problem.span().map(|span| span.start));
+
+  (script, problems)
+}
+
+fn compile(
+  statements: Vec<ast::Statement>,
+  require_result: bool,
+) -> Result<CompiledScript, CompilerError> {
+  let (script, problems) = check_statements(statements, require_result);
+  combine_problems(problems).map(|()| script)
 }
 
 #[cfg(test)]
 mod tests {
-  use super::{ast, compile_script};
+  use matches::assert_matches;
+
+  use super::{
+    ast, compile_script, BuiltInFunction, CompilerError, ExecutionError, FunctionError,
+    FunctionLike, InterpreterError, MAX_CALL_DEPTH,
+  };
 
   #[test]
   fn eval_literal() {
     let compiled = compile_script(vec![ast::Statement::AssignmentS(ast::Assignment {
       name: String::from("result"),
-      value: Box::new(ast::Expression::LiteralE(String::from("Hello, world!"))),
+      sticky: false,
+      private: false,
+      value: Box::new(ast::Expression::literal("Hello, world!")),
+      span: crate::source::Span::default(),
     })])
     .unwrap();
 
@@ -607,14 +2478,344 @@ mod tests {
   fn eval_upper_first() {
     let compiled = compile_script(vec![ast::Statement::AssignmentS(ast::Assignment {
       name: String::from("result"),
-      value: Box::new(ast::Expression::CallE(
+      sticky: false,
+      private: false,
+      value: Box::new(ast::Expression::from(ast::ExpressionKind::CallE(
         String::from("capitalise"),
-        vec![ast::Expression::LiteralE(String::from("robert"))],
-      )),
+        vec![ast::Expression::literal("robert")],
+      ))),
+      span: crate::source::Span::default(),
     })])
     .unwrap();
 
     let output = compiled.run().unwrap();
     assert_eq!(output, "Robert");
   }
+
+  fn run(source: &str) -> String {
+    crate::run_script(source).unwrap()
+  }
+
+  /// Runs a script which fails in a built-in function, returning the function and the problem.
+  fn function_error(source: &str) -> (BuiltInFunction, FunctionError) {
+    let mut error = match crate::run_script(source) {
+      Err(ExecutionError::Interpreter(error)) => error,
+      otherwise => panic!("Expected an interpreter error, got {otherwise:?}"),
+    };
+
+    while let InterpreterError::At { inner, .. } = error {
+      error = *inner;
+    }
+
+    match error {
+      InterpreterError::FunctionError {
+        function: FunctionLike::BuiltIn(function),
+        inner,
+      } => (function, inner),
+      otherwise => panic!("Expected a function error, got {otherwise:?}"),
+    }
+  }
+
+  #[test]
+  fn eval_case_functions() {
+    assert_eq!(run(r#"result = upper("sieni ä");"#), "SIENI Ä");
+    assert_eq!(run(r#"result = lower("SIENI Ä");"#), "sieni ä");
+    assert_eq!(
+      run(r#"result = titleCase("paistettu silakka");"#),
+      "Paistettu Silakka"
+    );
+  }
+
+  #[test]
+  fn eval_trim_and_reverse() {
+    assert_eq!(run(r#"result = trim({ "  " "keitto" " " });"#), "keitto");
+    assert_eq!(run(r#"result = reverse("äiti");"#), "itiä");
+  }
+
+  #[test]
+  fn eval_replace() {
+    assert_eq!(
+      run(r#"result = replace("kala ja kala", "kala", "liha");"#),
+      "liha ja liha"
+    );
+    assert_matches!(
+      function_error(r#"result = replace("kala", "", "x");"#),
+      (
+        BuiltInFunction::Replace,
+        FunctionError::InvalidArgument { n: 2, .. }
+      )
+    );
+    assert_matches!(
+      function_error(r#"result = replace(repeat("a", "1000"), "a", repeat("b", "2000"));"#),
+      (
+        BuiltInFunction::Replace,
+        FunctionError::OutputTooLong { .. }
+      )
+    );
+  }
+
+  #[test]
+  fn eval_repeat() {
+    assert_eq!(run(r#"result = repeat("ha", "3");"#), "hahaha");
+    assert_eq!(run(r#"result = repeat("ha", " 0 ");"#), "");
+    assert_matches!(
+      function_error(r#"result = repeat("ha", "many");"#),
+      (
+        BuiltInFunction::Repeat,
+        FunctionError::InvalidArgument { n: 2, was, .. }
+      ) if was == "many"
+    );
+    assert_matches!(
+      function_error(r#"result = repeat("ha", "1000000");"#),
+      (BuiltInFunction::Repeat, FunctionError::OutputTooLong { .. })
+    );
+  }
+
+  #[test]
+  fn eval_join() {
+    assert_eq!(
+      run(r#"result = join(", ", "kala", "", bag ["peruna"]);"#),
+      "kala, peruna"
+    );
+    assert_eq!(run(r#"result = join("-");"#), "");
+    assert_matches!(
+      function_error(r#"result = join();"#),
+      (
+        BuiltInFunction::Join,
+        FunctionError::TooFewArguments {
+          expected: 1,
+          was: 0
+        }
+      )
+    );
+
+    let separator = r#"repeat("-", "1000")"#;
+    let parts = vec![r#""a""#; 1100].join(", ");
+    assert_matches!(
+      function_error(&format!("result = join({separator}, {parts});")),
+      (BuiltInFunction::Join, FunctionError::OutputTooLong { .. })
+    );
+
+    let parts = vec![r#""a""#; 300].join(", ");
+    assert_eq!(
+      run(&format!(r#"result = length(join("", {parts}));"#)),
+      "300"
+    );
+    assert_matches!(
+      function_error(&format!("result = upper({parts});")),
+      (
+        BuiltInFunction::Upper,
+        FunctionError::WrongNumberOfArguments {
+          expected: 1,
+          was: 300
+        }
+      )
+    );
+  }
+
+  #[test]
+  fn eval_truncate() {
+    assert_eq!(
+      run(r#"result = truncate("mustikkapiirakka", "8");"#),
+      "mustikka"
+    );
+    assert_eq!(run(r#"result = truncate("öljy", "10");"#), "öljy");
+    assert_matches!(
+      function_error(r#"result = truncate("öljy", "-1");"#),
+      (
+        BuiltInFunction::Truncate,
+        FunctionError::InvalidArgument { n: 2, .. }
+      )
+    );
+  }
+
+  #[test]
+  fn eval_length_guards() {
+    assert_eq!(run(r#"result = length("äiti");"#), "4");
+    assert_eq!(run(r#"result = ifShorter("kala", "5", "x");"#), "kala");
+    assert_eq!(run(r#"result = ifShorter("kalat", "5", "x");"#), "x");
+    assert_eq!(run(r#"result = ifLonger("kalat", "4", "x");"#), "kalat");
+    assert_eq!(run(r#"result = ifLonger("kala", "4", "x");"#), "x");
+  }
+
+  #[test]
+  fn eval_article() {
+    assert_eq!(run(r#"result = article("omena");"#), "an omena");
+    assert_eq!(
+      run(r#"result = article({ "hour" "glass" });"#),
+      "an hourglass"
+    );
+    assert_eq!(run(r#"result = article("FBI agent");"#), "an FBI agent");
+    assert_eq!(run(r#"result = article("university");"#), "a university");
+  }
+
+  #[test]
+  fn eval_article_exceptions() {
+    let source = r#"
+      articleExceptions = table [[.word, .article], ["herb", "an"], ["hour", "a"]];
+      result = { article("herb") ", " article("hourglass") ", " article("honest man") };
+    "#;
+    assert_eq!(run(source), "an herb, a hourglass, an honest man");
+  }
+
+  #[test]
+  fn eval_vowel_harmony_appends() {
+    let source = r#"
+      sana = table [
+          [.perus, .inessiivi],
+          ["talo", +"ss{a}"],
+          ["kylä", +"ss{a}"],
+          [{ bag ["kesä", "puna"] "kurpitsa" }, +{ "ss" "{a}" }]
+      ];
+      result = with s = row(sana) in s.inessiivi;
+    "#;
+    let script = crate::compile_script(source).unwrap();
+
+    let mut outputs: Vec<_> = (0..40)
+      .map(|seed| script.run_with_seed(seed).unwrap())
+      .collect();
+    outputs.sort();
+    outputs.dedup();
+    assert_eq!(
+      outputs,
+      vec!["kesäkurpitsassa", "kylässä", "punakurpitsassa", "talossa"]
+    );
+
+    assert_eq!(run(r#"result = harmonise("syö", "k{o}");"#), "syökö");
+  }
+
+  #[test]
+  fn eval_row_appends_share_their_base() {
+    let source = r#"
+      sana = table [
+          [.perus, .inessiivi],
+          [bag ["kesä", "puna", "kilo"], +"ss{a}"],
+          [{ bag ["a", "b"] }, +"!"]
+      ];
+      result = with s = row(sana) in { s.perus " / " s.inessiivi };
+    "#;
+    let script = crate::compile_script(source).unwrap();
+
+    let mut outputs: Vec<_> = (0..60)
+      .map(|seed| script.run_with_seed(seed).unwrap())
+      .collect();
+    outputs.sort();
+    outputs.dedup();
+    assert_eq!(
+      outputs,
+      vec![
+        "a / a!",
+        "b / b!",
+        "kesä / kesässä",
+        "kilo / kilossa",
+        "puna / punassa"
+      ]
+    );
+
+    // Columns are still sampled independently
+    let column = crate::compile_script(
+      r#"t = table [ [.a, .b], [bag ["x", "y"], +"!"] ]; result = { t.a t.b };"#,
+    )
+    .unwrap();
+    let outputs: Vec<_> = (0..40)
+      .map(|seed| column.run_with_seed(seed).unwrap())
+      .collect();
+    assert!(outputs.contains(&"xy!".to_string()));
+  }
+
+  #[test]
+  fn invalid_article_exceptions() {
+    for source in [
+      r#"articleExceptions = bag ["an"]; result = "";"#,
+      r#"articleExceptions = table [[.word], ["herb"]]; result = "";"#,
+      r#"articleExceptions = table [[.word, .article], ["herb", "the"]]; result = "";"#,
+      r#"articleExceptions = table [[.word, .article], ["herb", { "a" "n" }]]; result = "";"#,
+    ] {
+      assert!(
+        matches!(
+          crate::compile_script(source),
+          Err(ExecutionError::Compiler(
+            CompilerError::InvalidArticleExceptions { .. }
+          ))
+        ),
+        "{source}"
+      );
+    }
+  }
+
+  #[test]
+  fn eval_text_function_argument_checks() {
+    assert_matches!(
+      function_error(r#"result = upper("a", "b");"#),
+      (
+        BuiltInFunction::Upper,
+        FunctionError::WrongNumberOfArguments {
+          expected: 1,
+          was: 2
+        }
+      )
+    );
+    assert_matches!(
+      function_error(r#"t = table [[.a], ["x"]]; result = upper(row(t));"#),
+      (
+        BuiltInFunction::Upper,
+        FunctionError::UnexpectedArgumentType {
+          n: 1,
+          expected: "string",
+          was: "row"
+        }
+      )
+    );
+    assert_matches!(
+      function_error(r#"t = table [[.a], ["x"]]; result = join(" ", "a", t);"#),
+      (
+        BuiltInFunction::Join,
+        FunctionError::UnexpectedArgumentType {
+          n: 3,
+          was: "table",
+          ..
+        }
+      )
+    );
+  }
+
+  #[test]
+  fn eval_recursion_limit() {
+    for source in [
+      r#"f(x) = f(x); result = f("a");"#,
+      r#"f(x) = { "(" f({ x bag ["a", "b"] }) ")" }; result = f("a");"#,
+      r#"f(x) = with y = g(x) in y; g(x) = upper(f(x)); result = f("a");"#,
+    ] {
+      let mut error = match crate::run_script(source) {
+        Err(ExecutionError::Interpreter(error)) => error,
+        otherwise => panic!("Expected an interpreter error, got {otherwise:?}"),
+      };
+
+      while let InterpreterError::At { inner, .. } = error {
+        error = *inner;
+      }
+
+      assert_matches!(
+        error,
+        InterpreterError::RecursionLimit {
+          limit: MAX_CALL_DEPTH,
+          ..
+        }
+      );
+    }
+
+    let nested = r#"f(x) = { x "." }; g(x) = f(f(f(x))); result = g(g("a"));"#;
+    assert_eq!(run(nested), "a......");
+  }
+
+  #[test]
+  fn eval_maybe_append_reports_itself() {
+    assert_matches!(
+      function_error(r#"result = maybeAppend("a");"#),
+      (
+        BuiltInFunction::MaybeAppend,
+        FunctionError::WrongNumberOfArguments { .. }
+      )
+    );
+  }
 }
@@ -0,0 +1,638 @@
+//! Pretty-printer which turns parsed scripts back into canonically formatted source code.
+//!
+//! Comments are not part of the syntax tree, so they're collected from the source separately. While
+//! printing, every comment is emitted at the first point where a new line starts after it: before
+//! a statement, a multi-line bag entry, a table row or a closing bracket. Comments which followed
+//! code on the same line are appended to the previous line instead. Expressions which contain
+//! comments anywhere else, such as between the parts of a pattern, are copied from the source as
+//! they are, so that the comments stay next to their code.
+
+use crate::ast::{
+  Assignment, Bag, Expression, ExpressionKind, Statement, Table, TableEntry, TableRow,
+};
+use crate::parser::{self, ParseError};
+use crate::source::Span;
+
+const INDENT: &str = "    ";
+
+/// Bags which don't fit on a line of this many characters are split on multiple lines.
+const MAX_WIDTH: usize = 100;
+
+/// Parses and formats a whole script.
+pub fn format_source(source: &str) -> Result<String, ParseError> {
+  let statements = parser::parse_script(source)?;
+
+  let mut formatter = Formatter {
+    source,
+    comments: find_comments(source),
+    next_comment: 0,
+    last_end: 0,
+    output: String::new(),
+    indent: 0,
+    at_line_start: true,
+    line_comment: false,
+  };
+
+  formatter.program(&statements);
+  Ok(formatter.output)
+}
+
+/// Returns the text of every comment in the source, in order.
+pub fn comments(source: &str) -> Vec<&str> {
+  find_comments(source)
+    .into_iter()
+    .map(|comment| comment.text)
+    .collect()
+}
+
+struct Comment<'s> {
+  text: &'s str,
+  span: Span,
+  /// Whether there is code before the comment on the same line
+  trailing: bool,
+  /// Whether the comment was already printed as part of an expression copied from the source
+  copied: bool,
+}
+
+fn find_comments(source: &str) -> Vec<Comment<'_>> {
+  let mut comments = Vec::new();
+  let bytes = source.as_bytes();
+  let mut i = 0;
+
+  while i < bytes.len() {
+    match bytes[i] {
+      b'"' => {
+        i += 1;
+
+        while i < bytes.len() && bytes[i] != b'"' {
+          // Skip over escaped characters, including quotes
+          i += if bytes[i] == b'\\' { 2 } else { 1 };
+        }
+
+        i += 1;
+      }
+      b'/' if matches!(bytes.get(i + 1), Some(b'/') | Some(b'*')) => {
+        let start = i;
+
+        let end = if bytes[i + 1] == b'/' {
+          source[i..]
+            .find(['\r', '\n'])
+            .map_or(source.len(), |len| i + len)
+        } else {
+          source[i + 2..]
+            .find("*/")
+            .map_or(source.len(), |len| i + 2 + len + 2)
+        };
+
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+
+        comments.push(Comment {
+          text: &source[start..end],
+          span: Span::new(start, end),
+          trailing: !source[line_start..start].trim().is_empty(),
+          copied: false,
+        });
+
+        i = end;
+      }
+      _ => i += 1,
+    }
+  }
+
+  comments
+}
+
+struct Formatter<'s> {
+  source: &'s str,
+  comments: Vec<Comment<'s>>,
+  next_comment: usize,
+  /// End of the last item that was printed, in the source. Used to preserve blank lines.
+  last_end: usize,
+  output: String,
+  indent: usize,
+  at_line_start: bool,
+  /// Whether the last line ends with a line comment, so nothing more can be appended to it
+  line_comment: bool,
+}
+
+impl<'s> Formatter<'s> {
+  fn write(&mut self, text: &str) {
+    if self.at_line_start {
+      for _ in 0..self.indent {
+        self.output.push_str(INDENT);
+      }
+
+      self.at_line_start = false;
+      self.line_comment = false;
+    }
+
+    self.output.push_str(text);
+  }
+
+  fn newline(&mut self) {
+    self.output.push('\n');
+    self.at_line_start = true;
+  }
+
+  fn column(&self) -> usize {
+    let line_start = self.output.rfind('\n').map_or(0, |i| i + 1);
+    let written = self.output[line_start..].chars().count();
+
+    if self.at_line_start {
+      written + self.indent * INDENT.len()
+    } else {
+      written
+    }
+  }
+
+  /// Keeps a single blank line before an item starting at `start`, if there was one in the source.
+  fn separate(&mut self, start: usize) {
+    let between: Vec<_> = self.source[self.last_end.min(start)..start]
+      .split('\n')
+      .collect();
+
+    // Lines other than the first and the last are whole lines between the two items
+    let blank_line = between.len() > 2
+      && between[1..between.len() - 1]
+        .iter()
+        .any(|line| line.trim().is_empty());
+
+    if blank_line && !self.output.is_empty() && !self.output.ends_with("\n\n") {
+      self.newline();
+    }
+  }
+
+  /// Emits every comment which starts before `offset`. Must be called at the start of a line.
+  fn comments_before(&mut self, offset: usize) {
+    while let Some(comment) = self.comments.get(self.next_comment) {
+      if comment.span.start >= offset {
+        break;
+      }
+
+      let text = comment.text;
+      let span = comment.span;
+      let trailing = comment.trailing
+        && !self.line_comment
+        && self.output.ends_with('\n')
+        && !self.output.ends_with("\n\n");
+      self.next_comment += 1;
+
+      if comment.copied {
+        continue;
+      }
+
+      if trailing {
+        // Append to the end of the previous line
+        self.output.pop();
+        self.output.push(' ');
+        self.output.push_str(text);
+        self.output.push('\n');
+      } else {
+        self.separate(span.start);
+        self.write(text);
+        self.newline();
+      }
+
+      self.line_comment = text.starts_with("//");
+
+      self.last_end = span.end;
+    }
+  }
+
+  /// Starts an item which begins on its own line.
+  fn item(&mut self, start: usize) {
+    self.comments_before(start);
+    self.separate(start);
+  }
+
+  fn program(&mut self, statements: &[Statement]) {
+    for statement in statements {
+      let (start, end) = match statement {
+        Statement::AssignmentS(assignment) => (assignment.span.start, assignment.value.span.end),
+        Statement::FunctionS(definition) => (definition.span.start, definition.body.span.end),
+        Statement::ImportS(import) => (import.span.start, import.span.end),
+      };
+
+      self.item(start);
+
+      match statement {
+        Statement::AssignmentS(assignment) => {
+          if assignment.private {
+            self.write("private ");
+          }
+
+          let declaration = declaration(assignment);
+          self.write(&declaration);
+          self.block(&assignment.value);
+        }
+        Statement::FunctionS(definition) => {
+          if definition.private {
+            self.write("private ");
+          }
+
+          self.write(&format!(
+            "{}({}) = ",
+            definition.name,
+            definition.parameters.join(", ")
+          ));
+          self.block(&definition.body);
+        }
+        Statement::ImportS(import) => {
+          self.write(&format!("import {}", string_literal(&import.path)));
+
+          if let Some(alias) = &import.alias {
+            self.write(&format!(" as {alias}"));
+          }
+        }
+      }
+
+      self.write(";");
+      self.newline();
+      self.last_end = end;
+    }
+
+    self.comments_before(self.source.len());
+  }
+
+  /// Prints an expression which may span multiple lines.
+  fn block(&mut self, expression: &Expression) {
+    match &expression.kind {
+      ExpressionKind::TableE(table) => self.table(table, expression.span),
+      ExpressionKind::BagE(bag)
+        if self.column() + inline(expression).chars().count() > MAX_WIDTH
+          || self.has_comments_in(expression.span) =>
+      {
+        self.bag(bag, expression.span)
+      }
+      ExpressionKind::WithE(bindings, body)
+        if !self.has_comments_in(Span::new(expression.span.start, body.span.start)) =>
+      {
+        let bindings: Vec<_> = bindings.iter().map(binding).collect();
+        self.write(&format!("with {} in ", bindings.join(", ")));
+        self.block(body);
+      }
+      _ => {
+        let text = self.inline(expression);
+        self.write(&text);
+      }
+    }
+  }
+
+  fn has_comments_in(&self, span: Span) -> bool {
+    self.comments[self.next_comment..].iter().any(|comment| {
+      !comment.copied && comment.span.start >= span.start && comment.span.end <= span.end
+    })
+  }
+
+  /// Formats an expression on a single line, unless it contains comments. Then it's copied from
+  /// the source instead, comments included.
+  fn inline(&mut self, expression: &Expression) -> String {
+    let span = expression.span;
+
+    if !self.has_comments_in(span) {
+      return inline(expression);
+    }
+
+    for comment in &mut self.comments[self.next_comment..] {
+      if comment.span.start >= span.start && comment.span.end <= span.end {
+        comment.copied = true;
+      }
+    }
+
+    self.source[span.start..span.end].to_string()
+  }
+
+  fn table_entry(&mut self, entry: &TableEntry) -> String {
+    match entry {
+      TableEntry::Hole => String::from("_"),
+      TableEntry::Literal(expression) => self.inline(expression),
+      TableEntry::Append(expression) => format!("+{}", self.inline(expression)),
+    }
+  }
+
+  fn bag(&mut self, bag: &Bag, span: Span) {
+    self.write("bag [");
+    self.newline();
+    self.indent += 1;
+    self.last_end = span.start;
+
+    for (i, entry) in bag.items.iter().enumerate() {
+      self.item(entry.span.start);
+
+      if let Some(weight) = entry.weight {
+        self.write(&format!("{} ", weight));
+      }
+
+      let value = self.inline(&entry.value);
+      self.write(&value);
+
+      if i + 1 < bag.items.len() {
+        self.write(",");
+      }
+
+      self.newline();
+      self.last_end = entry.span.end;
+    }
+
+    self.comments_before(span.end);
+    self.indent -= 1;
+    self.write("]");
+  }
+
+  fn table(&mut self, table: &Table, span: Span) {
+    let header: Vec<_> = table
+      .columns
+      .iter()
+      .map(|column| format!(".{}", column))
+      .collect();
+
+    let rows: Vec<Vec<String>> = table
+      .rows
+      .iter()
+      .map(|row| {
+        row
+          .items
+          .iter()
+          .map(|item| self.table_entry(item))
+          .collect()
+      })
+      .collect();
+
+    // Every cell except the last one in a row is followed by a comma, which is part of its width
+    let column_count = rows.iter().map(Vec::len).chain([header.len()]).max();
+    let mut widths = vec![0; column_count.unwrap_or(0)];
+
+    for cells in rows.iter().chain([&header]) {
+      for (i, cell) in cells.iter().enumerate() {
+        widths[i] = widths[i].max(cell.chars().count());
+      }
+    }
+
+    let weights: Vec<_> = table.rows.iter().map(row_weight).collect();
+    let weight_width = weights.iter().map(String::len).max().unwrap_or(0);
+
+    self.write("table [");
+    self.newline();
+    self.indent += 1;
+    self.last_end = span.start;
+
+    self.item(table.header_span.start);
+    self.write(&grid_row("", weight_width, &header, &widths));
+    self.write(",");
+    self.newline();
+    self.last_end = table.header_span.end;
+
+    for (i, (row, cells)) in table.rows.iter().zip(&rows).enumerate() {
+      self.item(row.span.start);
+      self.write(&grid_row(&weights[i], weight_width, cells, &widths));
+
+      if i + 1 < table.rows.len() {
+        self.write(",");
+      }
+
+      self.newline();
+      self.last_end = row.span.end;
+    }
+
+    self.comments_before(span.end);
+    self.indent -= 1;
+    self.write("]");
+  }
+}
+
+fn declaration(assignment: &Assignment) -> String {
+  match assignment.sticky {
+    true => format!("sticky {} = ", assignment.name),
+    false => format!("{} = ", assignment.name),
+  }
+}
+
+fn binding(assignment: &Assignment) -> String {
+  format!("{}{}", declaration(assignment), inline(&assignment.value))
+}
+
+fn row_weight(row: &TableRow) -> String {
+  row
+    .weight
+    .map(|weight| weight.to_string())
+    .unwrap_or_default()
+}
+
+/// Formats a table row with each cell padded to the width of its column.
+fn grid_row(weight: &str, weight_width: usize, cells: &[String], widths: &[usize]) -> String {
+  let mut line = String::new();
+
+  if weight_width > 0 {
+    line.push_str(&format!("{:<width$} ", weight, width = weight_width));
+  }
+
+  line.push('[');
+
+  for (i, cell) in cells.iter().enumerate() {
+    if i + 1 < cells.len() {
+      // The padding goes after the comma, so that the commas stay next to the values
+      let cell = format!("{},", cell);
+      line.push_str(&format!("{:<width$} ", cell, width = widths[i] + 1));
+    } else {
+      line.push_str(cell);
+    }
+  }
+
+  line.push(']');
+  line
+}
+
+fn table_entry(entry: &TableEntry) -> String {
+  match entry {
+    TableEntry::Hole => String::from("_"),
+    TableEntry::Literal(expression) => inline(expression),
+    TableEntry::Append(expression) => format!("+{}", inline(expression)),
+  }
+}
+
+fn string_literal(literal: &str) -> String {
+  let escaped = literal.replace('\\', "\\\\").replace('"', "\\\"");
+  format!("\"{}\"", escaped)
+}
+
+/// Formats an expression on a single line.
+fn inline(expression: &Expression) -> String {
+  match &expression.kind {
+    ExpressionKind::LiteralE(literal) => string_literal(literal),
+    ExpressionKind::VariableE(name) => name.clone(),
+    ExpressionKind::PatternE(pattern) if pattern.parts.is_empty() => String::from("{}"),
+    ExpressionKind::PatternE(pattern) => {
+      let parts: Vec<_> = pattern.parts.iter().map(inline).collect();
+      format!("{{ {} }}", parts.join(" "))
+    }
+    ExpressionKind::BagE(bag) => {
+      let items: Vec<_> = bag
+        .items
+        .iter()
+        .map(|entry| match entry.weight {
+          Some(weight) => format!("{} {}", weight, inline(&entry.value)),
+          None => inline(&entry.value),
+        })
+        .collect();
+
+      format!("bag [{}]", items.join(", "))
+    }
+    ExpressionKind::TableE(table) => {
+      let header: Vec<_> = table
+        .columns
+        .iter()
+        .map(|column| format!(".{}", column))
+        .collect();
+
+      let rows = table.rows.iter().map(|row| {
+        let cells: Vec<_> = row.items.iter().map(table_entry).collect();
+
+        match row.weight {
+          Some(weight) => format!("{} [{}]", weight, cells.join(", ")),
+          None => format!("[{}]", cells.join(", ")),
+        }
+      });
+
+      let rows: Vec<_> = [format!("[{}]", header.join(", "))]
+        .into_iter()
+        .chain(rows)
+        .collect();
+
+      format!("table [{}]", rows.join(", "))
+    }
+    ExpressionKind::PropertyAccessE(target, property) => {
+      format!("{}.{}", inline(target), property)
+    }
+    ExpressionKind::CallE(function, arguments) => {
+      let arguments: Vec<_> = arguments.iter().map(inline).collect();
+      format!("{}({})", function, arguments.join(", "))
+    }
+    ExpressionKind::WithE(bindings, body) => {
+      let bindings: Vec<_> = bindings.iter().map(binding).collect();
+      format!("with {} in {}", bindings.join(", "), inline(body))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::format_source;
+
+  #[test]
+  fn format_aligns_table() {
+    let source = r#"noun = table [
+  [.base, .plural],
+  2 ["cat", +"s"],
+  ["mouse", "mice"], 0.5 [_, "geese"]
+];"#;
+
+    assert_eq!(
+      format_source(source).unwrap(),
+      r#"noun = table [
+        [.base,   .plural],
+    2   ["cat",   +"s"],
+        ["mouse", "mice"],
+    0.5 [_,       "geese"]
+];
+"#
+    );
+  }
+
+  #[test]
+  fn format_inline_expressions() {
+    let source = r#"
+    wrap( x,y )= {"(" x   ")"};
+    sticky  hero=bag[ 2 "a",{wrap("b")}, "c\"d" ];
+    result = with  r = row(t) ,  sticky h = hero in { r.base h };
+    "#;
+
+    assert_eq!(
+      format_source(source).unwrap(),
+      r#"wrap(x, y) = { "(" x ")" };
+sticky hero = bag [2 "a", { wrap("b") }, "c\"d"];
+result = with r = row(t), sticky h = hero in { r.base h };
+"#
+    );
+  }
+
+  #[test]
+  fn format_keeps_comments() {
+    let source = r#"// Greeting
+greeting = bag [ // The options
+  "Hello", // English
+  /* Finnish */ "Moi"
+];
+
+/* The end */ result = greeting; // trailing
+// Last"#;
+
+    assert_eq!(
+      format_source(source).unwrap(),
+      r#"// Greeting
+greeting = bag [ // The options
+    "Hello", // English
+    /* Finnish */
+    "Moi"
+];
+
+/* The end */
+result = greeting; // trailing
+// Last
+"#
+    );
+  }
+
+  #[test]
+  fn format_imports() {
+    let source = r#"import   "common/names.hd"  as names ;import"x.hd";
+result = names.greet( names.firstName.base );"#;
+
+    assert_eq!(
+      format_source(source).unwrap(),
+      r#"import "common/names.hd" as names;
+import "x.hd";
+result = names.greet(names.firstName.base);
+"#
+    );
+  }
+
+  #[test]
+  fn format_private_definitions() {
+    let source = r#"private  sticky helper = "a"; private wrap( x )= { "(" x ")" };"#;
+
+    assert_eq!(
+      format_source(source).unwrap(),
+      r#"private sticky helper = "a";
+private wrap(x) = { "(" x ")" };
+"#
+    );
+  }
+
+  #[test]
+  fn format_keeps_comments_inside_expressions() {
+    let source = r#"result = { "a" // first
+    "b" /* mid */ "c" };
+names = bag [ "x", { "y" /* inner */ "z" } ];
+"#;
+
+    assert_eq!(
+      format_source(source).unwrap(),
+      r#"result = { "a" // first
+    "b" /* mid */ "c" };
+names = bag [
+    "x",
+    { "y" /* inner */ "z" }
+];
+"#
+    );
+  }
+
+  #[test]
+  fn format_splits_long_bags() {
+    let source = format!("result = bag [{}];", ["\"abcdefghijklmnop\""; 8].join(", "));
+    let formatted = format_source(&source).unwrap();
+
+    assert_eq!(formatted.lines().count(), 10);
+    assert!(formatted.starts_with("result = bag [\n    \"abcdefghijklmnop\",\n"));
+  }
+}
@@ -1,18 +1,94 @@
+use std::path::Path;
+
+pub mod articles;
 mod ast;
+pub mod batch;
+pub mod enumerate;
 pub mod eval;
+pub mod format;
+pub mod loader;
+// Vendored as it is upstream
+#[allow(clippy::needless_lifetimes)]
+#[rustfmt::skip]
 mod nom_unicode;
+pub mod optimise;
+#[allow(mismatched_lifetime_syntaxes)]
 pub mod parser;
+pub mod source;
+pub mod stats;
 pub mod string_utils;
+pub mod trace;
+pub mod vm;
 
-pub fn compile_script(script: &str) -> Result<eval::CompiledScript, eval::CompilerError> {
-  match parser::parse_program(script) {
-    Err(nom::Err::Error(err)) => panic!("Parse error: {}", nom::error::convert_error(script, err)),
-    Err(err) => panic!("Parse error: {}", err),
-    Ok((_, parsed)) => eval::compile_script(parsed),
-  }
+/// Compiles a script. Its imports are resolved relative to the current directory; use
+/// [`loader::Loader`] to load scripts from files and to render errors in imported files.
+pub fn compile_script(script: &str) -> Result<eval::CompiledScript, eval::ExecutionError> {
+  loader::Loader::new().compile_source(script, Path::new(""), true)
+}
+
+/// Compiles a script which doesn't need to define `result`, such as a collection of generators
+/// which are run with [`eval::CompiledScript::run_entry`].
+pub fn compile_library(script: &str) -> Result<eval::CompiledScript, eval::ExecutionError> {
+  loader::Loader::new().compile_source(script, Path::new(""), false)
 }
 
 pub fn run_script(script: &str) -> Result<String, eval::ExecutionError> {
   let script = compile_script(script)?;
   Ok(script.run()?)
 }
+
+/// Everything [`check_script`] found wrong with a script.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+  pub errors: Vec<eval::ExecutionError>,
+  pub warnings: Vec<eval::CompilerWarning>,
+}
+
+impl Diagnostics {
+  pub fn is_empty(&self) -> bool {
+    self.errors.is_empty() && self.warnings.is_empty()
+  }
+
+  /// Renders every error and warning in compiler style, in the order they appear in the source.
+  pub fn render(&self, file_name: &str, source: &str) -> String {
+    self
+      .messages()
+      .into_iter()
+      .map(|(span, message)| source::render_message(file_name, source, span, &message))
+      .collect::<Vec<_>>()
+      .join("\n")
+  }
+
+  /// Every error and warning along with its location, in the order they appear in the source.
+  /// Notes follow the error they belong to.
+  pub fn messages(&self) -> Vec<(Option<source::Span>, String)> {
+    let errors = self.errors.iter().map(|error| {
+      let (message, note) = match error {
+        eval::ExecutionError::Parser(error) => (error.message(), None),
+        eval::ExecutionError::Compiler(error) => (error.to_string(), error.note()),
+        eval::ExecutionError::Interpreter(error) => (error.to_string(), None),
+        eval::ExecutionError::Import(error) => (error.to_string(), None),
+      };
+
+      let mut messages = vec![(error.span(), format!("error: {message}"))];
+      messages.extend(note.map(|(span, note)| (Some(span), format!("note: {note}"))));
+      messages
+    });
+
+    let warnings = self
+      .warnings
+      .iter()
+      .map(|warning| vec![(Some(warning.span()), format!("warning: {warning}"))]);
+
+    let mut messages: Vec<_> = errors.chain(warnings).collect();
+    messages.sort_by_key(|messages| messages[0].0.map(|span| span.start));
+    messages.into_iter().flatten().collect()
+  }
+}
+
+/// Runs every static check on a script without running it, collecting all problems instead of
+/// stopping at the first one. Syntax errors stop checking, because the rest of the script can't be
+/// parsed reliably.
+pub fn check_script(script: &str, require_result: bool) -> Diagnostics {
+  loader::Loader::new().check_source(script, Path::new(""), require_result)
+}
@@ -0,0 +1,501 @@
+//! Loading scripts from files, along with the files they import.
+//!
+//! `import "common/names.hd";` adds every variable and function of another file to the importing
+//! script, and `import "common/names.hd" as names;` adds them under a namespace, so that they are
+//! accessed as `names.firstName` and called as `names.fullName()`. Paths are relative to the
+//! importing file.
+//!
+//! Imports are resolved before compiling: the statements of every imported file are collected
+//! into one script, with the names defined in namespaced files prefixed with their namespace.
+//! Spans of every file are offset so that they don't overlap, which lets the [`Loader`] tell which
+//! file an error is in.
+
+use std::{
+  collections::{HashMap, HashSet},
+  fs, io,
+  path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::{
+  ast::{Expression, ExpressionKind, Statement, TableEntry, VisitSpans},
+  eval::{self, BuiltInFunction, CompiledScript, ExecutionError},
+  parser,
+  source::{render_message, Span},
+  Diagnostics,
+};
+
+#[derive(Error, Debug)]
+pub enum ImportError {
+  #[error("Could not read {}: {error}", .path.display())]
+  Unreadable {
+    path: PathBuf,
+    error: io::Error,
+    /// Span of the import statement, if the file was imported
+    span: Option<Span>,
+  },
+
+  #[error("Import cycle: {}", .cycle.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(" -> "))]
+  Cycle { cycle: Vec<PathBuf>, span: Span },
+}
+
+impl ImportError {
+  pub fn span(&self) -> Option<Span> {
+    match self {
+      ImportError::Unreadable { span, .. } => *span,
+      ImportError::Cycle { span, .. } => Some(*span),
+    }
+  }
+}
+
+/// A file which is part of a loaded script.
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+  pub path: PathBuf,
+  pub source: String,
+  /// Where the file starts in the spans of the loaded script
+  pub offset: usize,
+}
+
+/// Loads scripts and everything they import, and renders errors in any of the loaded files.
+#[derive(Debug, Default)]
+pub struct Loader {
+  files: Vec<SourceFile>,
+  /// Namespaces defined by each file which has been loaded, by canonical path and name prefix
+  loaded: HashMap<(PathBuf, String), HashSet<String>>,
+  /// Canonical and displayed paths of the files being loaded, the outermost first
+  stack: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Loader {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Compiles the script in the file at `path`, along with everything it imports.
+  pub fn compile_file(&mut self, path: impl AsRef<Path>) -> Result<CompiledScript, ExecutionError> {
+    let statements = self.load_file(path.as_ref())?;
+    Ok(eval::compile_script(statements)?)
+  }
+
+  /// Like [`Loader::compile_file`], but the script doesn't need to define `result`.
+  pub fn compile_library_file(
+    &mut self,
+    path: impl AsRef<Path>,
+  ) -> Result<CompiledScript, ExecutionError> {
+    let statements = self.load_file(path.as_ref())?;
+    Ok(eval::compile_library(statements)?)
+  }
+
+  /// Compiles a script which isn't stored in a file. Its imports are resolved relative to
+  /// `directory`.
+  pub fn compile_source(
+    &mut self,
+    source: &str,
+    directory: &Path,
+    require_result: bool,
+  ) -> Result<CompiledScript, ExecutionError> {
+    let mut statements = Vec::new();
+    self.add_source(
+      Path::new("<script>"),
+      source,
+      directory,
+      "",
+      false,
+      &mut statements,
+    )?;
+
+    match require_result {
+      true => Ok(eval::compile_script(statements)?),
+      false => Ok(eval::compile_library(statements)?),
+    }
+  }
+
+  /// Runs every static check on the script in the file at `path` and everything it imports.
+  pub fn check_file(&mut self, path: impl AsRef<Path>, require_result: bool) -> Diagnostics {
+    let statements = self.load_file(path.as_ref());
+    check(statements, require_result)
+  }
+
+  /// Like [`Loader::check_file`], for a script which isn't stored in a file.
+  pub fn check_source(
+    &mut self,
+    source: &str,
+    directory: &Path,
+    require_result: bool,
+  ) -> Diagnostics {
+    let mut statements = Vec::new();
+    let loaded = self.add_source(
+      Path::new("<script>"),
+      source,
+      directory,
+      "",
+      false,
+      &mut statements,
+    );
+    check(loaded.map(|_| statements), require_result)
+  }
+
+  /// Every file loaded so far, in the order they were read.
+  pub fn files(&self) -> &[SourceFile] {
+    &self.files
+  }
+
+  /// Renders an error like a compiler would, pointing at the file it occurred in.
+  pub fn render(&self, error: &ExecutionError) -> String {
+    self.render_messages(error.messages())
+  }
+
+  /// Renders every error and warning, pointing at the files they occurred in.
+  pub fn render_diagnostics(&self, diagnostics: &Diagnostics) -> String {
+    self.render_messages(diagnostics.messages())
+  }
+
+  /// Finds the file a span of the loaded script is in, and the span within that file.
+  pub fn locate(&self, span: Span) -> Option<(&SourceFile, Span)> {
+    let file = self
+      .files
+      .iter()
+      .rev()
+      .find(|file| file.offset <= span.start)?;
+    let span = Span::new(span.start - file.offset, span.end - file.offset);
+    Some((file, span))
+  }
+
+  fn render_messages(&self, messages: Vec<(Option<Span>, String)>) -> String {
+    messages
+      .into_iter()
+      .map(
+        |(span, message)| match span.and_then(|span| self.locate(span)) {
+          Some((file, span)) => render_message(
+            &file.path.display().to_string(),
+            &file.source,
+            Some(span),
+            &message,
+          ),
+          None => {
+            let file_name = self
+              .files
+              .first()
+              .map(|file| file.path.display().to_string());
+            render_message(
+              file_name.as_deref().unwrap_or("<script>"),
+              "",
+              None,
+              &message,
+            )
+          }
+        },
+      )
+      .collect::<Vec<_>>()
+      .join("\n")
+  }
+
+  fn load_file(&mut self, path: &Path) -> Result<Vec<Statement>, ExecutionError> {
+    let mut statements = Vec::new();
+    self.load(path, "", None, &mut statements)?;
+    Ok(statements)
+  }
+
+  /// Loads a file and the files it imports, appending their statements to `statements`. Returns
+  /// the namespaces the file can access. Files which have already been loaded with the same prefix
+  /// aren't loaded again.
+  fn load(
+    &mut self,
+    path: &Path,
+    prefix: &str,
+    span: Option<Span>,
+    statements: &mut Vec<Statement>,
+  ) -> Result<HashSet<String>, ExecutionError> {
+    let unreadable = |error| ImportError::Unreadable {
+      path: path.to_path_buf(),
+      error,
+      span,
+    };
+
+    let canonical = fs::canonicalize(path).map_err(unreadable)?;
+
+    if let Some(start) = self.stack.iter().position(|(file, _)| *file == canonical) {
+      let mut cycle: Vec<_> = self.stack[start..]
+        .iter()
+        .map(|(_, path)| path.clone())
+        .collect();
+      cycle.push(path.to_path_buf());
+
+      return Err(
+        ImportError::Cycle {
+          cycle,
+          span: span.unwrap_or_default(),
+        }
+        .into(),
+      );
+    }
+
+    let key = (canonical.clone(), prefix.to_string());
+
+    if let Some(namespaces) = self.loaded.get(&key) {
+      return Ok(namespaces.clone());
+    }
+
+    let source = fs::read_to_string(path).map_err(unreadable)?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+
+    self.stack.push((canonical, path.to_path_buf()));
+    let namespaces = self.add_source(path, &source, directory, prefix, span.is_some(), statements);
+    self.stack.pop();
+
+    let namespaces = namespaces?;
+    self.loaded.insert(key, namespaces.clone());
+    Ok(namespaces)
+  }
+
+  fn add_source(
+    &mut self,
+    path: &Path,
+    source: &str,
+    directory: &Path,
+    prefix: &str,
+    imported: bool,
+    statements: &mut Vec<Statement>,
+  ) -> Result<HashSet<String>, ExecutionError> {
+    let offset = self
+      .files
+      .last()
+      .map_or(0, |file| file.offset + file.source.len() + 1);
+
+    self.files.push(SourceFile {
+      path: path.to_path_buf(),
+      source: source.to_string(),
+      offset,
+    });
+
+    let mut parsed = parser::parse_script(source).map_err(|mut error| {
+      error.offset += offset;
+      error
+    })?;
+
+    parsed.visit_spans(&mut |span| *span = Span::new(span.start + offset, span.end + offset));
+
+    let mut namespaces = HashSet::new();
+    let mut own = Vec::new();
+
+    for statement in parsed {
+      let Statement::ImportS(import) = statement else {
+        own.push(statement);
+        continue;
+      };
+
+      let path = directory.join(&import.path);
+
+      match &import.alias {
+        Some(alias) => {
+          let prefix = format!("{prefix}{alias}.");
+          let nested = self.load(&path, &prefix, Some(import.span), statements)?;
+
+          namespaces.extend(
+            nested
+              .iter()
+              .map(|namespace| format!("{alias}.{namespace}")),
+          );
+          namespaces.insert(alias.clone());
+        }
+        None => namespaces.extend(self.load(&path, prefix, Some(import.span), statements)?),
+      }
+    }
+
+    let mut private_variables = HashSet::new();
+    let mut private_functions = HashSet::new();
+
+    // Private definitions get names which can't be written in a script, so only the file which
+    // defines them can use them. Nothing can import the outermost file, so its names are kept.
+    if imported {
+      for statement in &own {
+        match statement {
+          Statement::AssignmentS(assignment) if assignment.private => {
+            private_variables.insert(assignment.name.clone());
+          }
+          Statement::FunctionS(definition) if definition.private => {
+            private_functions.insert(definition.name.clone());
+          }
+          _ => {}
+        }
+      }
+    }
+
+    let mut qualifier = Qualifier {
+      prefix,
+      private_suffix: format!("@{}", path.display()),
+      private_variables: &private_variables,
+      private_functions: &private_functions,
+      namespaces: &namespaces,
+      locals: Vec::new(),
+    };
+
+    for mut statement in own {
+      qualifier.statement(&mut statement);
+      statements.push(statement);
+    }
+
+    Ok(namespaces)
+  }
+}
+
+fn check(statements: Result<Vec<Statement>, ExecutionError>, require_result: bool) -> Diagnostics {
+  let statements = match statements {
+    Ok(statements) => statements,
+    Err(error) => {
+      return Diagnostics {
+        errors: vec![error],
+        warnings: Vec::new(),
+      }
+    }
+  };
+
+  let (compiled, errors) = eval::check_statements(statements, require_result);
+
+  Diagnostics {
+    errors: errors.into_iter().map(Into::into).collect(),
+    warnings: compiled.into_warnings(),
+  }
+}
+
+/// Rewrites the names used in a file to the names they have in the loaded script.
+struct Qualifier<'a> {
+  /// Prefix of every name defined in the file, such as `names.`
+  prefix: &'a str,
+  /// Suffix of the names of private definitions, such as `@common/names.hd`
+  private_suffix: String,
+  private_variables: &'a HashSet<String>,
+  private_functions: &'a HashSet<String>,
+  /// Namespaces the file can access, such as `names` and `names.common`
+  namespaces: &'a HashSet<String>,
+  /// Local variables and parameters in scope, which shadow everything else
+  locals: Vec<String>,
+}
+
+impl Qualifier<'_> {
+  fn statement(&mut self, statement: &mut Statement) {
+    if self.prefix.is_empty()
+      && self.namespaces.is_empty()
+      && self.private_variables.is_empty()
+      && self.private_functions.is_empty()
+    {
+      return;
+    }
+
+    match statement {
+      Statement::AssignmentS(assignment) => {
+        assignment.name = self.variable(&assignment.name);
+        self.expression(&mut assignment.value);
+      }
+      Statement::FunctionS(definition) => {
+        definition.name = self.function(&definition.name);
+        self.locals.clone_from(&definition.parameters);
+        self.expression(&mut definition.body);
+        self.locals.clear();
+      }
+      Statement::ImportS(_) => {}
+    }
+  }
+
+  fn expression(&mut self, expression: &mut Expression) {
+    if let ExpressionKind::PropertyAccessE(target, property) = &expression.kind {
+      if let Some(namespace) = self.namespace(target) {
+        let name = format!("{}{namespace}.{property}", self.prefix);
+        expression.kind = ExpressionKind::VariableE(name);
+        return;
+      }
+    }
+
+    match &mut expression.kind {
+      ExpressionKind::LiteralE(_) => {}
+      ExpressionKind::VariableE(name) => {
+        if !self.locals.contains(name) {
+          *name = self.variable(name);
+        }
+      }
+      ExpressionKind::BagE(bag) => {
+        for entry in &mut bag.items {
+          self.expression(&mut entry.value);
+        }
+      }
+      ExpressionKind::PatternE(pattern) => {
+        for part in &mut pattern.parts {
+          self.expression(part);
+        }
+      }
+      ExpressionKind::PropertyAccessE(target, _) => self.expression(target),
+      ExpressionKind::TableE(table) => {
+        for entry in table.rows.iter_mut().flat_map(|row| row.items.iter_mut()) {
+          match entry {
+            TableEntry::Hole => {}
+            TableEntry::Literal(expression) | TableEntry::Append(expression) => {
+              self.expression(expression)
+            }
+          }
+        }
+      }
+      ExpressionKind::CallE(name, arguments) => {
+        match name.rsplit_once('.') {
+          Some((namespace, _)) if self.namespaces.contains(namespace) => {
+            *name = format!("{}{name}", self.prefix);
+          }
+          None if BuiltInFunction::try_parse(name).is_none() => *name = self.function(name),
+          _ => {}
+        }
+
+        for argument in arguments {
+          self.expression(argument);
+        }
+      }
+      ExpressionKind::WithE(bindings, body) => {
+        let depth = self.locals.len();
+
+        for binding in bindings.iter_mut() {
+          self.expression(&mut binding.value);
+          self.locals.push(binding.name.clone());
+        }
+
+        self.expression(body);
+        self.locals.truncate(depth);
+      }
+    }
+  }
+
+  /// The name a global variable defined in this file has in the loaded script.
+  fn variable(&self, name: &str) -> String {
+    match self.private_variables.contains(name) {
+      true => format!("{}{name}{}", self.prefix, self.private_suffix),
+      false => format!("{}{name}", self.prefix),
+    }
+  }
+
+  /// The name a function defined in this file has in the loaded script.
+  fn function(&self, name: &str) -> String {
+    match self.private_functions.contains(name) {
+      true => format!("{}{name}{}", self.prefix, self.private_suffix),
+      false => format!("{}{name}", self.prefix),
+    }
+  }
+
+  /// The namespace an expression such as `names` or `names.common` refers to, if any.
+  fn namespace(&self, expression: &Expression) -> Option<String> {
+    let path = dotted_path(expression)?;
+    let root = path.split('.').next().unwrap_or_default();
+
+    (!self.locals.iter().any(|local| local == root) && self.namespaces.contains(&path))
+      .then_some(path)
+  }
+}
+
+/// Turns a chain of property accesses like `a.b.c` into a dotted path.
+fn dotted_path(expression: &Expression) -> Option<String> {
+  match &expression.kind {
+    ExpressionKind::VariableE(name) => Some(name.clone()),
+    ExpressionKind::PropertyAccessE(target, property) => {
+      Some(format!("{}.{property}", dotted_path(target)?))
+    }
+    _ => None,
+  }
+}
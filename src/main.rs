@@ -1,20 +1,490 @@
-use std::{fs, io::Write, path::PathBuf};
+use std::{
+  collections::HashSet,
+  fs::{self, File},
+  io::{BufWriter, Write},
+  path::{Path, PathBuf},
+};
 
-use harald::eval::{CompiledScript, NameHint};
+use anyhow::anyhow;
+use clap::{Parser, Subcommand, ValueEnum};
+use harald::eval::{CompiledScript, ExecutionError, NameHint};
 use harald::{
-  compile_script,
-  parser::{parse_expression, parse_statement},
+  enumerate::{self, EnumerationError},
+  format::format_source,
+  loader::Loader,
+  parser::{parse_expression, parse_source, parse_statement},
+  stats::{Stats, VariableStats},
+  trace::Trace,
 };
 
-fn run_file(path: &str) -> Result<(), anyhow::Error> {
-  let path = PathBuf::from(path);
-  let source = fs::read_to_string(path)?;
+/// Generates text from Harald scripts. Without a file, starts a REPL.
+#[derive(Parser, Debug)]
+#[command(name = "haraldi", args_conflicts_with_subcommands = true)]
+struct Cli {
+  #[command(subcommand)]
+  command: Option<Command>,
+
+  /// Script to run
+  file: Option<PathBuf>,
+
+  /// Number of outputs to generate
+  #[arg(short = 'n', long = "count", default_value_t = 10)]
+  count: usize,
+
+  /// Variable to evaluate instead of `result`. The script doesn't need to define `result` when
+  /// this is set.
+  #[arg(long)]
+  entry: Option<String>,
+
+  /// Seed for the random number generator, to get the same outputs every time
+  #[arg(long)]
+  seed: Option<u64>,
+
+  /// Drop outputs which have already been generated
+  #[arg(long)]
+  unique: bool,
+
+  /// How the outputs are written
+  #[arg(long, value_enum, default_value_t = Format::Text)]
+  format: Format,
+
+  /// Write outputs to a file instead of stdout
+  #[arg(short, long)]
+  output: Option<PathBuf>,
+
+  /// Show which bag entries and table rows were chosen to produce each output
+  #[arg(long)]
+  trace: bool,
+
+  /// Number of threads generating outputs. The outputs for a seed are the same for any number of
+  /// threads.
+  #[arg(short, long, default_value_t = 1)]
+  jobs: usize,
+
+  /// Run the script as it was written instead of simplifying it first. Seeded outputs then match
+  /// `CompiledScript::run_with_seed`, and traces show the bags as they were written.
+  #[arg(long)]
+  no_optimise: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Compiles scripts and reports every problem found, without running them. Exits with a non-zero
+  /// status if there are any errors or warnings.
+  Check {
+    /// Scripts to check
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// Don't require the scripts to define `result`
+    #[arg(long)]
+    library: bool,
+  },
+
+  /// Rewrites scripts in the canonical format
+  Fmt {
+    /// Scripts to format in place
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// Don't write anything, but exit with a non-zero status if any file isn't formatted
+    #[arg(long)]
+    check: bool,
+  },
+
+  /// Lists every output a script can produce along with its probability, the most likely first
+  Enumerate {
+    /// Script to enumerate
+    file: PathBuf,
+
+    /// Variable to enumerate instead of `result`
+    #[arg(long)]
+    entry: Option<String>,
+
+    /// Give up once the script has more than this many ways of producing an output
+    #[arg(long, default_value_t = enumerate::DEFAULT_LIMIT)]
+    limit: usize,
+
+    /// How the outputs are written
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+  },
+
+  /// Reports how varied the outputs of each variable are, computed from the script without
+  /// running it
+  Stats {
+    /// Script to analyse
+    file: PathBuf,
+  },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+  /// One output per line
+  Text,
+  /// A JSON array of strings
+  Json,
+  /// One JSON string per line
+  Jsonl,
+}
+
+/// Compiles the script in a file along with everything it imports. The loader is returned too, so
+/// that errors in any of the files can be rendered.
+fn load_script(path: &Path, library: bool) -> Result<(CompiledScript, Loader), anyhow::Error> {
+  let mut loader = Loader::new();
+
+  let script = match library {
+    true => loader.compile_library_file(path),
+    false => loader.compile_file(path),
+  };
+
+  match script {
+    Ok(script) => Ok((script, loader)),
+    Err(err) => Err(anyhow!(loader.render(&err))),
+  }
+}
+
+fn run_file(path: &Path, cli: &Cli) -> Result<(), anyhow::Error> {
+  let (mut script, loader) = load_script(path, cli.entry.is_some())?;
+  let render = |err: ExecutionError| anyhow!(loader.render(&err));
+  let entry = cli.entry.as_deref().unwrap_or("result");
+
+  // Optimising changes which random choices are made, so a seed gives the same outputs only with
+  // the same optimisation setting. Traces use the same script as plain runs so that they match.
+  if !cli.no_optimise {
+    script.optimise();
+  }
+
+  // The output file only replaces an existing one once generation has succeeded
+  let mut pending = None;
+  let mut open_writer = || -> std::io::Result<Box<dyn Write>> {
+    Ok(match &cli.output {
+      Some(path) => {
+        let (file, temporary) = PendingFile::create(path)?;
+        pending = Some(temporary);
+        Box::new(BufWriter::new(file))
+      }
+      None => Box::new(BufWriter::new(std::io::stdout().lock())),
+    })
+  };
+
+  let mut seen = HashSet::new();
+  let mut writer;
+
+  if cli.trace {
+    let mut traces = script
+      .trace_entry_batch(entry, cli.count, cli.seed)
+      .map_err(|err| render(err.into()))?;
+
+    if cli.unique {
+      traces.retain(|trace| seen.insert(trace.output.clone()));
+    }
+
+    writer = open_writer()?;
+    write_traces(&mut writer, &traces, cli.format)?;
+  } else if cli.jobs == 1 && !cli.unique && cli.format == Format::Text {
+    // Plain outputs are streamed, so even huge batches never have to fit in memory
+    let mut sink = IoSink {
+      inner: open_writer()?,
+      error: None,
+    };
+
+    let result = script
+      .lower()
+      .write_entry_batch(entry, cli.count, cli.seed, &mut sink);
+
+    if let Some(error) = sink.error {
+      return Err(error.into());
+    }
+
+    result.map_err(|err| render(err.into()))?;
+    writer = sink.inner;
+  } else {
+    let mut outputs = script
+      .lower()
+      .generate_entry_batch(entry, cli.count, cli.jobs, cli.seed)
+      .map_err(|err| render(err.into()))?;
+
+    if cli.unique {
+      outputs.retain(|output| seen.insert(output.clone()));
+    }
+
+    writer = open_writer()?;
+    write_outputs(&mut writer, &outputs, cli.format)?;
+  }
+
+  writer.flush()?;
+  drop(writer);
+
+  if let Some(pending) = pending {
+    pending.persist()?;
+  }
+
+  Ok(())
+}
+
+/// A file which is written under a temporary name next to its destination, and replaces the
+/// destination once it's persisted. If it never is, it's removed when dropped, so a failed run
+/// leaves an existing file as it was.
+struct PendingFile {
+  temporary: PathBuf,
+  path: PathBuf,
+  persisted: bool,
+}
+
+impl PendingFile {
+  fn create(path: &Path) -> std::io::Result<(File, PendingFile)> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temporary = path.with_file_name(format!(".{name}.{}.tmp", std::process::id()));
+    let file = File::create(&temporary)?;
+
+    let pending = PendingFile {
+      temporary,
+      path: path.to_path_buf(),
+      persisted: false,
+    };
+
+    Ok((file, pending))
+  }
+
+  fn persist(mut self) -> std::io::Result<()> {
+    fs::rename(&self.temporary, &self.path)?;
+    self.persisted = true;
+    Ok(())
+  }
+}
+
+impl Drop for PendingFile {
+  fn drop(&mut self) {
+    if !self.persisted {
+      let _ = fs::remove_file(&self.temporary);
+    }
+  }
+}
+
+fn enumerate_file(
+  path: &Path,
+  entry: Option<&str>,
+  limit: usize,
+  format: Format,
+) -> Result<(), anyhow::Error> {
+  let (script, loader) = load_script(path, entry.is_some())?;
+  let render = |err: ExecutionError| anyhow!(loader.render(&err));
+  let entry = entry.unwrap_or("result");
+
+  let enumeration_error = |err: EnumerationError| match err {
+    EnumerationError::Interpreter(err) => render(err.into()),
+    err => anyhow!(err),
+  };
+
+  let outcomes = script
+    .distribution_with_limit(entry, limit)
+    .map_err(enumeration_error)?;
+
+  let mut writer = BufWriter::new(std::io::stdout().lock());
+
+  if let Format::Json = format {
+    write!(writer, "[")?;
+  }
+
+  for (i, outcome) in outcomes.into_iter().enumerate() {
+    let json = || serde_json::json!({ "text": outcome.text, "probability": outcome.probability });
+
+    match format {
+      Format::Text => writeln!(writer, "{}\t{}", outcome.probability, outcome.text)?,
+      Format::Json => {
+        let separator = if i == 0 { "" } else { "," };
+        write!(writer, "{separator}\n  {}", json())?;
+      }
+      Format::Jsonl => writeln!(writer, "{}", json())?,
+    }
+  }
+
+  if let Format::Json = format {
+    writeln!(writer, "\n]")?;
+  }
+
+  writer.flush()?;
+  Ok(())
+}
+
+fn stats_file(path: &Path) -> Result<(), anyhow::Error> {
+  let (script, _) = load_script(path, true)?;
+
+  let mut writer = BufWriter::new(std::io::stdout().lock());
+
+  for (name, stats) in script.stats() {
+    match stats {
+      VariableStats::Text(stats) => write_stats(&mut writer, &name, &stats)?,
+      VariableStats::Table(columns) => {
+        for (column, stats) in columns {
+          write_stats(&mut writer, &format!("{name}.{column}"), &stats)?;
+        }
+      }
+      VariableStats::Recursive(cycle) => writeln!(
+        writer,
+        "{name}: infinite outputs (recursive: {})",
+        cycle.join(" -> ")
+      )?,
+      VariableStats::NotText => writeln!(writer, "{name}: not text")?,
+    }
+  }
+
+  writer.flush()?;
+  Ok(())
+}
+
+fn write_stats(writer: &mut impl Write, name: &str, stats: &Stats) -> std::io::Result<()> {
+  let (bound, estimated) = match stats.exact {
+    true => ("", ""),
+    false => ("at most ", " (estimated)"),
+  };
+
+  let outputs = match stats.outputs < 1e15 {
+    true => format!("{}", stats.outputs),
+    false => format!("{:.2e}", stats.outputs),
+  };
+
+  writeln!(
+    writer,
+    "{name}: {bound}{outputs} outputs, {bound}{:.2} bits of entropy{estimated}",
+    stats.entropy,
+  )?;
+  writeln!(
+    writer,
+    "  length: {} to {}, {:.2} expected",
+    stats.min_length, stats.max_length, stats.expected_length
+  )?;
+  writeln!(
+    writer,
+    "  most likely: {:?} ({})",
+    stats.most_likely.text,
+    format_probability(stats.most_likely.probability)
+  )?;
+  writeln!(
+    writer,
+    "  least likely: {:?} ({})",
+    stats.least_likely.text,
+    format_probability(stats.least_likely.probability)
+  )
+}
+
+fn format_probability(probability: f64) -> String {
+  if probability >= 0.0001 {
+    format!("{:.2}%", probability * 100.0)
+  } else {
+    format!("{:.2e}", probability)
+  }
+}
+
+/// Returns whether all of the files passed the checks.
+fn check_files(files: &[PathBuf], library: bool) -> Result<bool, anyhow::Error> {
+  let mut ok = true;
+
+  for path in files {
+    let mut loader = Loader::new();
+    let diagnostics = loader.check_file(path, !library);
+
+    if !diagnostics.is_empty() {
+      ok = false;
+      eprintln!("{}", loader.render_diagnostics(&diagnostics));
+    }
+  }
+
+  Ok(ok)
+}
 
-  let script = compile_script(&source)?;
+/// Returns whether all of the files were already formatted.
+fn format_files(files: &[PathBuf], check: bool) -> Result<bool, anyhow::Error> {
+  let mut ok = true;
 
-  for _ in 0..10 {
-    let output = script.run()?;
-    println!("{}", output);
+  for path in files {
+    let source = fs::read_to_string(path)?;
+    let file_name = path.display().to_string();
+
+    let formatted = format_source(&source)
+      .map_err(|err| anyhow!(ExecutionError::from(err).render(&file_name, &source)))?;
+
+    if formatted == source {
+      continue;
+    }
+
+    ok = false;
+
+    if check {
+      eprintln!("{} is not formatted", file_name);
+    } else {
+      fs::write(path, formatted)?;
+    }
+  }
+
+  Ok(ok)
+}
+
+/// Adapts an `io::Write` into the `fmt::Write` scripts are run into, keeping the I/O error which
+/// `fmt::Error` can't carry.
+struct IoSink<W> {
+  inner: W,
+  error: Option<std::io::Error>,
+}
+
+impl<W: Write> std::fmt::Write for IoSink<W> {
+  fn write_str(&mut self, s: &str) -> std::fmt::Result {
+    self.inner.write_all(s.as_bytes()).map_err(|error| {
+      self.error = Some(error);
+      std::fmt::Error
+    })
+  }
+}
+
+fn write_outputs(
+  writer: &mut impl Write,
+  outputs: &[String],
+  format: Format,
+) -> Result<(), anyhow::Error> {
+  match format {
+    Format::Text => {
+      for output in outputs {
+        writeln!(writer, "{}", output)?;
+      }
+    }
+    Format::Json => {
+      serde_json::to_writer_pretty(&mut *writer, outputs)?;
+      writeln!(writer)?;
+    }
+    Format::Jsonl => {
+      for output in outputs {
+        serde_json::to_writer(&mut *writer, output)?;
+        writeln!(writer)?;
+      }
+    }
+  }
+
+  Ok(())
+}
+
+fn write_traces(
+  writer: &mut impl Write,
+  traces: &[Trace],
+  format: Format,
+) -> Result<(), anyhow::Error> {
+  match format {
+    Format::Text => {
+      for trace in traces {
+        writeln!(writer, "{}", trace)?;
+      }
+    }
+    Format::Json => {
+      let traces: Vec<_> = traces.iter().map(Trace::to_json).collect();
+      serde_json::to_writer_pretty(&mut *writer, &traces)?;
+      writeln!(writer)?;
+    }
+    Format::Jsonl => {
+      for trace in traces {
+        serde_json::to_writer(&mut *writer, &trace.to_json())?;
+        writeln!(writer)?;
+      }
+    }
   }
 
   Ok(())
@@ -41,8 +511,8 @@ fn run_repl() -> Result<(), anyhow::Error> {
       ":q" | ":exit" => {
         break;
       }
-      statement if command.ends_with(';') => {
-        let statement = parse_statement(statement);
+      source if command.ends_with(';') => {
+        let statement = parse_source(source, parse_statement);
         match statement {
           Err(err) => {
             println!("Invalid statement: {}", err);
@@ -63,8 +533,8 @@ fn run_repl() -> Result<(), anyhow::Error> {
           }
         }
       }
-      expression => {
-        let expression = parse_expression(expression);
+      source => {
+        let expression = parse_source(source, parse_expression);
 
         match expression {
           Err(err) => {
@@ -103,12 +573,35 @@ fn run_repl() -> Result<(), anyhow::Error> {
 }
 
 fn main() -> Result<(), anyhow::Error> {
-  let args = std::env::args().skip(1).collect::<Vec<_>>();
+  let cli = Cli::parse();
 
-  let file_path = args.get(0);
+  match &cli.command {
+    Some(Command::Check { files, library }) => {
+      if !check_files(files, *library)? {
+        std::process::exit(1);
+      }
+
+      return Ok(());
+    }
+    Some(Command::Fmt { files, check }) => {
+      if !format_files(files, *check)? && *check {
+        std::process::exit(1);
+      }
+
+      return Ok(());
+    }
+    Some(Command::Enumerate {
+      file,
+      entry,
+      limit,
+      format,
+    }) => return enumerate_file(file, entry.as_deref(), *limit, *format),
+    Some(Command::Stats { file }) => return stats_file(file),
+    None => {}
+  }
 
-  match file_path {
-    Some(file_path) => run_file(file_path)?,
+  match &cli.file {
+    Some(file_path) => run_file(file_path, &cli)?,
     None => run_repl()?,
   };
 
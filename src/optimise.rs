@@ -0,0 +1,514 @@
+//! Passes which simplify a compiled script without changing what it can produce.
+//!
+//! - Patterns made only of literals are folded into a single literal, and nested patterns are
+//!   flattened into the pattern containing them.
+//! - Bags nested directly in another bag are merged into it, with their weights scaled so that
+//!   every entry keeps its probability. Entries which can never be chosen are dropped.
+//! - Bags with a single entry are replaced with the entry, when the entry is a literal or when the
+//!   bag is turned into text right away. A bag bound to a variable is sampled again every time the
+//!   variable is used, so replacing it with a pattern, which is evaluated once, would change the
+//!   outputs.
+//! - Variables defined as just another variable are replaced with the variable they stand for.
+//!
+//! Optimised scripts make fewer random choices, so a seeded run of an optimised script produces
+//! different output than the same seed with the original script, although every output stays
+//! exactly as likely. Seeded runs of an optimised script are just as reproducible, so a seed is
+//! stable as long as the script is either always or never optimised.
+
+use std::collections::HashMap;
+
+use rand_distr::WeightedIndex;
+
+use crate::eval::{
+  Bag, BuiltInFunction, CompiledScript, Expression, ExpressionKind, FunctionLike, Pattern,
+};
+
+impl CompiledScript {
+  /// Simplifies the script in place. See the [module documentation](crate::optimise).
+  ///
+  /// Traces of an optimised script describe the simplified script, such as merged bags with
+  /// their combined weights, instead of the script as it was written.
+  pub fn optimise(&mut self) {
+    self.program.take();
+
+    let mut optimiser = Optimiser {
+      aliases: self.aliases(),
+      locals: Vec::new(),
+    };
+
+    for expression in self.variables.values_mut() {
+      optimiser.expression(expression, Position::Value);
+    }
+
+    for function in self.functions.values_mut() {
+      optimiser.locals.clone_from(&function.parameters);
+      optimiser.expression(&mut function.body, Position::Value);
+    }
+  }
+
+  /// Finds global variables which are defined as another global variable, and the variable each
+  /// of them ultimately stands for. Sticky aliases are kept, because they fix the value of a
+  /// variable which otherwise changes every time it is used.
+  fn aliases(&self) -> HashMap<String, String> {
+    let alias_of = |name: &str| match &self.variables.get(name)?.kind {
+      ExpressionKind::VariableE(target)
+        if !self.sticky_variables.contains(name) && self.variables.contains_key(target) =>
+      {
+        Some(target.as_str())
+      }
+      _ => None,
+    };
+
+    let mut aliases = HashMap::new();
+
+    for name in self.variables.keys() {
+      let mut target = match alias_of(name) {
+        Some(target) => target,
+        None => continue,
+      };
+
+      let mut steps = 1;
+
+      while let Some(next) = alias_of(target) {
+        // Aliases which form a cycle can't be resolved
+        if steps > self.variables.len() {
+          break;
+        }
+
+        target = next;
+        steps += 1;
+      }
+
+      if steps <= self.variables.len() {
+        aliases.insert(name.clone(), target.to_string());
+      }
+    }
+
+    aliases
+  }
+}
+
+/// How the value of an expression is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Position {
+  /// The value is turned into text as soon as it has been evaluated
+  Text,
+  /// The value may be bound to a variable or returned, and used several times
+  Value,
+}
+
+struct Optimiser {
+  aliases: HashMap<String, String>,
+  /// Local variables and parameters in scope, which shadow global aliases
+  locals: Vec<String>,
+}
+
+impl Optimiser {
+  fn expression(&mut self, expression: &mut Expression, position: Position) {
+    match &mut expression.kind {
+      ExpressionKind::LiteralE(_) => {}
+      ExpressionKind::VariableE(name) => {
+        // Neither the alias nor the variable it stands for may be shadowed by a local variable
+        if let Some(target) = self.aliases.get(name) {
+          if !self.locals.contains(name) && !self.locals.contains(target) {
+            name.clone_from(target);
+          }
+        }
+      }
+      ExpressionKind::PatternE(pattern) => {
+        for part in &mut pattern.parts {
+          self.expression(part, Position::Text);
+        }
+
+        if let Some(folded) = fold_pattern(pattern) {
+          expression.kind = folded;
+        }
+      }
+      ExpressionKind::BagE(bag) => {
+        for item in &mut bag.items {
+          self.expression(item, position);
+        }
+
+        merge_nested_bags(bag);
+
+        if let [item] = bag.items.as_slice() {
+          let collapsible = match position {
+            Position::Text => produces_string(item),
+            Position::Value => matches!(item.kind, ExpressionKind::LiteralE(_)),
+          };
+
+          if collapsible {
+            *expression = item.clone();
+          }
+        }
+      }
+      ExpressionKind::TableE(table) => {
+        // Tables keep their shape, because columns and rows are sampled separately
+        for item in table.bags.values_mut().flat_map(|bag| bag.items.iter_mut()) {
+          self.expression(item, Position::Value);
+        }
+
+        for base in table.bases.iter_mut().flatten() {
+          self.expression(base, Position::Text);
+        }
+
+        for cell in table.rows.iter_mut().flatten().flatten() {
+          self.expression(cell, Position::Value);
+        }
+      }
+      ExpressionKind::PropertyAccessE(target, _) => self.expression(target, Position::Value),
+      ExpressionKind::CallE(function, arguments) => {
+        let position = match function {
+          FunctionLike::BuiltIn(BuiltInFunction::Row) | FunctionLike::UserDefined(_) => {
+            Position::Value
+          }
+          FunctionLike::BuiltIn(_) => Position::Text,
+        };
+
+        for argument in arguments {
+          self.expression(argument, position);
+        }
+      }
+      ExpressionKind::WithE(bindings, body) => {
+        let depth = self.locals.len();
+
+        for binding in bindings.iter_mut() {
+          self.expression(&mut binding.expression, Position::Value);
+          self.locals.push(binding.name.clone());
+        }
+
+        self.expression(body, position);
+        self.locals.truncate(depth);
+      }
+    }
+  }
+}
+
+/// Flattens nested patterns and joins adjacent literals. Returns a literal if nothing else is left.
+fn fold_pattern(pattern: &mut Pattern) -> Option<ExpressionKind> {
+  let mut parts: Vec<Expression> = Vec::with_capacity(pattern.parts.len());
+
+  for part in pattern.parts.drain(..) {
+    let nested = match part.kind {
+      ExpressionKind::PatternE(nested) => nested.parts,
+      _ => vec![part],
+    };
+
+    for part in nested {
+      match (&mut parts.last_mut().map(|last| &mut last.kind), &part.kind) {
+        (_, ExpressionKind::LiteralE(literal)) if literal.is_empty() => {}
+        (Some(ExpressionKind::LiteralE(previous)), ExpressionKind::LiteralE(literal)) => {
+          previous.push_str(literal);
+        }
+        _ => parts.push(part),
+      }
+    }
+  }
+
+  let all_literals = parts
+    .iter()
+    .all(|part| matches!(part.kind, ExpressionKind::LiteralE(_)));
+
+  if all_literals {
+    let literal = match parts.pop() {
+      Some(Expression {
+        kind: ExpressionKind::LiteralE(literal),
+        ..
+      }) => literal,
+      _ => String::new(),
+    };
+
+    return Some(ExpressionKind::LiteralE(literal));
+  }
+
+  pattern.parts = parts;
+  None
+}
+
+/// Replaces entries which are bags themselves with their entries, and drops entries which can
+/// never be chosen. The bag is left as it is if the merged weights can't be represented.
+fn merge_nested_bags(bag: &mut Bag) {
+  let nested = bag
+    .items
+    .iter()
+    .any(|item| matches!(item.kind, ExpressionKind::BagE(_)));
+  let unreachable = bag.weights.contains(&0.0);
+
+  if !nested && !unreachable {
+    return;
+  }
+
+  let Some(weights) = merged_weights(bag) else {
+    return;
+  };
+
+  let Ok(distribution) = WeightedIndex::new(&weights) else {
+    return;
+  };
+
+  let mut items = Vec::with_capacity(weights.len());
+
+  for (item, weight) in bag.items.drain(..).zip(bag.weights.drain(..)) {
+    if weight == 0.0 {
+      continue;
+    }
+
+    match item.kind {
+      ExpressionKind::BagE(inner) => {
+        for (item, inner_weight) in inner.items.into_iter().zip(inner.weights) {
+          if inner_weight > 0.0 {
+            items.push(item);
+          }
+        }
+      }
+      _ => items.push(item),
+    }
+  }
+
+  bag.distribution = distribution;
+  bag.items = items;
+  bag.weights = weights;
+}
+
+/// The probability of every entry of a bag once nested bags are merged into it, in the order
+/// [`merge_nested_bags`] adds the entries. The products are computed in `f64`, since weights which
+/// are valid on their own can overflow an `f32` when multiplied, and `None` is returned if a
+/// probability is too small for an `f32`.
+fn merged_weights(bag: &Bag) -> Option<Vec<f32>> {
+  let total = |weights: &[f32]| weights.iter().map(|&weight| f64::from(weight)).sum::<f64>();
+  let outer_total = total(&bag.weights);
+  let mut weights = Vec::with_capacity(bag.weights.len());
+
+  for (item, &weight) in bag.items.iter().zip(&bag.weights) {
+    if weight == 0.0 {
+      continue;
+    }
+
+    let probability = f64::from(weight) / outer_total;
+
+    match &item.kind {
+      ExpressionKind::BagE(inner) => {
+        let inner_total = total(&inner.weights);
+
+        for &inner_weight in &inner.weights {
+          if inner_weight > 0.0 {
+            weights.push(probability * f64::from(inner_weight) / inner_total);
+          }
+        }
+      }
+      _ => weights.push(probability),
+    }
+  }
+
+  let weights: Vec<f32> = weights.into_iter().map(|weight| weight as f32).collect();
+
+  weights
+    .iter()
+    .all(|weight| weight.is_finite() && *weight > 0.0)
+    .then_some(weights)
+}
+
+/// Whether an expression always evaluates to a string, or a bag of strings. Such expressions produce
+/// the same text whether or not they are wrapped in a bag.
+fn produces_string(expression: &Expression) -> bool {
+  match &expression.kind {
+    ExpressionKind::LiteralE(_) | ExpressionKind::PatternE(_) => true,
+    ExpressionKind::BagE(bag) => bag.items.iter().all(produces_string),
+    ExpressionKind::CallE(FunctionLike::BuiltIn(function), _) => {
+      !matches!(function, BuiltInFunction::Row)
+    }
+    _ => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{compile_script, enumerate::Outcome};
+
+  use super::*;
+
+  fn optimised(source: &str) -> CompiledScript {
+    let mut script = compile_script(source).unwrap();
+    script.optimise();
+    script
+  }
+
+  fn result_kind(script: &CompiledScript) -> &ExpressionKind {
+    &script.variables["result"].kind
+  }
+
+  /// Checks that optimising the script doesn't change the probability of any output.
+  fn assert_same_distribution(source: &str) {
+    let original = compile_script(source)
+      .unwrap()
+      .distribution("result")
+      .unwrap();
+    let optimised = optimised(source).distribution("result").unwrap();
+
+    assert_eq!(original.len(), optimised.len());
+
+    for Outcome { text, probability } in original {
+      let optimised = optimised
+        .iter()
+        .find(|outcome| outcome.text == text)
+        .unwrap_or_else(|| panic!("{text:?} is missing from the optimised script"));
+
+      assert!(
+        (optimised.probability - probability).abs() < 1e-6,
+        "{text:?} had probability {probability}, now {}",
+        optimised.probability
+      );
+    }
+  }
+
+  #[test]
+  fn folds_literal_patterns() {
+    let source = r#"result = { "a" { "b" "" } "c" };"#;
+    assert!(
+      matches!(result_kind(&optimised(source)), ExpressionKind::LiteralE(literal) if literal == "abc")
+    );
+    assert_same_distribution(source);
+  }
+
+  #[test]
+  fn flattens_patterns_around_choices() {
+    let source = r#"result = { "a" { "b" bag ["c", "d"] "e" } "f" };"#;
+    let script = optimised(source);
+
+    match result_kind(&script) {
+      ExpressionKind::PatternE(pattern) => assert_eq!(pattern.parts.len(), 3),
+      kind => panic!("expected a pattern, got {kind:?}"),
+    }
+
+    assert_same_distribution(source);
+  }
+
+  #[test]
+  fn collapses_single_item_bags() {
+    let source = r#"result = { bag ["a"] bag [2 "b", 0 "never"] };"#;
+    assert!(
+      matches!(result_kind(&optimised(source)), ExpressionKind::LiteralE(literal) if literal == "ab")
+    );
+    assert_same_distribution(source);
+  }
+
+  #[test]
+  fn keeps_bound_bags() {
+    // `x` is sampled again every time it is used, but a pattern would only be evaluated once
+    let source = r#"result = with x = bag [{ bag ["a", "b"] }] in { x x };"#;
+    let script = optimised(source);
+
+    match result_kind(&script) {
+      ExpressionKind::WithE(bindings, _) => {
+        assert!(matches!(
+          bindings[0].expression.kind,
+          ExpressionKind::BagE(_)
+        ))
+      }
+      kind => panic!("expected a with expression, got {kind:?}"),
+    }
+
+    assert_same_distribution(source);
+  }
+
+  #[test]
+  fn keeps_bags_of_variables() {
+    // The variable could be a table, which behaves differently when wrapped in a bag
+    let script = optimised(r#"t = table [ [.a], ["x"] ]; result = { bag [t] };"#);
+    match result_kind(&script) {
+      ExpressionKind::PatternE(pattern) => {
+        assert!(matches!(pattern.parts[0].kind, ExpressionKind::BagE(_)))
+      }
+      kind => panic!("expected a pattern, got {kind:?}"),
+    }
+  }
+
+  #[test]
+  fn merges_nested_bags() {
+    let source = r#"result = bag [ 2 bag ["a", 3 "b"], "c", bag [bag ["d", "e"], "f"] ];"#;
+    let script = optimised(source);
+
+    match result_kind(&script) {
+      ExpressionKind::BagE(bag) => {
+        assert_eq!(bag.weights, vec![0.125, 0.375, 0.25, 0.0625, 0.0625, 0.125]);
+      }
+      kind => panic!("expected a bag, got {kind:?}"),
+    }
+
+    assert_same_distribution(source);
+  }
+
+  #[test]
+  fn merges_weights_which_overflow_when_multiplied() {
+    let huge = format!("{:.0}", 3e38_f64);
+    let source = format!(r#"result = bag [ {huge} bag [ 2 "a", 2 "b" ], 1 "c" ];"#);
+    let script = optimised(&source);
+
+    match result_kind(&script) {
+      ExpressionKind::BagE(bag) => {
+        assert_eq!(bag.items.len(), 3);
+        assert!(bag.weights.iter().all(|weight| weight.is_finite()));
+      }
+      kind => panic!("expected a bag, got {kind:?}"),
+    }
+
+    script.run().unwrap();
+    assert_same_distribution(&source);
+  }
+
+  #[test]
+  fn keeps_nested_bags_whose_merged_weights_underflow() {
+    let huge = format!("{:.0}", 3e38_f64);
+    let source = format!(r#"result = bag [ 1 bag [ {huge} "a", 1 "b" ], {huge} "c" ];"#);
+    let script = optimised(&source);
+
+    match result_kind(&script) {
+      ExpressionKind::BagE(bag) => {
+        assert!(matches!(bag.items[0].kind, ExpressionKind::BagE(_)));
+      }
+      kind => panic!("expected a bag, got {kind:?}"),
+    }
+
+    for seed in 0..100 {
+      script.run_with_seed(seed).unwrap();
+    }
+  }
+
+  #[test]
+  fn inlines_aliases() {
+    let source = r#"
+      name = bag ["Ada", "Alan"];
+      person = name;
+      someone = person;
+      result = { someone " and " someone };
+    "#;
+    let script = optimised(source);
+
+    match result_kind(&script) {
+      ExpressionKind::PatternE(pattern) => {
+        assert!(matches!(&pattern.parts[0].kind, ExpressionKind::VariableE(name) if name == "name"))
+      }
+      kind => panic!("expected a pattern, got {kind:?}"),
+    }
+
+    assert_same_distribution(source);
+  }
+
+  #[test]
+  fn keeps_sticky_and_shadowed_aliases() {
+    let source = r#"
+      name = bag ["Ada", "Alan"];
+      sticky chosen = name;
+      person = name;
+      greet(person) = { "hi " person };
+      shadow(name) = { name person };
+      result = { chosen chosen with person = "Bob" in person greet("Eve") shadow("!") };
+    "#;
+    let script = optimised(source);
+
+    assert!(
+      matches!(&script.variables["chosen"].kind, ExpressionKind::VariableE(name) if name == "name")
+    );
+    assert_same_distribution(source);
+  }
+}
@@ -1,59 +1,107 @@
 use nom::branch::alt;
-use nom::bytes::complete::{escaped_transform, tag, take_while1};
+use nom::bytes::complete::{escaped_transform, is_not, tag, take_until, take_while1};
 use nom::character::is_alphabetic;
-use nom::combinator::{all_consuming, map, opt, recognize};
-use nom::multi::{many0, many1, separated_list0};
+use nom::combinator::{cut, eof, map, not, opt, peek, recognize};
+use nom::multi::{many0, many1, many_till, separated_list0, separated_list1};
 use nom::number::complete::float;
-use nom::sequence::{delimited, preceded, terminated, tuple};
+use nom::sequence::{delimited, pair, preceded, terminated, tuple};
 use nom::{
-  character::complete::{char, multispace0},
+  character::complete::{char, multispace1, satisfy},
   combinator::value,
 };
 use nom::{
-  error::{context, VerboseError},
+  error::{context, ErrorKind, VerboseError, VerboseErrorKind},
   IResult,
 };
+use std::fmt::Display;
 
 type ParseResult<'a, T> = IResult<&'a str, T, VerboseError<&'a str>>;
 
+use crate::source::{render_snippet, Position, Span};
+
 use crate::ast::{
-  Assignment, Bag, BagEntry, Expression, Pattern, Statement, Table, TableEntry, TableRow,
+  Assignment, Bag, BagEntry, Expression, ExpressionKind, FunctionDefinition, Import, Pattern,
+  Statement, Table, TableEntry, TableRow, VisitSpans,
 };
 
 fn parse_string_literal(input: &str) -> ParseResult<String> {
   context(
     "string literal",
-    delimited(
+    preceded(
       char('"'),
-      map(
-        opt(escaped_transform(
-          nom::bytes::complete::is_not("\\\""),
-          '\\',
-          alt((
-            value("\\", tag("\\")),
-            value("\"", tag("\"")),
-            value("\n", tag("\n")),
+      cut(terminated(
+        map(
+          opt(escaped_transform(
+            is_not("\\\""),
+            '\\',
+            alt((
+              value("\\", tag("\\")),
+              value("\"", tag("\"")),
+              value("\n", tag("\n")),
+            )),
           )),
-        )),
-        |literal: Option<String>| literal.unwrap_or_else(|| String::from("")),
-      ),
-      char('"'),
+          |literal: Option<String>| literal.unwrap_or_else(|| String::from("")),
+        ),
+        char('"'),
+      )),
     ),
   )(input)
 }
 
+fn line_comment(input: &str) -> ParseResult<&str> {
+  recognize(preceded(tag("//"), opt(is_not("\r\n"))))(input)
+}
+
+fn block_comment(input: &str) -> ParseResult<&str> {
+  recognize(preceded(
+    tag("/*"),
+    cut(context(
+      "end of block comment",
+      terminated(take_until("*/"), tag("*/")),
+    )),
+  ))(input)
+}
+
+/// Runs `parser` and also returns the span of the input it consumed.
+///
+/// As the parsers only see the remaining input, the span is counted from the end of the input.
+/// [`parse_source`] converts spans into regular byte offsets once parsing is done.
+fn spanned<'a, O>(
+  mut parser: impl FnMut(&'a str) -> ParseResult<'a, O>,
+) -> impl FnMut(&'a str) -> ParseResult<'a, (O, Span)> {
+  move |input: &'a str| {
+    let (rest, output) = parser(input)?;
+    Ok((rest, (output, Span::new(input.len(), rest.len()))))
+  }
+}
+
+/// Skips any amount of whitespace and comments.
 fn ws(input: &str) -> ParseResult<()> {
-  let (input, _) = multispace0(input)?;
+  let (input, _) = many0(alt((multispace1, line_comment, block_comment)))(input)?;
   Ok((input, ()))
 }
 
+/// Matches a keyword, but not the start of a longer identifier such as `input` for `in`.
+fn keyword<'a>(word: &'static str) -> impl FnMut(&'a str) -> ParseResult<'a, &'a str> {
+  terminated(tag(word), not(satisfy(char::is_alphanumeric)))
+}
+
 pub fn parse_bag_entry(input: &str) -> ParseResult<BagEntry> {
-  let (input, (_, weight, _, value)) =
-    context("bag entry", tuple((ws, opt(float), ws, parse_expression)))(input)?;
+  let (input, (_, ((weight, _, value), span))) = context(
+    "bag entry",
+    tuple((ws, spanned(tuple((opt(float), ws, parse_expression))))),
+  )(input)?;
 
   let value = Box::new(value);
 
-  Ok((input, BagEntry { weight, value }))
+  Ok((
+    input,
+    BagEntry {
+      weight,
+      value,
+      span,
+    },
+  ))
 }
 
 pub fn parse_bag(input: &str) -> ParseResult<Bag> {
@@ -62,13 +110,15 @@ pub fn parse_bag(input: &str) -> ParseResult<Bag> {
     tuple((
       tag("bag"),
       ws,
-      delimited(
+      preceded(
         tag("["),
-        terminated(
-          separated_list0(tag(","), terminated(parse_bag_entry, ws)),
-          opt(terminated(tag(","), ws)),
-        ),
-        tag("]"),
+        cut(terminated(
+          terminated(
+            separated_list0(tag(","), terminated(parse_bag_entry, ws)),
+            opt(terminated(tag(","), ws)),
+          ),
+          char(']'),
+        )),
       ),
     )),
   )(input)?;
@@ -83,10 +133,15 @@ pub fn parse_identifier(input: &str) -> ParseResult<&str> {
 }
 
 pub fn parse_pattern(input: &str) -> ParseResult<Pattern> {
-  let (input, expressions) = delimited(
-    char('{'),
-    many0(delimited(multispace0, parse_expression, multispace0)),
-    char('}'),
+  let (input, expressions) = context(
+    "pattern",
+    preceded(
+      char('{'),
+      cut(terminated(
+        many0(delimited(ws, parse_expression, ws)),
+        char('}'),
+      )),
+    ),
   )(input)?;
 
   Ok((input, Pattern { parts: expressions }))
@@ -95,17 +150,19 @@ pub fn parse_pattern(input: &str) -> ParseResult<Pattern> {
 pub fn parse_table_header(input: &str) -> ParseResult<Vec<String>> {
   let (input, columns) = context(
     "table header",
-    delimited(
+    preceded(
       char('['),
-      separated_list0(
-        tag(","),
-        delimited(
-          multispace0,
-          preceded(char('.'), take_while1(|c| is_alphabetic(c as u8))),
-          multispace0,
+      cut(terminated(
+        separated_list0(
+          tag(","),
+          delimited(
+            ws,
+            preceded(char('.'), take_while1(|c| is_alphabetic(c as u8))),
+            ws,
+          ),
         ),
-      ),
-      char(']'),
+        char(']'),
+      )),
     ),
   )(input)?;
 
@@ -136,75 +193,136 @@ pub fn parse_table_entry(input: &str) -> ParseResult<TableEntry> {
 
 pub fn parse_table_row(input: &str) -> ParseResult<TableRow> {
   let (input, _) = ws(input)?;
-  let (input, weight) = opt(float)(input)?;
-  let (input, _) = ws(input)?;
 
-  let (input, items) = delimited(
-    char('['),
-    separated_list0(
-      tag(","),
-      delimited(
-        multispace0,
-        context("table entry", parse_table_entry),
-        multispace0,
+  let (input, ((weight, _, items), span)) = spanned(tuple((
+    opt(float),
+    ws,
+    context(
+      "table row",
+      preceded(
+        char('['),
+        cut(terminated(
+          separated_list0(
+            tag(","),
+            delimited(ws, context("table entry", parse_table_entry), ws),
+          ),
+          char(']'),
+        )),
       ),
     ),
-    char(']'),
-  )(input)?;
+  )))(input)?;
 
-  Ok((input, TableRow { items, weight }))
+  Ok((
+    input,
+    TableRow {
+      items,
+      weight,
+      span,
+    },
+  ))
 }
 
 pub fn parse_table(input: &str) -> ParseResult<Table> {
-  let (input, (columns, rows)) = context(
+  let (input, ((columns, header_span), rows)) = context(
     "table",
     preceded(
       tag("table"),
       preceded(
         ws,
-        delimited(
+        preceded(
           char('['),
-          delimited(
-            ws,
-            tuple((
-              terminated(parse_table_header, tuple((ws, char(','), ws))),
-              separated_list0(char(','), parse_table_row),
-            )),
-            delimited(ws, opt(char(',')), ws),
-          ),
-          char(']'),
+          cut(terminated(
+            delimited(
+              ws,
+              tuple((
+                terminated(spanned(parse_table_header), tuple((ws, char(','), ws))),
+                separated_list0(char(','), parse_table_row),
+              )),
+              delimited(ws, opt(char(',')), ws),
+            ),
+            char(']'),
+          )),
         ),
       ),
     ),
   )(input)?;
 
-  Ok((input, Table { columns, rows }))
+  Ok((
+    input,
+    Table {
+      columns,
+      rows,
+      header_span,
+    },
+  ))
 }
 
-pub fn parse_property_access(input: &str) -> ParseResult<Expression> {
+pub fn parse_property_access(input: &str) -> ParseResult<ExpressionKind> {
   // TODO: Support other expressions.
-  let (input, identifier) = parse_identifier(input)?;
-  let expression = Expression::VariableE(String::from(identifier));
+  let (input, (identifier, span)) = spanned(parse_identifier)(input)?;
+  let mut expression = Expression::new(ExpressionKind::VariableE(String::from(identifier)), span);
 
-  let (input, property) = preceded(char('.'), parse_identifier)(input)?;
+  // Chains like `names.firstName.base` access namespaces and then columns
+  let (input, properties) = many1(spanned(preceded(char('.'), parse_identifier)))(input)?;
 
-  Ok((
-    input,
-    Expression::PropertyAccessE(Box::new(expression), String::from(property)),
-  ))
+  for (property, property_span) in properties {
+    let span = Span::new(span.start, property_span.end);
+    let kind = ExpressionKind::PropertyAccessE(Box::new(expression), String::from(property));
+    expression = Expression::new(kind, span);
+  }
+
+  Ok((input, expression.kind))
+}
+
+/// Parses a function name, which may be qualified with a namespace like `names.fullName`.
+fn parse_function_name(input: &str) -> ParseResult<&str> {
+  recognize(separated_list1(char('.'), parse_identifier))(input)
 }
 
-pub fn parse_call(input: &str) -> ParseResult<Expression> {
+pub fn parse_call(input: &str) -> ParseResult<ExpressionKind> {
   let parse_argument_list = separated_list0(delimited(ws, tag(","), ws), parse_expression);
 
   context(
     "function call",
     map(
       tuple((
-        parse_identifier,
-        delimited(tag("("), delimited(ws, parse_argument_list, ws), tag(")")),
+        parse_function_name,
+        preceded(
+          tag("("),
+          cut(terminated(
+            delimited(ws, parse_argument_list, ws),
+            char(')'),
+          )),
+        ),
       )),
-      |(function, args)| Expression::CallE(function.to_string(), args),
+      |(function, args)| ExpressionKind::CallE(function.to_string(), args),
+    ),
+  )(input)
+}
+
+pub fn parse_with(input: &str) -> ParseResult<ExpressionKind> {
+  context(
+    "with",
+    map(
+      preceded(
+        // Only commit to parsing a with expression once the first binding is recognised
+        tuple((
+          keyword("with"),
+          ws,
+          peek(tuple((
+            parse_sticky_modifier,
+            parse_identifier,
+            ws,
+            char('='),
+          ))),
+        )),
+        cut(tuple((
+          separated_list1(delimited(ws, tag(","), ws), parse_assignment),
+          delimited(ws, context("keyword \"in\"", keyword("in")), ws),
+          parse_expression,
+        ))),
+      ),
+      |(bindings, _, body)| ExpressionKind::WithE(bindings, Box::new(body)),
     ),
   )(input)
 }
@@ -212,29 +330,51 @@ pub fn parse_call(input: &str) -> ParseResult<Expression> {
 pub fn parse_expression(input: &str) -> ParseResult<Expression> {
   context(
     "expression",
-    alt((
-      map(parse_pattern, Expression::PatternE),
-      map(parse_string_literal, Expression::LiteralE),
-      map(parse_table, Expression::TableE),
-      map(parse_bag, Expression::BagE),
-      parse_property_access,
-      parse_call,
-      map(parse_identifier, |s| Expression::VariableE(String::from(s))),
-    )),
+    map(
+      spanned(alt((
+        map(parse_pattern, ExpressionKind::PatternE),
+        map(parse_string_literal, ExpressionKind::LiteralE),
+        map(parse_table, ExpressionKind::TableE),
+        map(parse_bag, ExpressionKind::BagE),
+        parse_with,
+        parse_call,
+        parse_property_access,
+        map(parse_identifier, |s| {
+          ExpressionKind::VariableE(String::from(s))
+        }),
+      ))),
+      |(kind, span)| Expression::new(kind, span),
+    ),
   )(input)
 }
 
+fn parse_sticky_modifier(input: &str) -> ParseResult<bool> {
+  // The lookahead makes sure a variable called "sticky" can still be assigned to
+  let modifier = terminated(keyword("sticky"), tuple((ws, peek(parse_identifier))));
+  map(opt(modifier), |modifier| modifier.is_some())(input)
+}
+
 pub fn parse_assignment(input: &str) -> ParseResult<Assignment> {
-  let (input, (name, _, _, _, value)) = context(
+  let (input, (sticky, (name, span), _, _, _, value)) = context(
     "assignment",
-    tuple((parse_identifier, ws, tag("="), ws, parse_expression)),
+    tuple((
+      parse_sticky_modifier,
+      spanned(parse_identifier),
+      ws,
+      tag("="),
+      ws,
+      cut(parse_expression),
+    )),
   )(input)?;
 
   Ok((
     input,
     Assignment {
       name: name.to_string(),
+      sticky,
+      private: false,
       value: Box::new(value),
+      span,
     },
   ))
 }
@@ -243,24 +383,228 @@ pub fn parse_assignment_statement(input: &str) -> ParseResult<Statement> {
   map(parse_assignment, Statement::AssignmentS)(input)
 }
 
+pub fn parse_function_definition(input: &str) -> ParseResult<FunctionDefinition> {
+  let parse_parameter_list = separated_list0(delimited(ws, tag(","), ws), parse_identifier);
+
+  let (input, (((name, parameters), span), _, _, _, body)) = context(
+    "function definition",
+    tuple((
+      spanned(pair(
+        parse_identifier,
+        delimited(tag("("), delimited(ws, parse_parameter_list, ws), tag(")")),
+      )),
+      ws,
+      tag("="),
+      ws,
+      cut(parse_expression),
+    )),
+  )(input)?;
+
+  Ok((
+    input,
+    FunctionDefinition {
+      name: name.to_string(),
+      parameters: parameters.into_iter().map(String::from).collect(),
+      private: false,
+      body: Box::new(body),
+      span,
+    },
+  ))
+}
+
+pub fn parse_function_statement(input: &str) -> ParseResult<Statement> {
+  map(parse_function_definition, Statement::FunctionS)(input)
+}
+
+pub fn parse_import(input: &str) -> ParseResult<Import> {
+  let alias = preceded(
+    tuple((ws, keyword("as"), ws)),
+    cut(context("namespace", parse_identifier)),
+  );
+
+  let (input, ((path, alias), span)) = context(
+    "import",
+    spanned(preceded(
+      // A variable called "import" can still be assigned to
+      tuple((tag("import"), ws, peek(char('"')))),
+      cut(pair(parse_string_literal, opt(alias))),
+    )),
+  )(input)?;
+
+  Ok((
+    input,
+    Import {
+      path,
+      alias: alias.map(String::from),
+      span,
+    },
+  ))
+}
+
+/// Parses a definition marked `private`, which files importing this one can't see.
+fn parse_private_definition(input: &str) -> ParseResult<Statement> {
+  let (input, mut statement) = preceded(
+    pair(keyword("private"), ws),
+    alt((parse_function_statement, parse_assignment_statement)),
+  )(input)?;
+
+  match &mut statement {
+    Statement::AssignmentS(assignment) => assignment.private = true,
+    Statement::FunctionS(definition) => definition.private = true,
+    Statement::ImportS(_) => {}
+  }
+
+  Ok((input, statement))
+}
+
 pub fn parse_statement(input: &str) -> ParseResult<Statement> {
   let (input, (_, statement, _, _)) = context(
     "statement",
-    tuple((ws, parse_assignment_statement, ws, char(';'))),
+    tuple((
+      ws,
+      alt((
+        map(parse_import, Statement::ImportS),
+        parse_private_definition,
+        parse_function_statement,
+        parse_assignment_statement,
+      )),
+      ws,
+      cut(char(';')),
+    )),
   )(input)?;
   Ok((input, statement))
 }
 
 pub fn parse_program(input: &str) -> ParseResult<Vec<Statement>> {
-  let (input, statements) = context(
-    "program",
-    all_consuming(terminated(many1(parse_statement), ws)),
-  )(input)?;
+  // many_till reports the error of the statement that failed to parse, which is a lot more
+  // helpful than "expected end of input".
+  let (input, (statements, _)) =
+    context("program", many_till(parse_statement, tuple((ws, eof))))(input)?;
   Ok((input, statements))
 }
 
+/// A syntax error, located in the source code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+  /// Byte offset of the error
+  pub offset: usize,
+  pub position: Position,
+  /// Description of what the parser was expecting to find
+  pub expected: String,
+  /// The constructs being parsed when the error occurred, innermost first
+  pub context: Vec<&'static str>,
+  /// The offending line of source code, with the error position marked
+  pub snippet: String,
+}
+
+impl ParseError {
+  pub fn new(source: &str, error: nom::Err<VerboseError<&str>>) -> ParseError {
+    let error = match error {
+      nom::Err::Error(error) | nom::Err::Failure(error) => error,
+      nom::Err::Incomplete(_) => VerboseError {
+        errors: vec![(
+          &source[source.len()..],
+          VerboseErrorKind::Nom(ErrorKind::Eof),
+        )],
+      },
+    };
+
+    // The first entry is where parsing actually failed, the rest are the parsers it was nested in
+    let (remaining, _) = error.errors[0];
+    let offset = source.len() - remaining.len();
+
+    let context: Vec<_> = error
+      .errors
+      .iter()
+      .filter_map(|(_, kind)| match kind {
+        VerboseErrorKind::Context(context) => Some(*context),
+        _ => None,
+      })
+      .collect();
+
+    let expected = match &error.errors[0].1 {
+      VerboseErrorKind::Char(ch) => format!("'{}'", ch),
+      VerboseErrorKind::Context(context) => context.to_string(),
+      VerboseErrorKind::Nom(ErrorKind::Eof) if remaining.is_empty() => String::from("more input"),
+      VerboseErrorKind::Nom(_) => context.first().copied().unwrap_or("program").to_string(),
+    };
+
+    ParseError {
+      offset,
+      position: Position::from_offset(source, offset),
+      expected,
+      context,
+      snippet: render_snippet(source, offset),
+    }
+  }
+}
+
+impl ParseError {
+  /// Describes what went wrong, without the position or the snippet.
+  pub fn message(&self) -> String {
+    let mut message = format!("expected {}", self.expected);
+
+    // The innermost context is often what was expected, so it's not worth repeating
+    if let Some(context) = self
+      .context
+      .iter()
+      .find(|context| **context != self.expected)
+    {
+      message.push_str(&format!(" in {}", context));
+    }
+
+    message
+  }
+}
+
+impl Display for ParseError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}: {}\n{}", self.position, self.message(), self.snippet)
+  }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Runs one of the parsers in this module on a piece of source code. Spans in the result are
+/// converted into byte offsets, and errors into a [`ParseError`]. Returns the unparsed remainder of
+/// the source along with the result.
+pub fn parse_source<'a, T: VisitSpans>(
+  source: &'a str,
+  mut parser: impl FnMut(&'a str) -> ParseResult<'a, T>,
+) -> Result<(&'a str, T), ParseError> {
+  match parser(source) {
+    Ok((rest, mut parsed)) => {
+      parsed.visit_spans(&mut |span| {
+        *span = Span::new(source.len() - span.start, source.len() - span.end)
+      });
+      Ok((rest, parsed))
+    }
+    Err(err) => Err(ParseError::new(source, err)),
+  }
+}
+
+/// Parses a whole script.
+pub fn parse_script(source: &str) -> Result<Vec<Statement>, ParseError> {
+  let (_, statements) = parse_source(source, parse_program)?;
+  Ok(statements)
+}
+
+/// Resets every span in a syntax tree, so that trees can be compared by structure alone, such as a
+/// script before and after it was formatted.
+pub fn without_spans<T: VisitSpans>(mut tree: T) -> T {
+  tree.visit_spans(&mut |span| *span = Span::default());
+  tree
+}
+
 #[cfg(test)]
 mod tests {
+  use super::{ParseResult, VisitSpans};
+
+  /// Resets the spans of a parse result, so that it can be compared with a tree built by hand.
+  fn strip<T: VisitSpans>(result: ParseResult<T>) -> ParseResult<T> {
+    result.map(|(rest, tree)| (rest, super::without_spans(tree)))
+  }
+
   #[test]
   fn test_parse_string_literal() {
     use super::parse_string_literal;
@@ -296,7 +640,7 @@ mod tests {
     ];
 
     assert_eq!(
-      parse_bag(r#"bag["epic", "awesome", "cool"]"#),
+      strip(parse_bag(r#"bag["epic", "awesome", "cool"]"#)),
       Ok((
         "",
         Bag {
@@ -306,7 +650,7 @@ mod tests {
     );
 
     assert_eq!(
-      parse_bag(r#"bag["epic", "awesome", "cool",]"#),
+      strip(parse_bag(r#"bag["epic", "awesome", "cool",]"#)),
       Ok((
         "",
         Bag {
@@ -316,7 +660,7 @@ mod tests {
     );
 
     assert_eq!(
-      parse_bag(r#"bag [ "epic",   "awesome",  "cool" ,  ]"#),
+      strip(parse_bag(r#"bag [ "epic",   "awesome",  "cool" ,  ]"#)),
       Ok((
         "",
         Bag {
@@ -331,31 +675,36 @@ mod tests {
     use super::{parse_bag_entry, BagEntry};
 
     assert_eq!(
-      parse_bag_entry(r#""no weight""#),
+      strip(parse_bag_entry(r#""no weight""#)),
       Ok(("", BagEntry::from_string("no weight")))
     );
     assert_eq!(
-      parse_bag_entry(r#"3.0 "float weighted""#),
+      strip(parse_bag_entry(r#"3.0 "float weighted""#)),
       Ok(("", BagEntry::from_string("float weighted").with_weight(3.0)))
     );
   }
 
   #[test]
   fn test_parse_assignment() {
-    use super::{parse_assignment, Assignment, Bag, BagEntry, Expression};
+    use super::{parse_assignment, Assignment, Bag, BagEntry, Expression, ExpressionKind, Span};
 
     assert_eq!(
-      parse_assignment(r#"adjective = bag["Friendly", "Unfriendly"]"#),
+      strip(parse_assignment(
+        r#"adjective = bag["Friendly", "Unfriendly"]"#
+      )),
       Ok((
         "",
         Assignment {
           name: String::from("adjective"),
-          value: Box::new(Expression::BagE(Bag {
+          sticky: false,
+          private: false,
+          value: Box::new(Expression::from(ExpressionKind::BagE(Bag {
             items: vec![
               BagEntry::from_string("Friendly"),
               BagEntry::from_string("Unfriendly")
             ],
-          })),
+          }))),
+          span: Span::default(),
         },
       ))
     );
@@ -363,15 +712,51 @@ mod tests {
 
   #[test]
   fn test_parse_assignment_literal() {
-    use super::{parse_assignment, Assignment, Expression};
+    use super::{parse_assignment, Assignment, Expression, Span};
 
     assert_eq!(
-      parse_assignment(r#"secretWord = "hunter2""#),
+      strip(parse_assignment(r#"secretWord = "hunter2""#)),
       Ok((
         "",
         Assignment {
           name: String::from("secretWord"),
-          value: Box::new(Expression::LiteralE(String::from("hunter2")))
+          sticky: false,
+          private: false,
+          value: Box::new(Expression::literal("hunter2")),
+          span: Span::default(),
+        }
+      ))
+    );
+  }
+
+  #[test]
+  fn test_parse_assignment_sticky() {
+    use super::{parse_assignment, Assignment, Expression, Span};
+
+    assert_eq!(
+      strip(parse_assignment(r#"sticky hero = heroes"#)),
+      Ok((
+        "",
+        Assignment {
+          name: String::from("hero"),
+          sticky: true,
+          private: false,
+          value: Box::new(Expression::variable("heroes")),
+          span: Span::default(),
+        }
+      ))
+    );
+
+    assert_eq!(
+      strip(parse_assignment(r#"sticky = "not a modifier""#)),
+      Ok((
+        "",
+        Assignment {
+          name: String::from("sticky"),
+          sticky: false,
+          private: false,
+          value: Box::new(Expression::literal("not a modifier")),
+          span: Span::default(),
         }
       ))
     );
@@ -379,7 +764,9 @@ mod tests {
 
   #[test]
   fn test_parse_program() {
-    use super::{parse_program, Assignment, Bag, BagEntry, Expression, Statement};
+    use super::{
+      parse_program, Assignment, Bag, BagEntry, Expression, ExpressionKind, Span, Statement,
+    };
 
     let program = r#"
             adjective = bag["Friendly", "Unfriendly"];
@@ -387,22 +774,28 @@ mod tests {
         "#;
 
     assert_eq!(
-      parse_program(program),
+      strip(parse_program(program)),
       Ok((
         "",
         vec![
           Statement::AssignmentS(Assignment {
             name: String::from("adjective"),
-            value: Box::new(Expression::BagE(Bag {
+            sticky: false,
+            private: false,
+            value: Box::new(Expression::from(ExpressionKind::BagE(Bag {
               items: vec![
                 BagEntry::from_string("Friendly"),
                 BagEntry::from_string("Unfriendly")
               ],
-            })),
+            }))),
+            span: Span::default(),
           }),
           Statement::AssignmentS(Assignment {
             name: String::from("result"),
-            value: Box::new(Expression::VariableE(String::from("adjective")))
+            sticky: false,
+            private: false,
+            value: Box::new(Expression::variable("adjective")),
+            span: Span::default(),
           })
         ]
       ))
@@ -410,148 +803,571 @@ mod tests {
   }
 
   #[test]
-  fn test_parse_pattern() {
-    use super::{parse_expression, Expression, Pattern};
+  fn test_parse_function_definition() {
+    use super::{
+      parse_statement, Expression, ExpressionKind, FunctionDefinition, Pattern, Span, Statement,
+    };
 
     assert_eq!(
-      parse_expression(r#"{ "Hello " world }"#),
+      strip(parse_statement(
+        r#"withSauce(dish, sauce) = { dish " ja " sauce };"#
+      )),
       Ok((
         "",
-        Expression::PatternE(Pattern {
-          parts: vec![
-            Expression::LiteralE(String::from("Hello ")),
-            Expression::VariableE(String::from("world"))
-          ]
+        Statement::FunctionS(FunctionDefinition {
+          name: String::from("withSauce"),
+          parameters: vec![String::from("dish"), String::from("sauce")],
+          private: false,
+          body: Box::new(Expression::from(ExpressionKind::PatternE(Pattern {
+            parts: vec![
+              Expression::variable("dish"),
+              Expression::literal(" ja "),
+              Expression::variable("sauce"),
+            ]
+          }))),
+          span: Span::default(),
         })
       ))
     );
   }
 
+  #[test]
+  fn test_parse_pattern() {
+    use super::{parse_expression, Expression, ExpressionKind, Pattern};
+
+    assert_eq!(
+      strip(parse_expression(r#"{ "Hello " world }"#)),
+      Ok((
+        "",
+        Expression::from(ExpressionKind::PatternE(Pattern {
+          parts: vec![Expression::literal("Hello "), Expression::variable("world")]
+        }))
+      ))
+    );
+  }
+
   #[test]
   fn test_parse_call_1() {
-    use super::{parse_expression, Expression};
+    use super::{parse_expression, Expression, ExpressionKind};
 
     assert_eq!(
-      parse_expression(r#"print("Hello, world!")"#),
+      strip(parse_expression(r#"print("Hello, world!")"#)),
       Ok((
         "",
-        Expression::CallE(
+        Expression::from(ExpressionKind::CallE(
           String::from("print"),
-          vec![Expression::LiteralE(String::from("Hello, world!"))]
-        )
+          vec![Expression::literal("Hello, world!")]
+        ))
       ))
     );
   }
 
   #[test]
   fn test_parse_call_0() {
-    use super::{parse_expression, Expression};
+    use super::{parse_expression, Expression, ExpressionKind};
 
     assert_eq!(
-      parse_expression(r#"printHello()"#),
-      Ok(("", Expression::CallE(String::from("printHello"), vec![])))
+      strip(parse_expression(r#"printHello()"#)),
+      Ok((
+        "",
+        Expression::from(ExpressionKind::CallE(String::from("printHello"), vec![]))
+      ))
     );
   }
 
   #[test]
   fn test_parse_call_2_pattern() {
-    use super::{parse_expression, Expression, Pattern};
+    use super::{parse_expression, Expression, ExpressionKind, Pattern};
 
     assert_eq!(
-      parse_expression(r#"concat( { "Hello" }, {" world!"} )"#),
+      strip(parse_expression(r#"concat( { "Hello" }, {" world!"} )"#)),
       Ok((
         "",
-        Expression::CallE(
+        Expression::from(ExpressionKind::CallE(
           String::from("concat"),
           vec![
-            Expression::PatternE(Pattern {
-              parts: vec![Expression::LiteralE(String::from("Hello")),]
-            }),
-            Expression::PatternE(Pattern {
-              parts: vec![Expression::LiteralE(String::from(" world!")),]
-            }),
+            Expression::from(ExpressionKind::PatternE(Pattern {
+              parts: vec![Expression::literal("Hello"),]
+            })),
+            Expression::from(ExpressionKind::PatternE(Pattern {
+              parts: vec![Expression::literal(" world!"),]
+            })),
           ]
-        )
+        ))
       ))
     );
   }
 
   #[test]
-  fn test_parse_table_entry() {
-    use super::{parse_table_entry, Expression, TableEntry};
+  fn test_parse_call_namespaced() {
+    use super::{parse_expression, Expression, ExpressionKind};
 
     assert_eq!(
-      parse_table_entry(r#""Harald""#),
+      strip(parse_expression(r#"names.greet("Ada")"#)),
       Ok((
         "",
-        TableEntry::Literal(Box::new(Expression::LiteralE(String::from("Harald"))))
+        Expression::from(ExpressionKind::CallE(
+          String::from("names.greet"),
+          vec![Expression::literal("Ada")]
+        ))
       ))
     );
+  }
+
+  #[test]
+  fn test_parse_property_chain() {
+    use super::{parse_expression, Expression, ExpressionKind};
 
     assert_eq!(
-      parse_table_entry(r#"+"in""#),
+      strip(parse_expression("names.firstName.base")),
       Ok((
         "",
-        TableEntry::Append(Box::new(Expression::LiteralE(String::from("in"))))
+        Expression::from(ExpressionKind::PropertyAccessE(
+          Box::new(Expression::from(ExpressionKind::PropertyAccessE(
+            Box::new(Expression::variable("names")),
+            String::from("firstName")
+          ))),
+          String::from("base")
+        ))
       ))
     );
   }
 
+  #[test]
+  fn test_parse_import() {
+    use super::{parse_statement, Import, Span, Statement};
+
+    assert_eq!(
+      strip(parse_statement(r#"import "common/names.hd" as names;"#)),
+      Ok((
+        "",
+        Statement::ImportS(Import {
+          path: String::from("common/names.hd"),
+          alias: Some(String::from("names")),
+          span: Span::default(),
+        })
+      ))
+    );
+    assert_eq!(
+      strip(parse_statement(r#"import "names.hd";"#)),
+      Ok((
+        "",
+        Statement::ImportS(Import {
+          path: String::from("names.hd"),
+          alias: None,
+          span: Span::default(),
+        })
+      ))
+    );
+    assert_eq!(
+      strip(parse_statement("import \"a.hd\" /* c */ as // c\n n;")),
+      Ok((
+        "",
+        Statement::ImportS(Import {
+          path: String::from("a.hd"),
+          alias: Some(String::from("n")),
+          span: Span::default(),
+        })
+      ))
+    );
+    assert!(matches!(
+      parse_statement(r#"import = "x";"#),
+      Ok(("", Statement::AssignmentS(_)))
+    ));
+  }
+
+  #[test]
+  fn test_parse_private_definitions() {
+    use super::{parse_statement, Statement};
+
+    let Ok(("", Statement::AssignmentS(assignment))) =
+      parse_statement(r#"private sticky helper = "a";"#)
+    else {
+      panic!("expected an assignment");
+    };
+    assert!(assignment.private && assignment.sticky);
+    assert_eq!(assignment.name, "helper");
+
+    let Ok(("", Statement::FunctionS(definition))) = parse_statement("private wrap(x) = x;") else {
+      panic!("expected a function definition");
+    };
+    assert!(definition.private);
+
+    let Ok(("", Statement::AssignmentS(assignment))) = parse_statement("private // c\nx = \"a\";")
+    else {
+      panic!("expected an assignment");
+    };
+    assert!(assignment.private);
+
+    // "private" is still a valid name
+    let Ok(("", Statement::AssignmentS(assignment))) = parse_statement(r#"private = "x";"#) else {
+      panic!("expected an assignment");
+    };
+    assert!(!assignment.private);
+    assert_eq!(assignment.name, "private");
+  }
+
+  #[test]
+  fn test_parse_with() {
+    use super::{parse_expression, Assignment, Expression, ExpressionKind, Pattern, Span};
+
+    assert_eq!(
+      strip(parse_expression(
+        r#"with r = row(noun) in { r.base r.plural }"#
+      )),
+      Ok((
+        "",
+        Expression::from(ExpressionKind::WithE(
+          vec![Assignment {
+            name: String::from("r"),
+            sticky: false,
+            private: false,
+            value: Box::new(Expression::from(ExpressionKind::CallE(
+              String::from("row"),
+              vec![Expression::variable("noun")]
+            ))),
+            span: Span::default(),
+          }],
+          Box::new(Expression::from(ExpressionKind::PatternE(Pattern {
+            parts: vec![
+              Expression::from(ExpressionKind::PropertyAccessE(
+                Box::new(Expression::variable("r")),
+                String::from("base")
+              )),
+              Expression::from(ExpressionKind::PropertyAccessE(
+                Box::new(Expression::variable("r")),
+                String::from("plural")
+              )),
+            ]
+          })))
+        ))
+      ))
+    );
+  }
+
+  #[test]
+  fn test_parse_with_multiple_bindings() {
+    use super::{parse_expression, Assignment, Expression, ExpressionKind, Span};
+
+    assert_eq!(
+      strip(parse_expression(r#"with a = "x", b = a in b"#)),
+      Ok((
+        "",
+        Expression::from(ExpressionKind::WithE(
+          vec![
+            Assignment {
+              name: String::from("a"),
+              sticky: false,
+              private: false,
+              value: Box::new(Expression::literal("x")),
+              span: Span::default(),
+            },
+            Assignment {
+              name: String::from("b"),
+              sticky: false,
+              private: false,
+              value: Box::new(Expression::variable("a")),
+              span: Span::default(),
+            }
+          ],
+          Box::new(Expression::variable("b"))
+        ))
+      ))
+    );
+  }
+
+  #[test]
+  fn test_parse_with_prefixed_identifier() {
+    use super::{parse_expression, Expression};
+
+    assert_eq!(
+      strip(parse_expression("without")),
+      Ok(("", Expression::variable("without")))
+    );
+  }
+
+  #[test]
+  fn test_parse_comments_after_modifiers() {
+    use super::{parse_expression, parse_program, ExpressionKind, Statement};
+
+    let (_, statements) = parse_program(
+      r#"sticky /* c */ x = "a"; sticky// c
+y = x;"#,
+    )
+    .unwrap();
+    assert!(statements.iter().all(
+      |statement| matches!(statement, Statement::AssignmentS(assignment) if assignment.sticky)
+    ));
+
+    let (rest, expression) = parse_expression(
+      r#"with /* c */ sticky // c
+ x = "a" in x"#,
+    )
+    .unwrap();
+    assert_eq!(rest, "");
+    assert!(matches!(
+      expression.kind,
+      ExpressionKind::WithE(bindings, _) if bindings[0].sticky && bindings[0].name == "x"
+    ));
+  }
+
+  #[test]
+  fn test_spans_are_compared() {
+    use super::{parse_expression, parse_source, without_spans};
+
+    let (_, tight) = parse_source("{ a }", parse_expression).unwrap();
+    let (_, loose) = parse_source("{  a }", parse_expression).unwrap();
+
+    assert_ne!(tight, loose);
+    assert_eq!(without_spans(tight), without_spans(loose));
+  }
+
+  #[test]
+  fn test_parse_with_body_starting_like_in() {
+    use super::{parse_expression, parse_program, ExpressionKind};
+
+    assert!(parse_program(r#"result = with a = "x" input;"#).is_err());
+
+    let (rest, expression) = parse_expression(r#"with a = "x" in input"#).unwrap();
+    assert_eq!(rest, "");
+    assert!(matches!(
+      expression.kind,
+      ExpressionKind::WithE(_, body)
+        if matches!(&body.kind, ExpressionKind::VariableE(name) if name == "input")
+    ));
+  }
+
+  #[test]
+  fn test_parse_comments_between_statements() {
+    use super::{parse_program, Assignment, Expression, Span, Statement};
+
+    let program = r#"
+      // The greeting
+      greeting = "Hello"; // trailing comment
+      /* A block comment
+         spanning multiple lines */
+      result = greeting;
+      // Comment at the end of the file"#;
+
+    assert_eq!(
+      strip(parse_program(program)),
+      Ok((
+        "",
+        vec![
+          Statement::AssignmentS(Assignment {
+            name: String::from("greeting"),
+            sticky: false,
+            private: false,
+            value: Box::new(Expression::literal("Hello")),
+            span: Span::default(),
+          }),
+          Statement::AssignmentS(Assignment {
+            name: String::from("result"),
+            sticky: false,
+            private: false,
+            value: Box::new(Expression::variable("greeting")),
+            span: Span::default(),
+          })
+        ]
+      ))
+    );
+  }
+
+  #[test]
+  fn test_parse_comments_in_bag() {
+    use super::{parse_bag, Bag, BagEntry};
+
+    let bag = r#"bag [ // first line
+      "a", // after an entry
+      /* before a weight */ 2.0 /* after a weight */ "b" /* before a comma */,
+      // before the closing bracket
+    ]"#;
+
+    assert_eq!(
+      strip(parse_bag(bag)),
+      Ok((
+        "",
+        Bag {
+          items: vec![
+            BagEntry::from_string("a"),
+            BagEntry::from_string("b").with_weight(2.0)
+          ]
+        }
+      ))
+    );
+  }
+
+  #[test]
+  fn test_parse_comments_in_table() {
+    use super::{parse_table, Expression, Span, Table, TableEntry, TableRow};
+
+    let table = r#"table [ // first line
+      [.base /* first column */, .plural], // header
+      // rows
+      ["kitten", /* append */ +"s"], /* between rows */
+      1.0 /* weight */ [_ /* hole */, "cats"]
+    ]"#;
+
+    assert_eq!(
+      strip(parse_table(table)),
+      Ok((
+        "",
+        Table {
+          header_span: Span::default(),
+          columns: vec![String::from("base"), String::from("plural")],
+          rows: vec![
+            TableRow {
+              weight: None,
+              items: vec![
+                TableEntry::Literal(Box::new(Expression::literal("kitten"))),
+                TableEntry::Append(Box::new(Expression::literal("s")))
+              ],
+              span: Span::default(),
+            },
+            TableRow {
+              weight: Some(1.0),
+              items: vec![
+                TableEntry::Hole,
+                TableEntry::Literal(Box::new(Expression::literal("cats")))
+              ],
+              span: Span::default(),
+            }
+          ]
+        }
+      ))
+    );
+  }
+
+  #[test]
+  fn test_parse_comments_in_pattern() {
+    use super::{parse_expression, Expression, ExpressionKind, Pattern};
+
+    assert_eq!(
+      strip(parse_expression(
+        "{ // greeting\n \"Hello \" /* name */ world /* end */ }"
+      )),
+      Ok((
+        "",
+        Expression::from(ExpressionKind::PatternE(Pattern {
+          parts: vec![Expression::literal("Hello "), Expression::variable("world")]
+        }))
+      ))
+    );
+  }
+
+  #[test]
+  fn test_parse_comments_in_call() {
+    use super::{parse_expression, Expression, ExpressionKind};
+
+    assert_eq!(
+      strip(parse_expression(
+        r#"capitalise( /* argument */ name // trailing
+      )"#
+      )),
+      Ok((
+        "",
+        Expression::from(ExpressionKind::CallE(
+          String::from("capitalise"),
+          vec![Expression::variable("name")]
+        ))
+      ))
+    );
+  }
+
+  #[test]
+  fn test_parse_comment_markers_in_string_literal() {
+    use super::parse_string_literal;
+
+    assert_eq!(
+      parse_string_literal(r#""// not /* a comment */""#),
+      Ok(("", String::from("// not /* a comment */")))
+    );
+  }
+
+  #[test]
+  fn test_parse_unterminated_block_comment() {
+    use super::parse_program;
+
+    assert!(parse_program(r#"result = "a"; /* never closed"#).is_err());
+  }
+
+  #[test]
+  fn test_parse_table_entry() {
+    use super::{parse_table_entry, Expression, TableEntry};
+
+    assert_eq!(
+      strip(parse_table_entry(r#""Harald""#)),
+      Ok((
+        "",
+        TableEntry::Literal(Box::new(Expression::literal("Harald")))
+      ))
+    );
+
+    assert_eq!(
+      strip(parse_table_entry(r#"+"in""#)),
+      Ok(("", TableEntry::Append(Box::new(Expression::literal("in")))))
+    );
+  }
+
   #[test]
   fn test_parse_table_row() {
-    use super::{parse_table_row, Expression, TableEntry, TableRow};
+    use super::{parse_table_row, Expression, Span, TableEntry, TableRow};
 
     assert_eq!(
-      parse_table_row(r#"["unicorn", "unicorns"]"#),
+      strip(parse_table_row(r#"["unicorn", "unicorns"]"#)),
       Ok((
         "",
         TableRow {
           weight: None,
           items: vec![
-            TableEntry::Literal(Box::new(Expression::LiteralE(String::from("unicorn")))),
-            TableEntry::Literal(Box::new(Expression::LiteralE(String::from("unicorns"))))
-          ]
+            TableEntry::Literal(Box::new(Expression::literal("unicorn"))),
+            TableEntry::Literal(Box::new(Expression::literal("unicorns")))
+          ],
+          span: Span::default(),
         }
       ))
     );
 
     assert_eq!(
-      parse_table_row(r#"["unicorn", +"s"]"#),
+      strip(parse_table_row(r#"["unicorn", +"s"]"#)),
       Ok((
         "",
         TableRow {
           weight: None,
           items: vec![
-            TableEntry::Literal(Box::new(Expression::LiteralE(String::from("unicorn")))),
-            TableEntry::Append(Box::new(Expression::LiteralE(String::from("s"))))
-          ]
+            TableEntry::Literal(Box::new(Expression::literal("unicorn"))),
+            TableEntry::Append(Box::new(Expression::literal("s")))
+          ],
+          span: Span::default(),
         }
       ))
     );
 
     assert_eq!(
-      parse_table_row(r#"[  "unicorn"  , + "s"   ]"#),
+      strip(parse_table_row(r#"[  "unicorn"  , + "s"   ]"#)),
       Ok((
         "",
         TableRow {
           weight: None,
           items: vec![
-            TableEntry::Literal(Box::new(Expression::LiteralE(String::from("unicorn")))),
-            TableEntry::Append(Box::new(Expression::LiteralE(String::from("s"))))
-          ]
+            TableEntry::Literal(Box::new(Expression::literal("unicorn"))),
+            TableEntry::Append(Box::new(Expression::literal("s")))
+          ],
+          span: Span::default(),
         }
       ))
     );
     assert_eq!(
-      parse_table_row(r#"0.5 ["a", "b"]"#),
+      strip(parse_table_row(r#"0.5 ["a", "b"]"#)),
       Ok((
         "",
         TableRow {
           weight: Some(0.5),
           items: vec![
-            TableEntry::Literal(Box::new(Expression::LiteralE(String::from("a")))),
-            TableEntry::Literal(Box::new(Expression::LiteralE(String::from("b"))))
-          ]
+            TableEntry::Literal(Box::new(Expression::literal("a"))),
+            TableEntry::Literal(Box::new(Expression::literal("b")))
+          ],
+          span: Span::default(),
         }
       ))
     );
@@ -559,7 +1375,7 @@ mod tests {
 
   #[test]
   fn test_parse_table() {
-    use super::{parse_table, Expression, Table, TableEntry, TableRow};
+    use super::{parse_table, Expression, Span, Table, TableEntry, TableRow};
 
     let table = r#"table [
             [.base, .plural],
@@ -568,25 +1384,28 @@ mod tests {
         ]"#;
 
     assert_eq!(
-      parse_table(table),
+      strip(parse_table(table)),
       Ok((
         "",
         Table {
+          header_span: Span::default(),
           columns: vec![String::from("base"), String::from("plural")],
           rows: vec![
             TableRow {
               weight: None,
               items: vec![
-                TableEntry::Literal(Box::new(Expression::LiteralE(String::from("unicorn")))),
-                TableEntry::Literal(Box::new(Expression::LiteralE(String::from("unicorns"))))
-              ]
+                TableEntry::Literal(Box::new(Expression::literal("unicorn"))),
+                TableEntry::Literal(Box::new(Expression::literal("unicorns")))
+              ],
+              span: Span::default(),
             },
             TableRow {
               weight: None,
               items: vec![
-                TableEntry::Literal(Box::new(Expression::LiteralE(String::from("kitten")))),
-                TableEntry::Append(Box::new(Expression::LiteralE(String::from("s"))))
-              ]
+                TableEntry::Literal(Box::new(Expression::literal("kitten"))),
+                TableEntry::Append(Box::new(Expression::literal("s")))
+              ],
+              span: Span::default(),
             }
           ]
         }
@@ -0,0 +1,124 @@
+//! Helpers for mapping byte offsets in a script back to human readable positions.
+
+use std::fmt::Display;
+
+/// A range of bytes in a script.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+  pub start: usize,
+  pub end: usize,
+}
+
+impl Span {
+  pub fn new(start: usize, end: usize) -> Span {
+    Span { start, end }
+  }
+}
+
+/// A 1-based line and column. Columns are counted in characters, not bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+  pub line: usize,
+  pub column: usize,
+}
+
+impl Position {
+  pub fn from_offset(source: &str, offset: usize) -> Position {
+    let before = &source[..offset.min(source.len())];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+
+    Position {
+      line: before.matches('\n').count() + 1,
+      column: before[line_start..].chars().count() + 1,
+    }
+  }
+}
+
+impl Display for Position {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}:{}", self.line, self.column)
+  }
+}
+
+/// Renders the line containing `offset`, with a caret pointing at the offset.
+pub fn render_snippet(source: &str, offset: usize) -> String {
+  let offset = offset.min(source.len());
+  let position = Position::from_offset(source, offset);
+
+  let line_start = source[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+  let line_end = source[offset..]
+    .find('\n')
+    .map(|i| offset + i)
+    .unwrap_or(source.len());
+  let line = source[line_start..line_end].trim_end_matches('\r');
+
+  let line_number = position.line.to_string();
+  let gutter = " ".repeat(line_number.len());
+
+  // Tabs are kept as is so that the caret lines up with the source line
+  let padding: String = line
+    .chars()
+    .take(position.column - 1)
+    .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+    .collect();
+
+  format!(
+    "{gutter} |\n{line_number} | {line}\n{gutter} | {padding}^",
+    gutter = gutter,
+    line_number = line_number,
+    line = line,
+    padding = padding
+  )
+}
+
+/// Formats a message like a compiler would: `file:line:column: message`, followed by a snippet of
+/// the line `span` starts on.
+pub fn render_message(file_name: &str, source: &str, span: Option<Span>, message: &str) -> String {
+  match span {
+    Some(span) => format!(
+      "{file_name}:{}: {message}\n{}",
+      Position::from_offset(source, span.start),
+      render_snippet(source, span.start)
+    ),
+    None => format!("{file_name}: {message}"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{render_snippet, Position};
+
+  #[test]
+  fn position_from_offset() {
+    let source = "first\nsecond\nthird";
+    assert_eq!(
+      Position::from_offset(source, 0),
+      Position { line: 1, column: 1 }
+    );
+    assert_eq!(
+      Position::from_offset(source, 8),
+      Position { line: 2, column: 3 }
+    );
+    assert_eq!(
+      Position::from_offset(source, source.len()),
+      Position { line: 3, column: 6 }
+    );
+  }
+
+  #[test]
+  fn position_counts_characters() {
+    assert_eq!(
+      Position::from_offset("äö = x", 5),
+      Position { line: 1, column: 4 }
+    );
+  }
+
+  #[test]
+  fn snippet() {
+    let source = "a = \"x\";\nresult = bag [\"a\" \"b\"];\n";
+    assert_eq!(
+      render_snippet(source, 18),
+      "  |\n2 | result = bag [\"a\" \"b\"];\n  |          ^"
+    );
+  }
+}
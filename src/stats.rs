@@ -0,0 +1,1131 @@
+//! Statistics about the outputs of a script's variables, computed from the structure of the script
+//! without running it.
+//!
+//! Each expression is summarised by combining the summaries of its parts: a bag is a weighted
+//! mixture of its items and a pattern is the concatenation of independent parts. As long as an
+//! expression has few enough outputs, every output is tracked along with its exact probability.
+//! Beyond that only aggregate figures are kept, which assume that different choices never produce
+//! the same string.
+//!
+//! Parts aren't independent when they use the same value: a sticky variable, a `with` binding or a
+//! function argument which was evaluated once, or a row which was chosen once. Summaries remember
+//! which such values they depend on. A binding whose uses would be combined as if they were
+//! independent is analysed once for each of its outputs instead, and if that isn't possible the
+//! result is marked as inexact.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{
+  articles::Articles,
+  enumerate::Outcome,
+  eval::{
+    BuiltInFunction, CompiledScript, Expression, ExpressionKind, FunctionLike, LocalVariable,
+    Table, ROW_BASE,
+  },
+  string_utils,
+};
+
+/// Expressions with more outputs than this are only summarised with aggregate figures.
+const MAX_TRACKED_OUTPUTS: usize = 10_000;
+
+/// Bound values with more outputs than this aren't analysed once for each output.
+const MAX_ENUMERATED_VALUES: usize = 100;
+
+/// Identifies a value which is evaluated once and then used as it is, like a sticky variable.
+type Id = u32;
+
+/// Statistics about the strings a variable produces.
+#[derive(Debug, Clone)]
+pub struct Stats {
+  /// Number of distinct outputs. Kept as a float, because the estimates for large scripts can
+  /// exceed every integer type.
+  pub outputs: f64,
+  /// Shannon entropy of the outputs in bits
+  pub entropy: f64,
+  /// Length of the shortest output, in characters
+  pub min_length: usize,
+  /// Length of the longest output, in characters
+  pub max_length: usize,
+  pub expected_length: f64,
+  pub most_likely: Outcome,
+  pub least_likely: Outcome,
+  /// Whether every output was tracked individually. Otherwise the statistics assume that different
+  /// choices never produce the same output, so `outputs` and `entropy` are upper bounds and the
+  /// most and least likely outputs are estimates.
+  pub exact: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum VariableStats {
+  Text(Stats),
+  /// Statistics of each column of a table, in column order
+  Table(Vec<(String, Stats)>),
+  /// The variable refers to itself, so it can produce infinitely many outputs. Contains the names
+  /// along the cycle.
+  Recursive(Vec<String>),
+  /// The variable isn't something which can be coerced to a string, such as a row
+  NotText,
+}
+
+impl CompiledScript {
+  /// Statistics about every global variable, ordered by name.
+  pub fn stats(&self) -> Vec<(String, VariableStats)> {
+    let mut names: Vec<_> = self.variables.keys().collect();
+    names.sort();
+
+    let mut analyser = Analyser::new(self);
+
+    names
+      .into_iter()
+      .map(|name| (name.clone(), analyser.variable_stats(name)))
+      .collect()
+  }
+
+  /// Statistics about the global variable `name`, or `None` if there is no such variable.
+  pub fn variable_stats(&self, name: &str) -> Option<VariableStats> {
+    self
+      .variables
+      .contains_key(name)
+      .then(|| Analyser::new(self).variable_stats(name))
+  }
+}
+
+#[derive(Debug, Clone)]
+struct Summary {
+  /// Every output with its probability, if there are few enough of them to keep track of
+  outcomes: Option<HashMap<String, f64>>,
+  count: f64,
+  entropy: f64,
+  min_length: usize,
+  max_length: usize,
+  expected_length: f64,
+  /// Probability of producing an empty string
+  empty: f64,
+  most_likely: (String, f64),
+  least_likely: (String, f64),
+  /// Values which the outputs depend on
+  dependencies: BTreeSet<Id>,
+  /// Values which were used more than once as if every use was independent. The summary is only an
+  /// estimate if there are any.
+  conflicts: BTreeSet<Id>,
+}
+
+impl Summary {
+  fn constant(text: &str) -> Self {
+    Self::from_outcomes(HashMap::from([(text.to_string(), 1.0)]))
+  }
+
+  /// Collects the dependencies of summaries which are combined. Values which more than one of them
+  /// depends on are conflicts, since the combination assumes the summaries are independent.
+  fn dependencies_of<'a>(
+    summaries: impl IntoIterator<Item = &'a Summary>,
+  ) -> (BTreeSet<Id>, BTreeSet<Id>) {
+    let mut dependencies = BTreeSet::new();
+    let mut conflicts = BTreeSet::new();
+
+    for summary in summaries {
+      conflicts.extend(summary.dependencies.intersection(&dependencies).copied());
+      conflicts.extend(summary.conflicts.iter().copied());
+      dependencies.extend(summary.dependencies.iter().copied());
+    }
+
+    (dependencies, conflicts)
+  }
+
+  /// Sets what the summary depends on. Outputs can't be tracked exactly if there are conflicts.
+  fn depending_on(mut self, (dependencies, conflicts): (BTreeSet<Id>, BTreeSet<Id>)) -> Self {
+    if !conflicts.is_empty() {
+      self.outcomes = None;
+    }
+
+    self.dependencies = dependencies;
+    self.conflicts = conflicts;
+    self
+  }
+
+  fn from_outcomes(outcomes: HashMap<String, f64>) -> Self {
+    let mut sorted: Vec<_> = outcomes.iter().collect();
+    sorted.sort_by(|(a, p), (b, q)| q.total_cmp(p).then_with(|| a.cmp(b)));
+
+    let length = |text: &str| text.chars().count();
+    let (most_likely, least_likely) = (sorted[0], sorted[sorted.len() - 1]);
+
+    Summary {
+      count: outcomes.len() as f64,
+      entropy: sorted.iter().map(|(_, p)| -*p * p.log2()).sum(),
+      min_length: sorted
+        .iter()
+        .map(|(text, _)| length(text))
+        .min()
+        .unwrap_or(0),
+      max_length: sorted
+        .iter()
+        .map(|(text, _)| length(text))
+        .max()
+        .unwrap_or(0),
+      expected_length: sorted
+        .iter()
+        .map(|(text, p)| length(text) as f64 * *p)
+        .sum(),
+      empty: outcomes.get("").copied().unwrap_or(0.0),
+      most_likely: (most_likely.0.clone(), *most_likely.1),
+      least_likely: (least_likely.0.clone(), *least_likely.1),
+      outcomes: Some(outcomes),
+      dependencies: BTreeSet::new(),
+      conflicts: BTreeSet::new(),
+    }
+  }
+
+  /// Picks one of `items` at random according to their weights.
+  fn mixture(items: Vec<(f64, Summary)>) -> Option<Self> {
+    let total: f64 = items.iter().map(|(weight, _)| weight).sum();
+    let items: Vec<_> = items
+      .into_iter()
+      .filter(|(weight, _)| *weight > 0.0)
+      .map(|(weight, summary)| (weight / total, summary))
+      .collect();
+
+    if items.is_empty() {
+      return None;
+    }
+
+    // Only one item is chosen, so items depending on the same value don't conflict
+    let dependencies = items
+      .iter()
+      .flat_map(|(_, summary)| &summary.dependencies)
+      .copied()
+      .collect();
+    let conflicts = items
+      .iter()
+      .flat_map(|(_, summary)| &summary.conflicts)
+      .copied()
+      .collect();
+
+    let tracked: usize = items
+      .iter()
+      .map(|(_, summary)| summary.outcomes.as_ref().map_or(usize::MAX, HashMap::len))
+      .fold(0, usize::saturating_add);
+
+    if tracked <= MAX_TRACKED_OUTPUTS {
+      let mut outcomes = HashMap::new();
+
+      for (p, summary) in &items {
+        for (text, q) in summary.outcomes.iter().flatten() {
+          *outcomes.entry(text.clone()).or_default() += p * q;
+        }
+      }
+
+      return Some(Self::from_outcomes(outcomes).depending_on((dependencies, conflicts)));
+    }
+
+    let weighted = |f: fn(&Summary) -> f64| items.iter().map(|(p, s)| p * f(s)).sum::<f64>();
+    let pick = |f: fn(&Summary) -> &(String, f64), most: bool| {
+      let candidates = items.iter().map(|(p, s)| (f(s).0.clone(), p * f(s).1));
+
+      if most {
+        candidates.max_by(|(_, a), (_, b)| a.total_cmp(b))
+      } else {
+        candidates.min_by(|(_, a), (_, b)| a.total_cmp(b))
+      }
+      .expect("mixture has items")
+    };
+
+    Some(Summary {
+      outcomes: None,
+      count: items.iter().map(|(_, s)| s.count).sum(),
+      entropy: items.iter().map(|(p, s)| p * (s.entropy - p.log2())).sum(),
+      min_length: items.iter().map(|(_, s)| s.min_length).min().unwrap_or(0),
+      max_length: items.iter().map(|(_, s)| s.max_length).max().unwrap_or(0),
+      expected_length: weighted(|s| s.expected_length),
+      empty: weighted(|s| s.empty),
+      most_likely: pick(|s| &s.most_likely, true),
+      least_likely: pick(|s| &s.least_likely, false),
+      dependencies,
+      conflicts,
+    })
+  }
+
+  /// Concatenates the outputs of two independent expressions.
+  fn concat(self, other: Summary) -> Self {
+    self.combine(other, |a, b| Some(format!("{a}{b}")))
+  }
+
+  /// Combines every output of `self` with every output of `other`, assuming they are independent.
+  /// `combine` returns `None` when the output of `self` is used as it is.
+  fn combine(self, other: Summary, combine: impl Fn(&str, &str) -> Option<String>) -> Self {
+    let dependencies = Self::dependencies_of([&self, &other]);
+
+    if let (Some(left), Some(right)) = (&self.outcomes, &other.outcomes) {
+      if left.len().saturating_mul(right.len()) <= MAX_TRACKED_OUTPUTS {
+        let mut outcomes = HashMap::new();
+
+        for (a, p) in left {
+          if combine(a, "").is_none() {
+            *outcomes.entry(a.clone()).or_default() += p;
+            continue;
+          }
+
+          for (b, q) in right {
+            let text = combine(a, b).unwrap_or_else(|| a.clone());
+            *outcomes.entry(text).or_default() += p * q;
+          }
+        }
+
+        return Self::from_outcomes(outcomes).depending_on(dependencies);
+      }
+    }
+
+    let combine_outcome = |(a, p): &(String, f64), (b, q): &(String, f64)| match combine(a, b) {
+      Some(text) => (text, p * q),
+      None => (a.clone(), *p),
+    };
+
+    Summary {
+      outcomes: None,
+      count: self.count * other.count,
+      entropy: self.entropy + other.entropy,
+      min_length: self.min_length + other.min_length,
+      max_length: self.max_length + other.max_length,
+      expected_length: self.expected_length + other.expected_length,
+      empty: self.empty * other.empty,
+      most_likely: combine_outcome(&self.most_likely, &other.most_likely),
+      least_likely: combine_outcome(&self.least_likely, &other.least_likely),
+      dependencies: BTreeSet::new(),
+      conflicts: BTreeSet::new(),
+    }
+    .depending_on(dependencies)
+  }
+
+  /// Applies `f` to every output.
+  fn map(self, f: impl Fn(&str) -> String) -> Self {
+    match self.outcomes {
+      Some(outcomes) => {
+        let mut mapped = HashMap::new();
+
+        for (text, p) in outcomes {
+          *mapped.entry(f(&text)).or_default() += p;
+        }
+
+        Self::from_outcomes(mapped).depending_on((self.dependencies, self.conflicts))
+      }
+      None => Summary {
+        most_likely: (f(&self.most_likely.0), self.most_likely.1),
+        least_likely: (f(&self.least_likely.0), self.least_likely.1),
+        ..self
+      },
+    }
+  }
+
+  /// Summarises a call to a text function with independent arguments. Every combination of
+  /// arguments is tried if there are few enough of them. Otherwise only functions which keep the
+  /// length of their only argument can be summarised.
+  fn apply(
+    function: BuiltInFunction,
+    arguments: Vec<Summary>,
+    articles: &Articles,
+  ) -> Option<Self> {
+    let dependencies = Self::dependencies_of(&arguments);
+    let combinations = arguments.iter().try_fold(1_usize, |count, argument| {
+      let outcomes = argument.outcomes.as_ref()?;
+      Some(count.saturating_mul(outcomes.len()))
+    });
+
+    if combinations.is_some_and(|count| count <= MAX_TRACKED_OUTPUTS) {
+      let mut outcomes = HashMap::from([(Vec::new(), 1.0)]);
+
+      for argument in arguments
+        .iter()
+        .filter_map(|argument| argument.outcomes.as_ref())
+      {
+        let mut extended = HashMap::new();
+
+        for (texts, p) in &outcomes {
+          for (text, q) in argument {
+            let mut texts: Vec<&str> = Vec::clone(texts);
+            texts.push(text);
+            extended.insert(texts, p * q);
+          }
+        }
+
+        outcomes = extended;
+      }
+
+      let mut applied = HashMap::new();
+
+      for (texts, p) in outcomes {
+        *applied
+          .entry(function.apply(&texts, articles).ok()?)
+          .or_default() += p;
+      }
+
+      return Some(Self::from_outcomes(applied).depending_on(dependencies));
+    }
+
+    match (function, <[_; 1]>::try_from(arguments)) {
+      (
+        BuiltInFunction::Upper
+        | BuiltInFunction::Lower
+        | BuiltInFunction::TitleCase
+        | BuiltInFunction::Reverse,
+        Ok([argument]),
+      ) => Some(argument.map(|text| function.apply(&[text], articles).unwrap_or_default())),
+      _ => None,
+    }
+  }
+
+  /// Summarises `maybePrepend` and `maybeAppend`, which add `affix` to `condition` unless it is
+  /// empty.
+  fn maybe_affix(condition: Summary, affix: Summary, append: bool) -> Self {
+    let empty = condition.empty;
+    let affix_entropy = affix.entropy;
+    let affix_expected_length = affix.expected_length;
+
+    let mut summary = condition.combine(affix, |condition, affix| {
+      if condition.is_empty() {
+        None
+      } else if append {
+        Some(format!("{condition}{affix}"))
+      } else {
+        Some(format!("{affix}{condition}"))
+      }
+    });
+
+    // The aggregate figures of `combine` assume the affix is always added
+    if summary.outcomes.is_none() {
+      summary.entropy -= empty * affix_entropy;
+      summary.expected_length -= empty * affix_expected_length;
+      summary.empty = empty;
+
+      if empty > 0.0 {
+        summary.min_length = 0;
+      }
+    }
+
+    summary
+  }
+
+  fn into_stats(self) -> Stats {
+    Stats {
+      outputs: self.count,
+      entropy: self.entropy.max(0.0),
+      min_length: self.min_length,
+      max_length: self.max_length,
+      expected_length: self.expected_length,
+      most_likely: Outcome {
+        text: self.most_likely.0,
+        probability: self.most_likely.1,
+      },
+      least_likely: Outcome {
+        text: self.least_likely.0,
+        probability: self.least_likely.1,
+      },
+      exact: self.outcomes.is_some(),
+    }
+  }
+}
+
+/// What an expression evaluates to, as far as the analysis is concerned.
+#[derive(Debug, Clone)]
+enum Shape<'s> {
+  Text(Summary, Reuse),
+  /// A table, along with the local variables visible where it was created
+  Table(&'s Table, Locals<'s>),
+  /// A row of a table, if it is known which one. A random row which is used as a single row has an
+  /// id, which the cells of the row depend on.
+  Row(&'s Table, Option<usize>, Locals<'s>, Option<Id>),
+}
+
+/// What using a text value again produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reuse {
+  /// The same string, because the value has already been evaluated to a string
+  Same,
+  /// A new sample, because the value is a bag
+  Resampled,
+  /// Either one, depending on earlier choices
+  Mixed,
+}
+
+impl Reuse {
+  fn either(self, other: Reuse) -> Reuse {
+    match self == other {
+      true => self,
+      false => Reuse::Mixed,
+    }
+  }
+}
+
+type Locals<'s> = Vec<(&'s str, Shape<'s>)>;
+
+struct Analyser<'s> {
+  script: &'s CompiledScript,
+  /// Shapes of global variables which have already been analysed
+  globals: HashMap<&'s str, Option<Shape<'s>>>,
+  /// Ids of the sticky global variables which have been analysed
+  sticky: HashMap<&'s str, Id>,
+  /// Sticky global variables which are currently analysed with a single output each
+  fixed: HashMap<&'s str, Shape<'s>>,
+  next_id: Id,
+}
+
+impl<'s> Analyser<'s> {
+  fn new(script: &'s CompiledScript) -> Self {
+    Analyser {
+      script,
+      globals: HashMap::new(),
+      sticky: HashMap::new(),
+      fixed: HashMap::new(),
+      next_id: 0,
+    }
+  }
+
+  fn fresh_id(&mut self) -> Id {
+    self.next_id += 1;
+    self.next_id
+  }
+
+  fn variable_stats(&mut self, name: &'s str) -> VariableStats {
+    if let Some(cycle) = self.script.find_recursion(name) {
+      return VariableStats::Recursive(cycle);
+    }
+
+    match self.global(name) {
+      Some(Shape::Text(summary, _)) => {
+        VariableStats::Text(self.fix_sticky(name, summary).into_stats())
+      }
+      Some(Shape::Table(table, locals)) => {
+        let columns = table
+          .columns
+          .iter()
+          .map(|column| {
+            let summary = self.column(table, column, &locals)?;
+            Some((column.clone(), summary.into_stats()))
+          })
+          .collect::<Option<_>>();
+
+        columns.map_or(VariableStats::NotText, VariableStats::Table)
+      }
+      Some(Shape::Row(..)) | None => VariableStats::NotText,
+    }
+  }
+
+  /// Analyses the global variable `name` again for every combination of outputs of the sticky
+  /// variables its summary uses more than once, if there are few enough of them.
+  fn fix_sticky(&mut self, name: &'s str, summary: Summary) -> Summary {
+    let mut conflicting: Vec<_> = self
+      .sticky
+      .iter()
+      .filter(|(_, id)| summary.conflicts.contains(id))
+      .map(|(sticky, _)| *sticky)
+      .collect();
+    conflicting.sort();
+
+    let mut values = Vec::with_capacity(conflicting.len());
+    let mut combinations = 1_usize;
+
+    for sticky in conflicting {
+      let Some(Shape::Text(
+        Summary {
+          outcomes: Some(outcomes),
+          ..
+        },
+        _,
+      )) = self.global(sticky)
+      else {
+        return summary;
+      };
+
+      combinations = combinations.saturating_mul(outcomes.len());
+
+      if combinations > MAX_ENUMERATED_VALUES {
+        return summary;
+      }
+
+      values.push((sticky, Vec::from_iter(outcomes)));
+    }
+
+    if values.is_empty() {
+      return summary;
+    }
+
+    let expression = &self.script.variables[name];
+    let mut outputs = Vec::with_capacity(combinations);
+
+    for mut combination in 0..combinations {
+      let mut p = 1.0;
+
+      for (sticky, outcomes) in &values {
+        let (text, q) = &outcomes[combination % outcomes.len()];
+        combination /= outcomes.len();
+        p *= q;
+
+        let shape = Shape::Text(Summary::constant(text), Reuse::Same);
+        self.fixed.insert(sticky, shape);
+      }
+
+      match self.shape(expression, &mut Vec::new()) {
+        Some(Shape::Text(output, _)) => outputs.push((p, output)),
+        _ => break,
+      }
+    }
+
+    self.fixed.clear();
+
+    match outputs.len() == combinations {
+      true => Summary::mixture(outputs).unwrap_or(summary),
+      false => summary,
+    }
+  }
+
+  fn global(&mut self, name: &'s str) -> Option<Shape<'s>> {
+    if let Some(shape) = self.fixed.get(name) {
+      return Some(shape.clone());
+    }
+
+    // Global variables depend on the fixed ones, so they can't be cached while there are any
+    let shape = match self.globals.get(name) {
+      Some(shape) if self.fixed.is_empty() => shape.clone(),
+      _ => {
+        let expression = self.script.variables.get(name)?;
+        let mut shape = self.shape(expression, &mut Vec::new());
+
+        // Sticky variables are evaluated to a string once per run
+        if let Some(Shape::Text(summary, reuse)) = &mut shape {
+          if self.script.sticky_variables.contains(name) {
+            let id = match self.sticky.get(name) {
+              Some(id) => *id,
+              None => self.fresh_id(),
+            };
+
+            self.sticky.insert(name, id);
+            summary.dependencies.insert(id);
+            *reuse = Reuse::Same;
+          }
+        }
+
+        if self.fixed.is_empty() {
+          self.globals.insert(name, shape.clone());
+        }
+
+        shape
+      }
+    };
+
+    // Every reference evaluates the variable again, so the values bound inside it are new ones
+    shape.map(|shape| self.renamed(shape, &mut HashMap::new()))
+  }
+
+  /// Gives the values a shape depends on new ids, except for sticky variables.
+  fn renamed(&mut self, shape: Shape<'s>, ids: &mut HashMap<Id, Id>) -> Shape<'s> {
+    match shape {
+      Shape::Text(mut summary, reuse) => {
+        summary.dependencies = summary
+          .dependencies
+          .into_iter()
+          .map(|id| self.renamed_id(id, ids))
+          .collect();
+        summary.conflicts = summary
+          .conflicts
+          .into_iter()
+          .map(|id| self.renamed_id(id, ids))
+          .collect();
+
+        Shape::Text(summary, reuse)
+      }
+      Shape::Table(table, locals) => Shape::Table(table, self.renamed_locals(locals, ids)),
+      Shape::Row(table, row, locals, id) => Shape::Row(
+        table,
+        row,
+        self.renamed_locals(locals, ids),
+        id.map(|id| self.renamed_id(id, ids)),
+      ),
+    }
+  }
+
+  fn renamed_locals(&mut self, locals: Locals<'s>, ids: &mut HashMap<Id, Id>) -> Locals<'s> {
+    locals
+      .into_iter()
+      .map(|(name, shape)| (name, self.renamed(shape, ids)))
+      .collect()
+  }
+
+  fn renamed_id(&mut self, id: Id, ids: &mut HashMap<Id, Id>) -> Id {
+    if self.sticky.values().any(|sticky| *sticky == id) {
+      return id;
+    }
+
+    match ids.get(&id) {
+      Some(renamed) => *renamed,
+      None => {
+        let renamed = self.fresh_id();
+        ids.insert(id, renamed);
+        renamed
+      }
+    }
+  }
+
+  fn text(&mut self, expression: &'s Expression, locals: &mut Locals<'s>) -> Option<Summary> {
+    match self.shape(expression, locals)? {
+      Shape::Text(summary, _) => Some(summary),
+      Shape::Table(..) | Shape::Row(..) => None,
+    }
+  }
+
+  fn column(&mut self, table: &'s Table, column: &str, locals: &Locals<'s>) -> Option<Summary> {
+    let bag = table.bags.get(column)?;
+    let mut locals = locals.clone();
+
+    let items = bag
+      .items
+      .iter()
+      .zip(&bag.weights)
+      .map(|(item, weight)| Some((*weight as f64, self.text(item, &mut locals)?)))
+      .collect::<Option<_>>()?;
+
+    Summary::mixture(items)
+  }
+
+  fn cell(
+    &mut self,
+    table: &'s Table,
+    row: usize,
+    column: &str,
+    locals: &Locals<'s>,
+  ) -> Option<(Summary, Reuse)> {
+    let column = table.columns.iter().position(|name| name == column)?;
+
+    match &table.rows[row][column] {
+      None => Some((Summary::constant(""), Reuse::Same)),
+      Some(expression) => match self.shape(expression, &mut locals.clone())? {
+        Shape::Text(summary, reuse) => Some((summary, reuse)),
+        Shape::Table(..) | Shape::Row(..) => None,
+      },
+    }
+  }
+
+  /// The local variables the cells of a row see, with how likely each set is. A row whose append
+  /// entries share a base is analysed once for each output of the base, if there are few enough.
+  fn row_locals(
+    &mut self,
+    table: &'s Table,
+    row: usize,
+    locals: &Locals<'s>,
+  ) -> Option<Vec<(f64, Locals<'s>)>> {
+    let Some(base) = &table.bases[row] else {
+      return Some(vec![(1.0, locals.clone())]);
+    };
+
+    let mut summary = self.text(base, &mut locals.clone())?;
+    let with_base = |summary| {
+      let mut locals = locals.clone();
+      locals.push((ROW_BASE, Shape::Text(summary, Reuse::Same)));
+      locals
+    };
+
+    match &summary.outcomes {
+      Some(outcomes) if outcomes.len() <= MAX_ENUMERATED_VALUES => {
+        let outcomes = outcomes.iter().map(|(text, p)| {
+          let mut constant = Summary::constant(text);
+          constant.dependencies = summary.dependencies.clone();
+          (*p, with_base(constant))
+        });
+
+        Some(outcomes.collect())
+      }
+      _ => {
+        summary.dependencies.insert(self.fresh_id());
+        Some(vec![(1.0, with_base(summary))])
+      }
+    }
+  }
+
+  fn shape(&mut self, expression: &'s Expression, locals: &mut Locals<'s>) -> Option<Shape<'s>> {
+    let (summary, reuse) = match &expression.kind {
+      ExpressionKind::LiteralE(literal) => (Summary::constant(literal), Reuse::Same),
+      ExpressionKind::VariableE(name) => {
+        if let Some((_, shape)) = locals.iter().rev().find(|(local, _)| local == name) {
+          return Some(shape.clone());
+        }
+
+        return self.global(name);
+      }
+      ExpressionKind::PatternE(pattern) => {
+        let mut summary = Summary::constant("");
+
+        for part in &pattern.parts {
+          summary = summary.concat(self.text(part, locals)?);
+        }
+
+        (summary, Reuse::Same)
+      }
+      ExpressionKind::BagE(bag) => {
+        let items = bag
+          .items
+          .iter()
+          .zip(&bag.weights)
+          .map(|(item, weight)| Some((*weight as f64, self.text(item, locals)?)))
+          .collect::<Option<_>>()?;
+
+        (Summary::mixture(items)?, Reuse::Resampled)
+      }
+      ExpressionKind::TableE(table) => return Some(Shape::Table(table, locals.clone())),
+      ExpressionKind::PropertyAccessE(target, property) => match self.shape(target, locals)? {
+        Shape::Text(..) => return None,
+        Shape::Table(table, locals) => (self.column(table, property, &locals)?, Reuse::Resampled),
+        Shape::Row(table, Some(row), locals, _) => self.cell(table, row, property, &locals)?,
+        Shape::Row(table, None, locals, id) => {
+          let mut reuse: Option<Reuse> = None;
+
+          let mut rows = Vec::new();
+
+          for (row, weight) in table.weights.iter().enumerate() {
+            for (p, locals) in self.row_locals(table, row, &locals)? {
+              let (summary, cell_reuse) = self.cell(table, row, property, &locals)?;
+              reuse = Some(reuse.map_or(cell_reuse, |reuse| reuse.either(cell_reuse)));
+              rows.push((*weight as f64 * p, summary));
+            }
+          }
+
+          let mut summary = Summary::mixture(rows)?;
+          summary.dependencies.extend(id);
+          (summary, reuse.unwrap_or(Reuse::Same))
+        }
+      },
+      ExpressionKind::CallE(FunctionLike::BuiltIn(function), arguments) => {
+        let summary = match (function, arguments.as_slice()) {
+          (BuiltInFunction::UpperFirst, [inner]) => self
+            .text(inner, locals)?
+            .map(string_utils::capitalise_first),
+          (BuiltInFunction::MaybePrepend, [prefix, condition]) => {
+            let condition = self.text(condition, locals)?;
+            Summary::maybe_affix(condition, self.text(prefix, locals)?, false)
+          }
+          (BuiltInFunction::MaybeAppend, [condition, suffix]) => {
+            let condition = self.text(condition, locals)?;
+            Summary::maybe_affix(condition, self.text(suffix, locals)?, true)
+          }
+          (BuiltInFunction::Row, [table]) => match self.shape(table, locals)? {
+            Shape::Table(table, locals) => return Some(Shape::Row(table, None, locals, None)),
+            _ => return None,
+          },
+          (function, arguments)
+            if function.is_text_function() && function.check_arity(arguments.len()).is_ok() =>
+          {
+            let arguments = arguments
+              .iter()
+              .map(|argument| self.text(argument, locals))
+              .collect::<Option<Vec<_>>>()?;
+
+            Summary::apply(*function, arguments, &self.script.articles)?
+          }
+          _ => return None,
+        };
+
+        (summary, Reuse::Same)
+      }
+      ExpressionKind::CallE(FunctionLike::UserDefined(name), arguments) => {
+        let function = self.script.functions.get(name)?;
+
+        if function.parameters.len() != arguments.len() {
+          return None;
+        }
+
+        let mut parameters = Vec::with_capacity(arguments.len());
+
+        for (parameter, argument) in function.parameters.iter().zip(arguments) {
+          parameters.push((parameter.as_str(), self.shape(argument, locals)?));
+        }
+
+        return self.parameters(&parameters, &function.body, &mut Vec::new());
+      }
+      ExpressionKind::WithE(bindings, body) => {
+        let depth = locals.len();
+        let shape = self.with(bindings, body, locals);
+        locals.truncate(depth);
+        return shape;
+      }
+    };
+
+    Some(Shape::Text(summary, reuse))
+  }
+
+  /// Binds the local variables of a `with` expression one by one.
+  fn with(
+    &mut self,
+    bindings: &'s [LocalVariable],
+    body: &'s Expression,
+    locals: &mut Locals<'s>,
+  ) -> Option<Shape<'s>> {
+    let Some((binding, rest)) = bindings.split_first() else {
+      return self.shape(body, locals);
+    };
+
+    let shape = match self.shape(&binding.expression, locals)? {
+      // Sticky bindings are evaluated to a string right away
+      Shape::Text(summary, _) if binding.sticky => Shape::Text(summary, Reuse::Same),
+      shape => shape,
+    };
+
+    self.bind(&binding.name, shape, locals, &mut |analyser, locals| {
+      analyser.with(rest, body, locals)
+    })
+  }
+
+  /// Binds the parameters of a function one by one.
+  fn parameters(
+    &mut self,
+    parameters: &[(&'s str, Shape<'s>)],
+    body: &'s Expression,
+    locals: &mut Locals<'s>,
+  ) -> Option<Shape<'s>> {
+    let Some(((name, shape), rest)) = parameters.split_first() else {
+      return self.shape(body, locals);
+    };
+
+    self.bind(name, shape.clone(), locals, &mut |analyser, locals| {
+      analyser.parameters(rest, body, locals)
+    })
+  }
+
+  /// Analyses `scope` with `name` bound to `shape`. A random row is analysed separately for each
+  /// row, so that the columns of the row stay together. A string which `scope` uses more than once
+  /// is analysed separately for each of its outputs.
+  fn bind(
+    &mut self,
+    name: &'s str,
+    shape: Shape<'s>,
+    locals: &mut Locals<'s>,
+    scope: &mut dyn FnMut(&mut Self, &mut Locals<'s>) -> Option<Shape<'s>>,
+  ) -> Option<Shape<'s>> {
+    let depth = locals.len();
+    let mut analyse = |analyser: &mut Self, shape, locals: &mut Locals<'s>| {
+      locals.push((name, shape));
+      let shape = scope(analyser, locals);
+      locals.truncate(depth);
+      shape
+    };
+
+    let mut outputs = Vec::new();
+    let mut reuse: Option<Reuse> = None;
+
+    match shape {
+      Shape::Row(table, None, table_locals, _) => {
+        for (row, weight) in table.weights.iter().enumerate() {
+          if *weight == 0.0 {
+            continue;
+          }
+
+          for (p, row_locals) in self.row_locals(table, row, &table_locals)? {
+            let shape = Shape::Row(table, Some(row), row_locals, None);
+
+            match analyse(self, shape, locals)? {
+              Shape::Text(summary, output_reuse) => {
+                outputs.push((*weight as f64 * p, summary));
+                reuse = Some(reuse.map_or(output_reuse, |reuse| reuse.either(output_reuse)));
+              }
+              // A table or a row made from the row can't be summarised as a mixture. The cells of
+              // the row depend on it instead, so using more than one of them is a conflict.
+              _ => {
+                let shape = Shape::Row(table, None, table_locals, Some(self.fresh_id()));
+                return analyse(self, shape, locals);
+              }
+            }
+          }
+        }
+      }
+      Shape::Text(summary, bound_reuse @ (Reuse::Same | Reuse::Mixed)) if summary.count > 1.0 => {
+        let id = self.fresh_id();
+        let mut bound = summary.clone();
+        bound.dependencies.insert(id);
+        let shape = analyse(self, Shape::Text(bound, bound_reuse), locals)?;
+
+        let outcomes = match (&shape, &summary.outcomes, bound_reuse) {
+          (Shape::Text(output, _), Some(outcomes), Reuse::Same)
+            if output.conflicts.contains(&id) && outcomes.len() <= MAX_ENUMERATED_VALUES =>
+          {
+            outcomes
+          }
+          _ => return Some(shape),
+        };
+
+        for (text, p) in outcomes {
+          let mut constant = Summary::constant(text);
+          constant.dependencies = summary.dependencies.clone();
+
+          match analyse(self, Shape::Text(constant, Reuse::Same), locals)? {
+            Shape::Text(output, output_reuse) => {
+              outputs.push((*p, output));
+              reuse = Some(reuse.map_or(output_reuse, |reuse| reuse.either(output_reuse)));
+            }
+            _ => return Some(shape),
+          }
+        }
+      }
+      shape => return analyse(self, shape, locals),
+    }
+
+    let summary = Summary::mixture(outputs)?;
+    Some(Shape::Text(summary, reuse.unwrap_or(Reuse::Same)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::compile_script;
+
+  fn stats(source: &str) -> Stats {
+    match compile_script(source).unwrap().variable_stats("result") {
+      Some(VariableStats::Text(stats)) => stats,
+      other => panic!("expected text, got {other:?}"),
+    }
+  }
+
+  #[test]
+  fn bag_stats() {
+    let stats = stats(r#"result = bag [ 6 "aa", "b", "b" ];"#);
+    let entropy = -(0.75f64 * 0.75f64.log2() + 0.25 * 0.25f64.log2());
+
+    assert_eq!(stats.outputs, 2.0);
+    assert!(stats.exact);
+    assert!((stats.entropy - entropy).abs() < 1e-9);
+    assert_eq!((stats.min_length, stats.max_length), (1, 2));
+    assert!((stats.expected_length - 1.75).abs() < 1e-9);
+    assert_eq!(stats.most_likely.text, "aa");
+    assert_eq!(stats.least_likely.text, "b");
+  }
+
+  #[test]
+  fn rows_keep_columns_together() {
+    let stats = stats(
+      r#"
+      t = table [ [.a, .b], ["x", "X"], ["y", "Y"] ];
+      result = with r = row(t) in { r.a r.b };
+      "#,
+    );
+
+    assert_eq!(stats.outputs, 2.0);
+    assert!((stats.entropy - 1.0).abs() < 1e-9);
+  }
+
+  #[test]
+  fn large_patterns_are_estimated() {
+    let stats = stats(
+      r#"
+      digit = bag ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"];
+      result = { digit digit digit digit digit digit };
+      "#,
+    );
+
+    assert!(!stats.exact);
+    assert_eq!(stats.outputs, 1_000_000.0);
+    assert!((stats.entropy - 6.0 * 10f64.log2()).abs() < 1e-9);
+    assert_eq!((stats.min_length, stats.max_length), (6, 6));
+  }
+
+  #[test]
+  fn maybe_append_skips_empty() {
+    let stats = stats(r#"result = maybeAppend(bag ["", "a"], "!");"#);
+
+    assert_eq!(stats.outputs, 2.0);
+    assert_eq!((stats.min_length, stats.max_length), (0, 2));
+  }
+
+  #[test]
+  fn text_functions_combine_arguments() {
+    let stats = stats(r#"result = join(" ", bag ["a", ""], upper(bag ["b", "c"]));"#);
+
+    assert!(stats.exact);
+    assert_eq!(stats.outputs, 4.0);
+    assert_eq!((stats.min_length, stats.max_length), (1, 3));
+    assert!((stats.most_likely.probability - 0.25).abs() < 1e-9);
+  }
+
+  #[test]
+  fn large_text_functions_are_estimated() {
+    let stats = stats(
+      r#"
+      digit = bag ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"];
+      result = upper({ digit digit digit digit digit digit });
+      "#,
+    );
+
+    assert!(!stats.exact);
+    assert_eq!((stats.min_length, stats.max_length), (6, 6));
+    assert_eq!(
+      stats.most_likely.text.to_uppercase(),
+      stats.most_likely.text
+    );
+  }
+
+  /// Checks that the exact statistics of `result` agree with the enumerated outputs.
+  fn assert_matches_distribution(source: &str) {
+    let distribution = compile_script(source)
+      .unwrap()
+      .distribution("result")
+      .unwrap();
+    let stats = stats(source);
+    let entropy: f64 = distribution
+      .iter()
+      .map(|outcome| -outcome.probability * outcome.probability.log2())
+      .sum();
+
+    assert!(stats.exact, "{source}");
+    assert_eq!(stats.outputs, distribution.len() as f64, "{source}");
+    assert!((stats.entropy - entropy).abs() < 1e-9, "{source}");
+    assert_eq!(stats.most_likely.text, distribution[0].text, "{source}");
+  }
+
+  #[test]
+  fn values_bound_once_stay_correlated() {
+    let stats = stats(r#"sticky s = bag ["p", "q"]; result = { s s };"#);
+
+    assert!(stats.exact);
+    assert_eq!(stats.outputs, 2.0);
+    assert!((stats.entropy - 1.0).abs() < 1e-9);
+
+    for source in [
+      r#"sticky s = bag ["p", "q"]; result = { s s };"#,
+      r#"sticky s = bag ["p", "q"]; t = { s "-" }; result = { t s bag ["x", "y"] };"#,
+      r#"x = bag ["p", "q"]; result = with v = { x } in { v v };"#,
+      r#"x = bag ["p", "q"]; result = with v = bag [x, "r"] in { v v };"#,
+      r#"x = bag ["p", "q"]; result = with sticky v = bag [x, "r"] in { v "/" v };"#,
+      r#"twice(v) = { v v }; result = twice({ bag ["p", "q"] });"#,
+      r#"twice(v) = { v v }; result = twice(bag ["p", "q"]);"#,
+      r#"pair(a, b) = { a b a }; result = pair(upper(bag ["p", "q"]), bag ["r", "s"]);"#,
+      r#"
+      t = table [ [.a, .b], ["x", bag ["1", "2"]], ["y", "Y"] ];
+      result = with r = row(t) in { r.a r.b r.a };
+      "#,
+      r#"
+      t = table [ [.a, .b], [bag ["x", "y"], +"!"], ["z", +"?"] ];
+      result = with r = row(t) in { r.a r.b };
+      "#,
+    ] {
+      assert_matches_distribution(source);
+    }
+  }
+
+  #[test]
+  fn unresolved_correlations_are_estimated() {
+    let stats = stats(
+      r#"
+      digit = bag ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"];
+      result = with v = { digit digit digit } in { v v };
+      "#,
+    );
+
+    assert!(!stats.exact);
+    assert_eq!((stats.min_length, stats.max_length), (6, 6));
+  }
+
+  #[test]
+  fn recursion_is_infinite() {
+    let script = compile_script(r#"result = bag [ "", { "a" result } ];"#).unwrap();
+
+    assert!(matches!(
+      script.variable_stats("result"),
+      Some(VariableStats::Recursive(_))
+    ));
+  }
+}
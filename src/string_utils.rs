@@ -23,9 +23,70 @@ pub fn capitalise_first(s: &str) -> String {
   }
 }
 
+/// Capitalises the first letter of every word, leaving the other letters as they are.
+pub fn title_case(s: &str) -> String {
+  let mut title = String::with_capacity(s.len());
+  let mut word_start = true;
+
+  for ch in s.chars() {
+    if word_start {
+      title.extend(ch.to_uppercase());
+    } else {
+      title.push(ch);
+    }
+
+    word_start = ch.is_whitespace() || ch == '-';
+  }
+
+  title
+}
+
+/// Placeholders for Finnish suffix vowels, along with their back and front forms.
+const HARMONY_PLACEHOLDERS: [(&str, &str, &str); 3] =
+  [("{a}", "a", "ä"), ("{o}", "o", "ö"), ("{u}", "u", "y")];
+
+/// Whether `s` contains a vowel harmony placeholder such as `{a}`.
+pub fn has_harmony_placeholder(s: &str) -> bool {
+  HARMONY_PLACEHOLDERS
+    .iter()
+    .any(|(placeholder, _, _)| s.contains(placeholder))
+}
+
+/// Whether suffixes of a Finnish word take back vowels. The last vowel which isn't neutral decides,
+/// so compounds like "kesäkurpitsa" follow their last part. Words with only neutral vowels, like
+/// "tili", take front vowels.
+pub fn takes_back_vowels(word: &str) -> bool {
+  word
+    .chars()
+    .rev()
+    .flat_map(char::to_lowercase)
+    .find_map(|ch| match ch {
+      'a' | 'o' | 'u' => Some(true),
+      'ä' | 'ö' | 'y' => Some(false),
+      _ => None,
+    })
+    .unwrap_or(false)
+}
+
+/// Appends `suffix` to `base`, replacing the placeholders `{a}`, `{o}` and `{u}` with the vowel
+/// that agrees with the base: `harmonise("talo", "ss{a}")` is "talossa" and
+/// `harmonise("kylä", "ss{a}")` is "kylässä".
+pub fn harmonise(base: &str, suffix: &str) -> String {
+  let back = takes_back_vowels(base);
+
+  let suffix = HARMONY_PLACEHOLDERS.iter().fold(
+    suffix.to_string(),
+    |suffix, (placeholder, back_vowel, front_vowel)| {
+      suffix.replace(placeholder, if back { back_vowel } else { front_vowel })
+    },
+  );
+
+  format!("{base}{suffix}")
+}
+
 #[cfg(test)]
 mod tests {
-  use super::capitalise_first;
+  use super::{capitalise_first, harmonise, title_case};
 
   #[test]
   fn capitalise_first_basic() {
@@ -36,4 +97,28 @@ mod tests {
     assert_eq!(capitalise_first("äe"), String::from("Äe"));
     assert_eq!(capitalise_first("😎"), String::from("😎"));
   }
+
+  #[test]
+  fn title_case_words() {
+    assert_eq!(title_case(""), "");
+    assert_eq!(title_case("paistettu silakka"), "Paistettu Silakka");
+    assert_eq!(
+      title_case("  äidin  omena-piirakka"),
+      "  Äidin  Omena-Piirakka"
+    );
+    assert_eq!(title_case("McDonald's"), "McDonald's");
+  }
+
+  #[test]
+  fn vowel_harmony() {
+    assert_eq!(harmonise("talo", "ss{a}"), "talossa");
+    assert_eq!(harmonise("kylä", "ss{a}"), "kylässä");
+    assert_eq!(harmonise("tili", "{a}"), "tiliä");
+    assert_eq!(harmonise("kesäkurpitsa", "{a}"), "kesäkurpitsaa");
+    assert_eq!(harmonise("Syö", "k{o}"), "Syökö");
+    assert_eq!(harmonise("SUU", "k{o}"), "SUUko");
+    assert_eq!(harmonise("juo", "n{u}t"), "juonut");
+    assert_eq!(harmonise("syö", "n{u}t"), "syönyt");
+    assert_eq!(harmonise("talo", "{e}"), "talo{e}");
+  }
 }
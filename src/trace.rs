@@ -0,0 +1,135 @@
+//! Records which bag entries and table rows were chosen during a run, to explain where each part of
+//! an output came from.
+
+use std::fmt::{self, Display, Write};
+
+use serde_json::json;
+
+/// The output of a traced run, along with every choice made to produce it.
+#[derive(Debug, Clone)]
+pub struct Trace {
+  pub output: String,
+  /// Choices made directly by the entry point. Choices made while evaluating a chosen entry are
+  /// its children.
+  pub choices: Vec<TraceNode>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChoiceKind {
+  /// An entry of a bag, or of a table column
+  Entry,
+  /// A row chosen with `row`
+  Row,
+}
+
+/// A single choice made during a traced run.
+#[derive(Debug, Clone)]
+pub struct TraceNode {
+  /// Variable which defines the bag or table
+  pub variable: String,
+  /// Column the bag was extracted from, if it is a table column
+  pub column: Option<String>,
+  pub kind: ChoiceKind,
+  /// Index of the chosen entry or row
+  pub index: usize,
+  /// Weight of the chosen entry or row
+  pub weight: f32,
+  /// The text the chosen entry produced. Empty for rows, whose columns are traced as they are read.
+  pub text: String,
+  pub children: Vec<TraceNode>,
+}
+
+impl TraceNode {
+  pub fn to_json(&self) -> serde_json::Value {
+    json!({
+      "variable": self.variable,
+      "column": self.column,
+      "kind": match self.kind {
+        ChoiceKind::Entry => "entry",
+        ChoiceKind::Row => "row",
+      },
+      "index": self.index,
+      "weight": self.weight,
+      "text": self.text,
+      "children": self.children.iter().map(TraceNode::to_json).collect::<Vec<_>>(),
+    })
+  }
+
+  fn render(&self, depth: usize, out: &mut String) -> fmt::Result {
+    write!(out, "{:indent$}{}", "", self.variable, indent = depth * 2)?;
+
+    if let Some(column) = &self.column {
+      write!(out, ".{column}")?;
+    }
+
+    match self.kind {
+      ChoiceKind::Entry => writeln!(out, "[{}] ({}) {:?}", self.index, self.weight, self.text)?,
+      ChoiceKind::Row => writeln!(out, " row {} ({})", self.index, self.weight)?,
+    }
+
+    for child in &self.children {
+      child.render(depth + 1, out)?;
+    }
+
+    Ok(())
+  }
+}
+
+impl Trace {
+  pub fn to_json(&self) -> serde_json::Value {
+    json!({
+      "output": self.output,
+      "choices": self.choices.iter().map(TraceNode::to_json).collect::<Vec<_>>(),
+    })
+  }
+}
+
+/// Renders the output followed by the choices as an indented tree, one choice per line:
+/// `variable.column[index] (weight) "text"` for entries and `variable row index (weight)` for rows.
+impl Display for Trace {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let mut out = String::new();
+    writeln!(out, "{}", self.output)?;
+
+    for choice in &self.choices {
+      choice.render(1, &mut out)?;
+    }
+
+    f.write_str(out.trim_end())
+  }
+}
+
+/// Builds the tree of choices while a run is in progress.
+#[derive(Debug, Default)]
+pub(crate) struct TraceBuilder {
+  /// Choices whose text hasn't been produced yet, innermost last
+  open: Vec<TraceNode>,
+  roots: Vec<TraceNode>,
+}
+
+impl TraceBuilder {
+  pub(crate) fn open(&mut self, node: TraceNode) {
+    self.open.push(node);
+  }
+
+  /// Finishes the innermost open choice.
+  pub(crate) fn close(&mut self, text: &str) {
+    let Some(mut node) = self.open.pop() else {
+      return;
+    };
+
+    node.text = text.to_string();
+
+    match self.open.last_mut() {
+      Some(parent) => parent.children.push(node),
+      None => self.roots.push(node),
+    }
+  }
+
+  pub(crate) fn finish(self, output: String) -> Trace {
+    Trace {
+      output,
+      choices: self.roots,
+    }
+  }
+}
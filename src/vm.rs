@@ -0,0 +1,1175 @@
+//! A compact bytecode form of a [`CompiledScript`] and the virtual machine which runs it.
+//!
+//! Lowering resolves every global variable, function, bag and table to an index, so running a
+//! program does no name lookups. Each expression is compiled in one or both of two modes:
+//!
+//! - _emit_ code evaluates the expression, coerces it to a string and appends it to the output
+//!   buffer. Most of a script only ever runs in this mode, so patterns never allocate.
+//! - _value_ code pushes the value of the expression to the value stack. It is used where the
+//!   language needs an actual value, such as `with` bindings, function arguments and the targets of
+//!   property access.
+//!
+//! The machine makes the same random choices in the same order as the tree-walking interpreter, so
+//! both produce the same output from identically seeded generators.
+
+use std::{collections::HashMap, fmt, rc::Rc, sync::Arc};
+
+use rand::Rng;
+use rand_distr::{Distribution, WeightedIndex};
+
+use crate::{
+  articles::Articles,
+  eval::{
+    Bag, BuiltInFunction, CompiledScript, Expression, ExpressionKind, FunctionError, FunctionLike,
+    InterpreterError, Table, MAX_CALL_DEPTH, ROW_BASE,
+  },
+  source::Span,
+  string_utils,
+};
+
+type Addr = u32;
+
+/// Size of the scratch buffer at which finished text is written to the sink.
+const FLUSH_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+  /// Appends a string literal to the output
+  Text(u32),
+  EmitGlobal(u32),
+  PushGlobal(u32),
+  EmitLocal(u32),
+  PushLocal(u32),
+  /// Samples a bag and runs the emit code of the chosen entry in the current frame
+  EmitBag(u32),
+  /// Samples a table column whose entries can't refer to local variables, in a new frame
+  EmitColumn(u32),
+  /// Pushes a bag along with the local variables it can see
+  PushBag(u32),
+  /// Pushes a table along with the local variables it can see
+  PushTable(u32),
+  PushString(u32),
+  /// Pops a value and appends it to the output, sampling it if it is a bag
+  Emit,
+  /// Pops a table or a row and pushes the value of one of its columns
+  Property(u32),
+  /// Pops a table or a row and appends one of its columns to the output
+  EmitProperty(u32),
+  /// Pops a table and pushes a random row of it
+  Row,
+  /// Pops a value and pushes it back, sampled until it is not a bag
+  Force,
+  /// Pops a value and binds it to the next local variable slot
+  Bind,
+  /// Drops the given number of local variables
+  Unbind(u32),
+  CallEmit(u32),
+  CallValue(u32),
+  /// Remembers the current length of the output
+  Mark,
+  PopMark,
+  /// Pops the mark and skips the given number of instructions if nothing has been written since it
+  SkipIfUnchanged(u32),
+  /// Pops two marks and moves the text written after the second one to the first one
+  Prepend,
+  /// Pops a mark and capitalises the text written after it
+  Capitalise,
+  /// Pops a mark and moves the text written after it to the value stack
+  Capture,
+  /// Pops a value and pushes its text, for the given argument of a text function
+  TextArgument(BuiltInFunction, u32),
+  /// Pops the given number of strings and appends the output of a text function to them
+  ApplyText(BuiltInFunction, u32),
+  Fail(u32),
+  Return,
+}
+
+/// A problem found during lowering, which is reported if the instruction is ever reached, just like
+/// the tree-walking interpreter reports it.
+#[derive(Debug, Clone)]
+enum Failure {
+  UnknownVariable(String),
+  UnknownFunction(String),
+  WrongNumberOfArguments {
+    function: FunctionLike,
+    expected: usize,
+    was: usize,
+  },
+  BuiltIn(BuiltInFunction, FunctionError),
+}
+
+impl Failure {
+  fn to_error(&self) -> InterpreterError {
+    match self {
+      Failure::UnknownVariable(name) => InterpreterError::UnknownVariable(name.clone()),
+      Failure::UnknownFunction(name) => InterpreterError::UnknownFunction(name.clone()),
+      Failure::WrongNumberOfArguments {
+        function,
+        expected,
+        was,
+      } => InterpreterError::FunctionError {
+        function: function.clone(),
+        inner: FunctionError::WrongNumberOfArguments {
+          expected: *expected,
+          was: *was,
+        },
+      },
+      Failure::BuiltIn(function, inner) => InterpreterError::FunctionError {
+        function: FunctionLike::BuiltIn(*function),
+        inner: inner.clone(),
+      },
+    }
+  }
+}
+
+#[derive(Debug)]
+struct Global {
+  emit: Addr,
+  value: Addr,
+  sticky: bool,
+  span: Span,
+}
+
+#[derive(Debug)]
+struct Function {
+  name: String,
+  arity: usize,
+  emit: Addr,
+  value: Addr,
+}
+
+#[derive(Debug)]
+struct ProgramBag {
+  distribution: WeightedIndex<f32>,
+  emit: Vec<Addr>,
+  value: Vec<Addr>,
+}
+
+#[derive(Debug)]
+struct ProgramTable {
+  distribution: WeightedIndex<f32>,
+  columns: Vec<String>,
+  /// Bag of each column, in column order
+  column_bags: Vec<u32>,
+  /// Emit and value code of each cell, row-major. Holes are `None`.
+  cells: Vec<Vec<Option<(Addr, Addr)>>>,
+  /// Value code of the base each row shares with its append entries, if it has one. The cells of
+  /// such a row find the base in the slot after the captured locals.
+  bases: Vec<Option<Addr>>,
+}
+
+/// A script lowered into bytecode. Created with [`CompiledScript::lower`].
+#[derive(Debug)]
+pub struct Program {
+  code: Vec<Op>,
+  /// Location of the expression each instruction was compiled from
+  spans: Vec<Span>,
+  strings: Vec<Arc<str>>,
+  properties: Vec<String>,
+  failures: Vec<Failure>,
+  globals: Vec<Global>,
+  global_ids: HashMap<String, u32>,
+  functions: Vec<Function>,
+  bags: Vec<ProgramBag>,
+  tables: Vec<ProgramTable>,
+  articles: Articles,
+}
+
+impl CompiledScript {
+  /// Lowers the script into bytecode, which runs faster than the script itself.
+  pub fn lower(&self) -> Program {
+    Lowering::new(self).lower()
+  }
+
+  /// The script lowered into bytecode. It's lowered the first time it's needed and kept until the
+  /// script changes, so repeated batches don't pay for lowering again.
+  pub fn program(&self) -> &Program {
+    self.program.get_or_init(|| self.lower())
+  }
+}
+
+impl Program {
+  pub fn run_with_rng(&self, rng: &mut impl Rng) -> Result<String, InterpreterError> {
+    self.run_entry_with_rng("result", rng)
+  }
+
+  pub fn run_entry_with_rng(
+    &self,
+    name: &str,
+    rng: &mut impl Rng,
+  ) -> Result<String, InterpreterError> {
+    let mut output = String::new();
+    self.run_entry_into(name, rng, &mut output)?;
+    Ok(output)
+  }
+
+  /// Runs `result` and writes its output into `out` as it is produced.
+  pub fn run_into(
+    &self,
+    rng: &mut impl Rng,
+    out: &mut impl fmt::Write,
+  ) -> Result<(), InterpreterError> {
+    self.run_entry_into("result", rng, out)
+  }
+
+  /// Runs the entry point `name` and writes its output into `out` as it is produced. Text is
+  /// collected in a scratch buffer and written whenever it grows past a few kilobytes, unless a
+  /// `capitalise`, `maybePrepend` or `maybeAppend` still needs to see it. If the run fails, `out`
+  /// keeps whatever was written before the error.
+  pub fn run_entry_into(
+    &self,
+    name: &str,
+    rng: &mut impl Rng,
+    out: &mut impl fmt::Write,
+  ) -> Result<(), InterpreterError> {
+    let id = self
+      .global_ids
+      .get(name)
+      .ok_or_else(|| InterpreterError::UnknownEntry(name.to_string()))?;
+
+    let global = &self.globals[*id as usize];
+
+    let mut machine = Machine {
+      program: self,
+      rng,
+      sink: out,
+      locals: Vec::new(),
+      values: Vec::new(),
+      marks: Vec::new(),
+      sticky: Vec::new(),
+      depth: 0,
+    };
+
+    let mut buffer = String::new();
+
+    machine
+      .exec(global.emit, 0, &mut buffer)
+      .map_err(|error| error.at(global.span))?;
+
+    Ok(out.write_str(&buffer)?)
+  }
+}
+
+struct Lowering<'s> {
+  script: &'s CompiledScript,
+  program: Program,
+  /// Instructions of the subroutines being compiled, innermost last
+  buffers: Vec<(Vec<Op>, Vec<Span>)>,
+  strings: HashMap<&'s str, u32>,
+  properties: HashMap<&'s str, u32>,
+  function_ids: HashMap<&'s str, u32>,
+  bag_ids: HashMap<*const Bag, u32>,
+  table_ids: HashMap<*const Table, u32>,
+}
+
+impl<'s> Lowering<'s> {
+  fn new(script: &'s CompiledScript) -> Self {
+    Lowering {
+      script,
+      program: Program {
+        code: Vec::new(),
+        spans: Vec::new(),
+        strings: Vec::new(),
+        properties: Vec::new(),
+        failures: Vec::new(),
+        globals: Vec::new(),
+        global_ids: HashMap::new(),
+        functions: Vec::new(),
+        bags: Vec::new(),
+        tables: Vec::new(),
+        articles: script.articles.clone(),
+      },
+      buffers: Vec::new(),
+      strings: HashMap::new(),
+      properties: HashMap::new(),
+      function_ids: HashMap::new(),
+      bag_ids: HashMap::new(),
+      table_ids: HashMap::new(),
+    }
+  }
+
+  fn lower(mut self) -> Program {
+    let mut globals: Vec<_> = self.script.variables.iter().collect();
+    globals.sort_by_key(|(name, _)| name.as_str());
+
+    // Ids are assigned before anything is compiled, because globals and functions can refer to
+    // each other in any order
+    for (id, (name, _)) in globals.iter().enumerate() {
+      self.program.global_ids.insert(name.to_string(), id as u32);
+    }
+
+    let mut functions: Vec<_> = self.script.functions.iter().collect();
+    functions.sort_by_key(|(name, _)| name.as_str());
+
+    for (id, (name, _)) in functions.iter().enumerate() {
+      self.function_ids.insert(name, id as u32);
+    }
+
+    for (name, expression) in globals {
+      let emit = self.subroutine(|lowering| lowering.emit(expression, &mut Vec::new()));
+      let value = self.subroutine(|lowering| lowering.value(expression, &mut Vec::new()));
+
+      self.program.globals.push(Global {
+        emit,
+        value,
+        sticky: self.script.sticky_variables.contains(name),
+        span: expression.span,
+      });
+    }
+
+    for (name, function) in functions {
+      let parameters: Vec<_> = function.parameters.iter().map(String::as_str).collect();
+
+      let emit = self.subroutine(|lowering| lowering.emit(&function.body, &mut parameters.clone()));
+      let value =
+        self.subroutine(|lowering| lowering.value(&function.body, &mut parameters.clone()));
+
+      self.program.functions.push(Function {
+        name: name.to_string(),
+        arity: parameters.len(),
+        emit,
+        value,
+      });
+    }
+
+    self.program
+  }
+
+  /// Compiles a separate piece of code which ends with `Return`, and returns its address.
+  fn subroutine(&mut self, compile: impl FnOnce(&mut Self)) -> Addr {
+    self.buffers.push((Vec::new(), Vec::new()));
+    compile(self);
+    self.op(Op::Return, Span::default());
+
+    let (code, spans) = self.buffers.pop().expect("a subroutine is being compiled");
+    let address = self.program.code.len() as Addr;
+    self.program.code.extend(code);
+    self.program.spans.extend(spans);
+    address
+  }
+
+  fn op(&mut self, op: Op, span: Span) {
+    let (code, spans) = self
+      .buffers
+      .last_mut()
+      .expect("a subroutine is being compiled");
+    code.push(op);
+    spans.push(span);
+  }
+
+  /// Emits a `SkipIfUnchanged` whose distance is filled in by `end_skip`.
+  fn begin_skip(&mut self, span: Span) -> usize {
+    self.op(Op::SkipIfUnchanged(0), span);
+    self.buffers.last().map_or(0, |(code, _)| code.len() - 1)
+  }
+
+  fn end_skip(&mut self, at: usize) {
+    let (code, _) = self
+      .buffers
+      .last_mut()
+      .expect("a subroutine is being compiled");
+    code[at] = Op::SkipIfUnchanged((code.len() - at - 1) as u32);
+  }
+
+  fn string(&mut self, string: &'s str) -> u32 {
+    let strings = &mut self.program.strings;
+    *self.strings.entry(string).or_insert_with(|| {
+      strings.push(Arc::from(string));
+      strings.len() as u32 - 1
+    })
+  }
+
+  fn property(&mut self, property: &'s str) -> u32 {
+    let properties = &mut self.program.properties;
+    *self.properties.entry(property).or_insert_with(|| {
+      properties.push(property.to_string());
+      properties.len() as u32 - 1
+    })
+  }
+
+  fn fail(&mut self, failure: Failure, span: Span) {
+    self.program.failures.push(failure);
+    self.op(Op::Fail(self.program.failures.len() as u32 - 1), span);
+  }
+
+  fn bag(&mut self, bag: &'s Bag, locals: &[&'s str]) -> u32 {
+    if let Some(id) = self.bag_ids.get(&(bag as *const Bag)) {
+      return *id;
+    }
+
+    let mut emit = Vec::with_capacity(bag.items.len());
+    let mut value = Vec::with_capacity(bag.items.len());
+
+    for item in &bag.items {
+      emit.push(self.subroutine(|lowering| lowering.emit(item, &mut locals.to_vec())));
+      value.push(self.subroutine(|lowering| lowering.value(item, &mut locals.to_vec())));
+    }
+
+    self.program.bags.push(ProgramBag {
+      distribution: bag.distribution.clone(),
+      emit,
+      value,
+    });
+
+    let id = self.program.bags.len() as u32 - 1;
+    self.bag_ids.insert(bag, id);
+    id
+  }
+
+  fn table(&mut self, table: &'s Table, locals: &[&'s str]) -> u32 {
+    if let Some(id) = self.table_ids.get(&(table as *const Table)) {
+      return *id;
+    }
+
+    let column_bags = table
+      .columns
+      .iter()
+      .map(|column| self.bag(&table.bags[column], locals))
+      .collect();
+
+    let bases = table
+      .bases
+      .iter()
+      .map(|base| {
+        base
+          .as_ref()
+          .map(|base| self.subroutine(|lowering| lowering.value(base, &mut locals.to_vec())))
+      })
+      .collect();
+
+    let cells = table
+      .rows
+      .iter()
+      .zip(&table.bases)
+      .map(|(row, base)| {
+        let mut locals = locals.to_vec();
+
+        if base.is_some() {
+          locals.push(ROW_BASE);
+        }
+
+        row
+          .iter()
+          .map(|cell| {
+            cell.as_ref().map(|cell| {
+              let emit = self.subroutine(|lowering| lowering.emit(cell, &mut locals.clone()));
+              let value = self.subroutine(|lowering| lowering.value(cell, &mut locals.clone()));
+              (emit, value)
+            })
+          })
+          .collect()
+      })
+      .collect();
+
+    self.program.tables.push(ProgramTable {
+      distribution: table.distribution.clone(),
+      columns: table.columns.clone(),
+      column_bags,
+      cells,
+      bases,
+    });
+
+    let id = self.program.tables.len() as u32 - 1;
+    self.table_ids.insert(table, id);
+    id
+  }
+
+  /// Finds the slot of a local variable. Later bindings shadow earlier ones.
+  fn local(locals: &[&str], name: &str) -> Option<u32> {
+    locals
+      .iter()
+      .rposition(|local| *local == name)
+      .map(|slot| slot as u32)
+  }
+
+  /// The table a global variable is defined as, if it can be sampled directly.
+  fn global_table(&self, name: &str) -> Option<&'s Table> {
+    if self.script.sticky_variables.contains(name) {
+      return None;
+    }
+
+    match &self.script.variables.get(name)?.kind {
+      ExpressionKind::TableE(table) => Some(table),
+      _ => None,
+    }
+  }
+
+  /// Compiles the arguments of a call to a user defined function, or a failure if the call is
+  /// invalid. Returns the id of the function.
+  fn arguments(
+    &mut self,
+    name: &str,
+    arguments: &'s [Expression],
+    locals: &mut Vec<&'s str>,
+    span: Span,
+  ) -> Option<u32> {
+    let Some(id) = self.function_ids.get(name).copied() else {
+      self.fail(Failure::UnknownFunction(name.to_string()), span);
+      return None;
+    };
+
+    let expected = self.script.functions[name].parameters.len();
+
+    if expected != arguments.len() {
+      let failure = Failure::WrongNumberOfArguments {
+        function: FunctionLike::UserDefined(name.to_string()),
+        expected,
+        was: arguments.len(),
+      };
+      self.fail(failure, span);
+      return None;
+    }
+
+    for argument in arguments {
+      self.value(argument, locals);
+    }
+
+    Some(id)
+  }
+
+  fn bindings(
+    &mut self,
+    bindings: &'s [crate::eval::LocalVariable],
+    locals: &mut Vec<&'s str>,
+    span: Span,
+  ) {
+    for binding in bindings {
+      self.value(&binding.expression, locals);
+
+      if binding.sticky {
+        self.op(Op::Force, span);
+      }
+
+      self.op(Op::Bind, span);
+      locals.push(&binding.name);
+    }
+  }
+
+  fn emit(&mut self, expression: &'s Expression, locals: &mut Vec<&'s str>) {
+    let span = expression.span;
+
+    match &expression.kind {
+      ExpressionKind::LiteralE(literal) => {
+        if !literal.is_empty() {
+          let id = self.string(literal);
+          self.op(Op::Text(id), span);
+        }
+      }
+      ExpressionKind::VariableE(name) => {
+        if let Some(slot) = Self::local(locals, name) {
+          self.op(Op::EmitLocal(slot), span);
+        } else if let Some(id) = self.program.global_ids.get(name.as_str()) {
+          self.op(Op::EmitGlobal(*id), span);
+        } else {
+          self.fail(Failure::UnknownVariable(name.clone()), span);
+        }
+      }
+      ExpressionKind::PatternE(pattern) => {
+        for part in &pattern.parts {
+          self.emit(part, locals);
+        }
+      }
+      ExpressionKind::BagE(bag) => {
+        let id = self.bag(bag, locals);
+        self.op(Op::EmitBag(id), span);
+      }
+      ExpressionKind::PropertyAccessE(target, property) => {
+        // Columns of global tables are by far the most common property access, so they are
+        // sampled directly
+        if let ExpressionKind::VariableE(name) = &target.kind {
+          let table = match Self::local(locals, name) {
+            None => self.global_table(name),
+            Some(_) => None,
+          };
+
+          if let Some(table) = table {
+            if let Some(column) = table.columns.iter().position(|c| c == property) {
+              let id = self.table(table, &[]);
+              let bag = self.program.tables[id as usize].column_bags[column];
+              self.op(Op::EmitColumn(bag), span);
+              return;
+            }
+          }
+        }
+
+        self.value(target, locals);
+        let id = self.property(property);
+        self.op(Op::EmitProperty(id), span);
+      }
+      ExpressionKind::CallE(FunctionLike::BuiltIn(function), arguments) => {
+        match (function, arguments.as_slice()) {
+          (BuiltInFunction::UpperFirst, [inner]) => {
+            self.op(Op::Mark, span);
+            self.emit(inner, locals);
+            self.op(Op::Capitalise, span);
+          }
+          (BuiltInFunction::MaybePrepend, [prefix, condition]) => {
+            self.op(Op::Mark, span);
+            self.emit(condition, locals);
+            let skip = self.begin_skip(span);
+            self.op(Op::Mark, span);
+            self.emit(prefix, locals);
+            self.op(Op::Prepend, span);
+            self.end_skip(skip);
+          }
+          (BuiltInFunction::MaybeAppend, [condition, suffix]) => {
+            self.op(Op::Mark, span);
+            self.emit(condition, locals);
+            let skip = self.begin_skip(span);
+            self.op(Op::PopMark, span);
+            self.emit(suffix, locals);
+            self.end_skip(skip);
+          }
+          (function, arguments) if function.is_text_function() => {
+            if let Err(inner) = function.check_arity(arguments.len()) {
+              self.fail(Failure::BuiltIn(*function, inner), span);
+              return;
+            }
+
+            for (i, argument) in arguments.iter().enumerate() {
+              self.value(argument, locals);
+              self.op(Op::TextArgument(*function, i as u32 + 1), span);
+            }
+
+            self.op(Op::ApplyText(*function, arguments.len() as u32), span);
+          }
+          _ => {
+            // Rows can't be coerced to strings, so this always fails, but only if it's reached
+            self.value(expression, locals);
+            self.op(Op::Emit, span);
+          }
+        }
+      }
+      ExpressionKind::CallE(FunctionLike::UserDefined(name), arguments) => {
+        if let Some(id) = self.arguments(name, arguments, locals, span) {
+          self.op(Op::CallEmit(id), span);
+        }
+      }
+      ExpressionKind::WithE(bindings, body) => {
+        let depth = locals.len();
+        self.bindings(bindings, locals, span);
+        self.emit(body, locals);
+        self.op(Op::Unbind(bindings.len() as u32), span);
+        locals.truncate(depth);
+      }
+      ExpressionKind::TableE(_) => {
+        self.value(expression, locals);
+        self.op(Op::Emit, span);
+      }
+    }
+  }
+
+  fn value(&mut self, expression: &'s Expression, locals: &mut Vec<&'s str>) {
+    let span = expression.span;
+
+    match &expression.kind {
+      ExpressionKind::LiteralE(literal) => {
+        let id = self.string(literal);
+        self.op(Op::PushString(id), span);
+      }
+      ExpressionKind::VariableE(name) => {
+        if let Some(slot) = Self::local(locals, name) {
+          self.op(Op::PushLocal(slot), span);
+        } else if let Some(id) = self.program.global_ids.get(name.as_str()) {
+          self.op(Op::PushGlobal(*id), span);
+        } else {
+          self.fail(Failure::UnknownVariable(name.clone()), span);
+        }
+      }
+      ExpressionKind::BagE(bag) => {
+        let id = self.bag(bag, locals);
+        self.op(Op::PushBag(id), span);
+      }
+      ExpressionKind::TableE(table) => {
+        let id = self.table(table, locals);
+        self.op(Op::PushTable(id), span);
+      }
+      ExpressionKind::PropertyAccessE(target, property) => {
+        self.value(target, locals);
+        let id = self.property(property);
+        self.op(Op::Property(id), span);
+      }
+      ExpressionKind::CallE(FunctionLike::BuiltIn(BuiltInFunction::Row), arguments) => {
+        match arguments.as_slice() {
+          [table] => {
+            self.value(table, locals);
+            self.op(Op::Row, span);
+          }
+          _ => self.fail(
+            Failure::WrongNumberOfArguments {
+              function: FunctionLike::BuiltIn(BuiltInFunction::Row),
+              expected: 1,
+              was: arguments.len(),
+            },
+            span,
+          ),
+        }
+      }
+      ExpressionKind::CallE(FunctionLike::BuiltIn(function), arguments) => {
+        if let Err(inner) = function.check_arity(arguments.len()) {
+          self.fail(Failure::BuiltIn(*function, inner), span);
+          return;
+        }
+
+        self.op(Op::Mark, span);
+        self.emit(expression, locals);
+        self.op(Op::Capture, span);
+      }
+      ExpressionKind::CallE(FunctionLike::UserDefined(name), arguments) => {
+        if let Some(id) = self.arguments(name, arguments, locals, span) {
+          self.op(Op::CallValue(id), span);
+        }
+      }
+      ExpressionKind::WithE(bindings, body) => {
+        let depth = locals.len();
+        self.bindings(bindings, locals, span);
+        self.value(body, locals);
+        self.op(Op::Unbind(bindings.len() as u32), span);
+        locals.truncate(depth);
+      }
+      ExpressionKind::PatternE(_) => {
+        self.op(Op::Mark, span);
+        self.emit(expression, locals);
+        self.op(Op::Capture, span);
+      }
+    }
+  }
+}
+
+/// Local variables visible to a bag or a table, captured when it was evaluated.
+type Env = Option<Rc<[Value]>>;
+
+#[derive(Debug, Clone)]
+enum Value {
+  String(Arc<str>),
+  Bag(u32, Env),
+  Table(u32, Env),
+  Row(u32, usize, Env),
+}
+
+impl Value {
+  fn type_name(&self) -> &'static str {
+    match self {
+      Value::String(_) => "string",
+      Value::Bag(..) => "bag",
+      Value::Table(..) => "table",
+      Value::Row(..) => "row",
+    }
+  }
+}
+
+enum Flow {
+  Next,
+  Skip(u32),
+  Return,
+}
+
+/// State of a single run.
+struct Machine<'p, 'r, R: Rng + ?Sized> {
+  program: &'p Program,
+  rng: &'r mut R,
+  /// Where the output is written once nothing can change it anymore
+  sink: &'r mut dyn fmt::Write,
+  /// Local variables of every active frame. Each frame addresses its locals from its own base.
+  locals: Vec<Value>,
+  values: Vec<Value>,
+  marks: Vec<usize>,
+  /// Values of sticky global variables which have been evaluated during this run
+  sticky: Vec<Option<Value>>,
+  /// Number of user defined functions being called
+  depth: usize,
+}
+
+impl<'p, 'r, R: Rng + ?Sized> Machine<'p, 'r, R> {
+  fn exec(&mut self, mut pc: Addr, base: usize, out: &mut String) -> Result<(), InterpreterError> {
+    let program = self.program;
+
+    loop {
+      let at = pc as usize;
+      pc += 1;
+
+      let flow = self
+        .step(program.code[at], base, out)
+        .map_err(|error| error.at(program.spans[at]))?;
+
+      if out.len() >= FLUSH_SIZE && self.marks.is_empty() {
+        self.sink.write_str(out)?;
+        out.clear();
+      }
+
+      match flow {
+        Flow::Next => {}
+        Flow::Skip(count) => pc += count,
+        Flow::Return => return Ok(()),
+      }
+    }
+  }
+
+  fn step(&mut self, op: Op, base: usize, out: &mut String) -> Result<Flow, InterpreterError> {
+    let program = self.program;
+
+    match op {
+      Op::Text(id) => out.push_str(&program.strings[id as usize]),
+      Op::EmitGlobal(id) => {
+        let global = &program.globals[id as usize];
+
+        if global.sticky {
+          let value = self.sticky(id, out)?;
+          self.emit_value(value, out)?;
+        } else {
+          self.exec(global.emit, self.locals.len(), out)?;
+        }
+      }
+      Op::PushGlobal(id) => {
+        let global = &program.globals[id as usize];
+
+        if global.sticky {
+          let value = self.sticky(id, out)?;
+          self.values.push(value);
+        } else {
+          self.exec(global.value, self.locals.len(), out)?;
+        }
+      }
+      Op::EmitLocal(slot) => {
+        let value = self.locals[base + slot as usize].clone();
+        self.emit_value(value, out)?;
+      }
+      Op::PushLocal(slot) => {
+        let value = self.locals[base + slot as usize].clone();
+        self.values.push(value);
+      }
+      Op::EmitBag(id) => {
+        let bag = &program.bags[id as usize];
+        let i = bag.distribution.sample(self.rng);
+        self.exec(bag.emit[i], base, out)?;
+      }
+      Op::EmitColumn(id) => {
+        let bag = &program.bags[id as usize];
+        let i = bag.distribution.sample(self.rng);
+        self.exec(bag.emit[i], self.locals.len(), out)?;
+      }
+      Op::PushBag(id) => {
+        let env = self.capture(base);
+        self.values.push(Value::Bag(id, env));
+      }
+      Op::PushTable(id) => {
+        let env = self.capture(base);
+        self.values.push(Value::Table(id, env));
+      }
+      Op::PushString(id) => {
+        let string = program.strings[id as usize].clone();
+        self.values.push(Value::String(string));
+      }
+      Op::Emit => {
+        let value = self.pop();
+        self.emit_value(value, out)?;
+      }
+      Op::Property(id) => {
+        let value = self.pop();
+        let value = self.property(value, id, out)?;
+        self.values.push(value);
+      }
+      Op::EmitProperty(id) => {
+        let value = self.pop();
+
+        match value {
+          Value::Row(table, row, env) => {
+            let column = self.column(table, id)?;
+
+            if let Some((emit, _)) = program.tables[table as usize].cells[row][column] {
+              self.exec_in(&env, emit, out)?;
+            }
+          }
+          value => {
+            let value = self.property(value, id, out)?;
+            self.emit_value(value, out)?;
+          }
+        }
+      }
+      Op::Row => match self.pop() {
+        Value::Table(id, env) => {
+          let table = &program.tables[id as usize];
+          let row = table.distribution.sample(self.rng);
+
+          // The base of the row is shared by its append entries
+          let env = match table.bases[row] {
+            None => env,
+            Some(base) => {
+              self.exec_in(&env, base, out)?;
+              let value = self.pop();
+              let base = self.string(value, out)?;
+              let locals = env.iter().flat_map(|env| env.iter().cloned());
+              Some(locals.chain([base]).collect())
+            }
+          };
+
+          self.values.push(Value::Row(id, row, env));
+        }
+        otherwise => {
+          return Err(InterpreterError::FunctionError {
+            function: FunctionLike::BuiltIn(BuiltInFunction::Row),
+            inner: FunctionError::UnexpectedArgumentType {
+              n: 1,
+              expected: "table",
+              was: otherwise.type_name(),
+            },
+          })
+        }
+      },
+      Op::Force => {
+        let value = self.pop();
+        let value = self.force(value, out)?;
+        self.values.push(value);
+      }
+      Op::Bind => {
+        let value = self.pop();
+        self.locals.push(value);
+      }
+      Op::Unbind(count) => {
+        self.locals.truncate(self.locals.len() - count as usize);
+      }
+      Op::CallEmit(id) | Op::CallValue(id) => {
+        let function = &program.functions[id as usize];
+
+        if self.depth >= MAX_CALL_DEPTH {
+          return Err(InterpreterError::RecursionLimit {
+            function: function.name.clone(),
+            limit: MAX_CALL_DEPTH,
+          });
+        }
+
+        let frame = self.locals.len();
+        let arguments = self.values.len() - function.arity;
+        self.locals.extend(self.values.drain(arguments..));
+
+        let address = match op {
+          Op::CallEmit(_) => function.emit,
+          _ => function.value,
+        };
+
+        self.depth += 1;
+        let result = self.exec(address, frame, out);
+        self.depth -= 1;
+        result?;
+        self.locals.truncate(frame);
+      }
+      Op::Mark => self.marks.push(out.len()),
+      Op::PopMark => {
+        self.marks.pop();
+      }
+      Op::SkipIfUnchanged(count) => {
+        if self.marks.last() == Some(&out.len()) {
+          self.marks.pop();
+          return Ok(Flow::Skip(count));
+        }
+      }
+      Op::Prepend => {
+        let (prefix, start) = (self.pop_mark(), self.pop_mark());
+        let prefix = out.split_off(prefix);
+        out.insert_str(start, &prefix);
+      }
+      Op::Capitalise => {
+        let start = self.pop_mark();
+
+        match out[start..].chars().next() {
+          Some(first) if first.is_ascii() => out[start..start + 1].make_ascii_uppercase(),
+          Some(_) => {
+            let capitalised = string_utils::capitalise_first(&out[start..]);
+            out.truncate(start);
+            out.push_str(&capitalised);
+          }
+          None => {}
+        }
+      }
+      Op::Capture => {
+        let start = self.pop_mark();
+        let string = Arc::from(&out[start..]);
+        out.truncate(start);
+        self.values.push(Value::String(string));
+      }
+      Op::TextArgument(function, n) => match self.pop() {
+        Value::String(string) => self.values.push(Value::String(string)),
+        bag @ Value::Bag(..) => {
+          let start = out.len();
+          self.marks.push(start);
+          self.emit_value(bag, out)?;
+          self.marks.pop();
+
+          let string = Arc::from(&out[start..]);
+          out.truncate(start);
+          self.values.push(Value::String(string));
+        }
+        otherwise => {
+          return Err(InterpreterError::FunctionError {
+            function: FunctionLike::BuiltIn(function),
+            inner: FunctionError::UnexpectedArgumentType {
+              n: n as usize,
+              expected: "string",
+              was: otherwise.type_name(),
+            },
+          })
+        }
+      },
+      Op::ApplyText(function, count) => {
+        let start = self.values.len() - count as usize;
+        let arguments: Vec<_> = self
+          .values
+          .drain(start..)
+          .map(|value| match value {
+            Value::String(string) => string,
+            _ => unreachable!("text arguments are strings"),
+          })
+          .collect();
+
+        let output = function
+          .apply(&arguments, &program.articles)
+          .map_err(|inner| InterpreterError::FunctionError {
+            function: FunctionLike::BuiltIn(function),
+            inner,
+          })?;
+        out.push_str(&output);
+      }
+      Op::Fail(id) => return Err(program.failures[id as usize].to_error()),
+      Op::Return => return Ok(Flow::Return),
+    }
+
+    Ok(Flow::Next)
+  }
+
+  fn pop(&mut self) -> Value {
+    self.values.pop().expect("value stack underflow")
+  }
+
+  fn pop_mark(&mut self) -> usize {
+    self.marks.pop().expect("mark stack underflow")
+  }
+
+  /// Captures the locals of the current frame for a bag or a table.
+  fn capture(&self, base: usize) -> Env {
+    (self.locals.len() > base).then(|| Rc::from(&self.locals[base..]))
+  }
+
+  /// Runs code in a new frame which contains the captured locals.
+  fn exec_in(
+    &mut self,
+    env: &Env,
+    address: Addr,
+    out: &mut String,
+  ) -> Result<(), InterpreterError> {
+    let frame = self.locals.len();
+
+    if let Some(env) = env {
+      self.locals.extend(env.iter().cloned());
+    }
+
+    self.exec(address, frame, out)?;
+    self.locals.truncate(frame);
+    Ok(())
+  }
+
+  fn emit_value(&mut self, value: Value, out: &mut String) -> Result<(), InterpreterError> {
+    match value {
+      Value::String(string) => out.push_str(&string),
+      Value::Bag(id, env) => {
+        let bag = &self.program.bags[id as usize];
+        let i = bag.distribution.sample(self.rng);
+        self.exec_in(&env, bag.emit[i], out)?;
+      }
+      otherwise => {
+        return Err(InterpreterError::CoercionError {
+          target: "string",
+          was: otherwise.type_name(),
+        })
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Samples bags until the value is something other than a bag.
+  fn force(&mut self, mut value: Value, out: &mut String) -> Result<Value, InterpreterError> {
+    while let Value::Bag(id, env) = value {
+      let bag = &self.program.bags[id as usize];
+      let i = bag.distribution.sample(self.rng);
+      self.exec_in(&env, bag.value[i], out)?;
+      value = self.pop();
+    }
+
+    Ok(value)
+  }
+
+  /// Samples bags until the value is a string, and fails if it's something else.
+  fn string(&mut self, value: Value, out: &mut String) -> Result<Value, InterpreterError> {
+    match self.force(value, out)? {
+      string @ Value::String(_) => Ok(string),
+      otherwise => Err(InterpreterError::CoercionError {
+        target: "string",
+        was: otherwise.type_name(),
+      }),
+    }
+  }
+
+  fn sticky(&mut self, id: u32, out: &mut String) -> Result<Value, InterpreterError> {
+    let index = id as usize;
+
+    if let Some(Some(value)) = self.sticky.get(index) {
+      return Ok(value.clone());
+    }
+
+    self.exec(self.program.globals[index].value, self.locals.len(), out)?;
+    let value = self.pop();
+    let value = self.force(value, out)?;
+
+    if self.sticky.len() <= index {
+      self.sticky.resize(index + 1, None);
+    }
+
+    self.sticky[index] = Some(value.clone());
+    Ok(value)
+  }
+
+  fn column(&self, table: u32, property: u32) -> Result<usize, InterpreterError> {
+    let table = &self.program.tables[table as usize];
+    let property = &self.program.properties[property as usize];
+
+    table
+      .columns
+      .iter()
+      .position(|column| column == property)
+      .ok_or_else(|| InterpreterError::TableMissingProperty {
+        columns: table.columns.clone(),
+        key: property.clone(),
+      })
+  }
+
+  fn property(
+    &mut self,
+    value: Value,
+    property: u32,
+    out: &mut String,
+  ) -> Result<Value, InterpreterError> {
+    match value {
+      Value::Table(table, env) => {
+        let column = self.column(table, property)?;
+        let bag = self.program.tables[table as usize].column_bags[column];
+        Ok(Value::Bag(bag, env))
+      }
+      Value::Row(table, row, env) => {
+        let column = self.column(table, property)?;
+
+        match self.program.tables[table as usize].cells[row][column] {
+          // Holes behave like empty strings, just like in append entries
+          None => Ok(Value::String(Arc::from(""))),
+          Some((_, value)) => {
+            self.exec_in(&env, value, out)?;
+            Ok(self.pop())
+          }
+        }
+      }
+      otherwise => Err(InterpreterError::CannotBeIndexed {
+        was: otherwise.type_name(),
+        key: self.program.properties[property as usize].clone(),
+      }),
+    }
+  }
+}
@@ -0,0 +1,146 @@
+use harald::{
+  check_script,
+  eval::{CompilerError, CompilerWarning, ExecutionError},
+};
+use matches::assert_matches;
+
+#[test]
+fn check_reports_all_problems() {
+  let diagnostics = check_script(include_str!("./check_problems.hd"), true);
+
+  let errors: Vec<_> = diagnostics
+    .errors
+    .iter()
+    .map(|error| match error {
+      ExecutionError::Compiler(error) => error,
+      otherwise => panic!("Expected a compiler error, got {:?}", otherwise),
+    })
+    .collect();
+
+  assert_eq!(errors.len(), 4);
+  assert_matches!(
+    errors[0],
+    CompilerError::InvalidTableRow { row_number: 1, .. }
+  );
+  assert_matches!(errors[1], CompilerError::UndefinedVariable { name, .. } if name == "undefinedThing");
+  assert_matches!(errors[2], CompilerError::EmptyBag { name, .. } if name == "d");
+  assert_matches!(errors[3], CompilerError::UndefinedVariable { name, .. } if name == "e");
+
+  assert_eq!(diagnostics.warnings.len(), 1);
+  assert_matches!(
+    &diagnostics.warnings[0],
+    CompilerWarning::ZeroWeightBagEntry { entry_number: 0, in_variable, .. } if in_variable == "a"
+  );
+}
+
+#[test]
+fn check_reports_every_problem_in_a_statement() {
+  let source = r#"result = { bag [] with x = bag [-1 "a"] in x table [
+  [.a, .b],
+  [+"s", "c"],
+  ["d"],
+  ["e", +"f"]
+] };"#;
+  let diagnostics = check_script(source, true);
+
+  let errors: Vec<_> = diagnostics
+    .errors
+    .iter()
+    .map(|error| match error {
+      ExecutionError::Compiler(error) => error,
+      otherwise => panic!("Expected a compiler error, got {:?}", otherwise),
+    })
+    .collect();
+
+  assert_eq!(errors.len(), 4, "{errors:?}");
+  assert_matches!(errors[0], CompilerError::EmptyBag { .. });
+  assert_matches!(errors[1], CompilerError::InvalidBagWeight { .. });
+  assert_matches!(
+    errors[2],
+    CompilerError::AppendInFirstColumn { row_number: 0, .. }
+  );
+  assert_matches!(
+    errors[3],
+    CompilerError::InvalidTableRow { row_number: 1, .. }
+  );
+}
+
+#[test]
+fn check_does_not_report_references_to_broken_statements() {
+  let diagnostics = check_script("a = bag [];\nresult = a;", true);
+
+  assert_eq!(diagnostics.errors.len(), 1);
+  assert_matches!(
+    diagnostics.errors[0],
+    ExecutionError::Compiler(CompilerError::EmptyBag { .. })
+  );
+}
+
+#[test]
+fn check_render() {
+  let source = "a = bag [0 \"x\", \"y\"];\nresult = { a b };";
+  let diagnostics = check_script(source, true);
+
+  assert_eq!(
+    diagnostics.render("test.hd", source),
+    [
+      "test.hd:1:10: warning: Entry 0 of bag a has weight 0, so it can never be chosen",
+      "  |",
+      "1 | a = bag [0 \"x\", \"y\"];",
+      "  |          ^",
+      "test.hd:2:14: error: Variable b is not defined",
+      "  |",
+      "2 | result = { a b };",
+      "  |              ^",
+    ]
+    .join("\n")
+  );
+}
+
+#[test]
+fn check_render_redefinition() {
+  let source = "a = \"x\";\nresult = a;\na = \"y\";";
+  let diagnostics = check_script(source, true);
+
+  assert_eq!(
+    diagnostics.render("test.hd", source),
+    [
+      "test.hd:3:1: error: Variable a is already defined",
+      "  |",
+      "3 | a = \"y\";",
+      "  | ^",
+      "test.hd:1:1: note: a was first defined here",
+      "  |",
+      "1 | a = \"x\";",
+      "  | ^",
+    ]
+    .join("\n")
+  );
+}
+
+#[test]
+fn check_parse_error() {
+  let diagnostics = check_script("result = ", true);
+  assert_matches!(diagnostics.errors.as_slice(), [ExecutionError::Parser(_)]);
+}
+
+#[test]
+fn check_missing_result() {
+  assert_matches!(
+    check_script(r#"a = "x";"#, true).errors.as_slice(),
+    [ExecutionError::Compiler(CompilerError::MissingResult)]
+  );
+  assert!(check_script(r#"a = "x";"#, false).is_empty());
+}
+
+#[test]
+fn check_programs() {
+  for source in [
+    include_str!("../programs/menu.hd"),
+    include_str!("../programs/starwars.hd"),
+    include_str!("../programs/grön.hd"),
+  ] {
+    let diagnostics = check_script(source, true);
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+  }
+}
@@ -0,0 +1,241 @@
+use std::process::Command;
+
+fn haraldi(args: &[&str]) -> String {
+  let output = Command::new(env!("CARGO_BIN_EXE_haraldi"))
+    .args(args)
+    .output()
+    .expect("Failed to run haraldi");
+
+  assert!(
+    output.status.success(),
+    "haraldi failed: {}",
+    String::from_utf8_lossy(&output.stderr)
+  );
+
+  String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn cli_count() {
+  let output = haraldi(&["programs/menu.hd", "-n", "25"]);
+  assert_eq!(output.lines().count(), 25);
+}
+
+#[test]
+fn cli_seed_is_reproducible() {
+  let first = haraldi(&["programs/menu.hd", "--seed", "7", "-n", "5"]);
+  let second = haraldi(&["programs/menu.hd", "--seed", "7", "-n", "5"]);
+  assert_eq!(first, second);
+}
+
+#[test]
+fn cli_jobs_keep_seeded_outputs() {
+  let single = haraldi(&["programs/menu.hd", "--seed", "7", "-n", "3000"]);
+  let parallel = haraldi(&[
+    "programs/menu.hd",
+    "--seed",
+    "7",
+    "-n",
+    "3000",
+    "--jobs",
+    "4",
+  ]);
+  assert_eq!(single, parallel);
+  assert_eq!(single.lines().count(), 3000);
+}
+
+#[test]
+fn cli_no_optimise_matches_seeded_runs() {
+  let script = harald::compile_script(include_str!("../programs/menu.hd")).unwrap();
+  let output = haraldi(&[
+    "programs/menu.hd",
+    "--seed",
+    "7",
+    "-n",
+    "1",
+    "--no-optimise",
+  ]);
+
+  assert_eq!(output, format!("{}\n", script.run_with_seed(7).unwrap()));
+}
+
+#[test]
+fn cli_trace_keeps_seeded_outputs() {
+  let args = ["programs/menu.hd", "--seed", "7", "-n", "1500"];
+  let plain = haraldi(&args);
+  let parallel = haraldi(&[&args[..], &["--jobs", "3"]].concat());
+  let traced = haraldi(&[&args[..], &["--trace", "--format", "jsonl"]].concat());
+
+  let traced_outputs: Vec<String> = traced
+    .lines()
+    .map(|line| {
+      let trace: serde_json::Value = serde_json::from_str(line).unwrap();
+      trace["output"].as_str().unwrap().to_string()
+    })
+    .collect();
+
+  assert_eq!(plain, parallel);
+  assert_eq!(plain.lines().collect::<Vec<_>>(), traced_outputs);
+}
+
+#[test]
+fn cli_entry_and_unique() {
+  let output = haraldi(&[
+    "tests/library.hd",
+    "--entry",
+    "greeting",
+    "-n",
+    "100",
+    "--unique",
+  ]);
+
+  let mut lines: Vec<_> = output.lines().collect();
+  lines.sort();
+  assert_eq!(lines, vec!["Hello", "Hi"]);
+}
+
+#[test]
+fn cli_json() {
+  let output = haraldi(&["tests/hello_world.hd", "-n", "2", "--format", "json"]);
+  let parsed: Vec<String> = serde_json::from_str(&output).unwrap();
+  assert_eq!(parsed, vec!["Hello, world!", "Hello, world!"]);
+}
+
+#[test]
+fn cli_jsonl() {
+  let output = haraldi(&["tests/hello_world.hd", "-n", "3", "--format", "jsonl"]);
+
+  for line in output.lines() {
+    let parsed: String = serde_json::from_str(line).unwrap();
+    assert_eq!(parsed, "Hello, world!");
+  }
+
+  assert_eq!(output.lines().count(), 3);
+}
+
+#[test]
+fn cli_output_file() {
+  let path = std::env::temp_dir().join("haraldi_cli_output_file.txt");
+  let path_str = path.to_str().unwrap();
+
+  let stdout = haraldi(&["tests/hello_world.hd", "-n", "1", "--output", path_str]);
+  assert_eq!(stdout, "");
+
+  let written = std::fs::read_to_string(&path).unwrap();
+  assert_eq!(written, "Hello, world!\n");
+  std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn cli_failed_run_keeps_output_file() {
+  let dir = std::env::temp_dir().join("haraldi_cli_failed_run");
+  std::fs::create_dir_all(&dir).unwrap();
+  let script = dir.join("failing.hd");
+  let path = dir.join("out.txt");
+  std::fs::write(&script, r#"f(x) = f(x); result = bag [99 "a", f("b")];"#).unwrap();
+  std::fs::write(&path, "previous\n").unwrap();
+
+  let output = Command::new(env!("CARGO_BIN_EXE_haraldi"))
+    .args([script.to_str().unwrap(), "-n", "10000", "--seed", "1"])
+    .args(["--output", path.to_str().unwrap()])
+    .output()
+    .unwrap();
+  assert!(!output.status.success());
+
+  assert_eq!(std::fs::read_to_string(&path).unwrap(), "previous\n");
+  assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+  std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn cli_check() {
+  let status = Command::new(env!("CARGO_BIN_EXE_haraldi"))
+    .args([
+      "check",
+      "programs/menu.hd",
+      "programs/starwars.hd",
+      "programs/grön.hd",
+    ])
+    .status()
+    .unwrap();
+  assert!(status.success());
+
+  let output = Command::new(env!("CARGO_BIN_EXE_haraldi"))
+    .args(["check", "tests/check_problems.hd"])
+    .output()
+    .unwrap();
+  assert!(!output.status.success());
+
+  let stderr = String::from_utf8(output.stderr).unwrap();
+  assert!(
+    stderr.contains("tests/check_problems.hd:7:11: error: Variable undefinedThing is not defined")
+  );
+  assert!(stderr.contains("tests/check_problems.hd:1:10: warning:"));
+}
+
+#[test]
+fn cli_fmt() {
+  let path = std::env::temp_dir().join("haraldi_cli_fmt.hd");
+  std::fs::write(&path, "result=bag[\"a\",2 \"b\"];").unwrap();
+  let path_str = path.to_str().unwrap();
+
+  let status = Command::new(env!("CARGO_BIN_EXE_haraldi"))
+    .args(["fmt", "--check", path_str])
+    .status()
+    .unwrap();
+  assert!(!status.success());
+
+  haraldi(&["fmt", path_str]);
+  assert_eq!(
+    std::fs::read_to_string(&path).unwrap(),
+    "result = bag [\"a\", 2 \"b\"];\n"
+  );
+
+  haraldi(&["fmt", "--check", path_str]);
+  std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn cli_enumerate() {
+  let output = haraldi(&["enumerate", "tests/library.hd", "--entry", "greeting"]);
+  assert_eq!(output, "0.5\tHello\n0.5\tHi\n");
+}
+
+#[test]
+fn cli_stats() {
+  let output = haraldi(&["stats", "programs/starwars.hd"]);
+  assert!(output.contains("faction: 4 outputs, 2.00 bits of entropy\n"));
+  assert!(output.contains("  length: 4 to 7, 5.00 expected\n"));
+
+  let output = haraldi(&["stats", "tests/function_recursive.hd"]);
+  assert!(output.contains("result: infinite outputs (recursive: wrap() -> wrap())"));
+}
+
+#[test]
+fn cli_trace() {
+  let output = haraldi(&[
+    "tests/table_rows.hd",
+    "--trace",
+    "-n",
+    "1",
+    "--format",
+    "json",
+  ]);
+  let parsed: serde_json::Value = serde_json::from_str(&output).unwrap();
+  let choice = &parsed[0]["choices"][0];
+
+  assert_eq!(choice["variable"], "testTable");
+  assert_eq!(choice["kind"], "row");
+}
+
+#[test]
+fn cli_enumerate_limit() {
+  let output = Command::new(env!("CARGO_BIN_EXE_haraldi"))
+    .args(["enumerate", "programs/menu.hd", "--limit", "100"])
+    .output()
+    .unwrap();
+
+  assert!(!output.status.success());
+  assert!(String::from_utf8_lossy(&output.stderr).contains("more than 100 ways"));
+  assert!(output.stdout.is_empty());
+}
@@ -0,0 +1,59 @@
+use harald::{
+  compile_library, compile_script,
+  eval::{CompilerError, ExecutionError, InterpreterError},
+};
+use matches::assert_matches;
+
+#[test]
+fn missing_result_is_an_error() {
+  let output = compile_script(include_str!("./library.hd"));
+  assert_matches!(
+    output,
+    Err(ExecutionError::Compiler(CompilerError::MissingResult))
+  );
+}
+
+#[test]
+fn library_without_result() {
+  let script = compile_library(include_str!("./library.hd")).unwrap();
+
+  let formal = script.run_entry("formal").unwrap();
+  assert!(formal.ends_with("Harald.") || formal.ends_with("Paavo."));
+
+  let casual = script.run_entry("casual").unwrap();
+  assert!(casual == "hey Harald" || casual == "hey Paavo");
+}
+
+#[test]
+fn run_library_without_entry() {
+  let script = compile_library(include_str!("./library.hd")).unwrap();
+  assert_matches!(script.run(), Err(InterpreterError::UnknownEntry(name)) if name == "result");
+}
+
+#[test]
+fn run_unknown_entry() {
+  let script = compile_library(include_str!("./library.hd")).unwrap();
+  assert_matches!(
+    script.run_entry("doesNotExist"),
+    Err(InterpreterError::UnknownEntry(name)) if name == "doesNotExist"
+  );
+}
+
+#[test]
+fn bag_entry_is_sampled() {
+  let script = compile_library(include_str!("./library.hd")).unwrap();
+
+  for _ in 0..10 {
+    let greeting = script.run_entry("greeting").unwrap();
+    assert!(greeting == "Hello" || greeting == "Hi");
+  }
+}
+
+#[test]
+fn library_is_still_checked() {
+  let output = compile_library("a = b;");
+  assert_matches!(
+    output,
+    Err(ExecutionError::Compiler(CompilerError::UndefinedVariable { name, .. })) if name == "b"
+  );
+}
@@ -0,0 +1,57 @@
+use harald::{compile_script, enumerate::EnumerationError};
+
+#[test]
+fn enumerate_rows_keeps_columns_together() {
+  let script = compile_script(include_str!("./table_rows.hd")).unwrap();
+  let distribution = script.distribution("result").unwrap();
+
+  let mut outputs: Vec<_> = distribution
+    .iter()
+    .map(|outcome| outcome.text.as_str())
+    .collect();
+  outputs.sort();
+  assert_eq!(outputs, vec!["aA", "bbB", "c"]);
+
+  for outcome in &distribution {
+    assert!((outcome.probability - 1.0 / 3.0).abs() < 1e-12);
+  }
+}
+
+#[test]
+fn derivations_are_lazy() {
+  let script = compile_script(include_str!("../programs/menu.hd")).unwrap();
+  let outcomes: Vec<_> = script
+    .derivations("result")
+    .unwrap()
+    .take(1000)
+    .collect::<Result<_, _>>()
+    .unwrap();
+
+  assert_eq!(outcomes.len(), 1000);
+  assert!(outcomes
+    .iter()
+    .all(|outcome| outcome.probability > 0.0 && outcome.probability <= 1.0));
+}
+
+#[test]
+fn enumerate_probabilities_add_up_to_one() {
+  let script = compile_script(include_str!("../programs/starwars.hd")).unwrap();
+  let total: f64 = script
+    .distribution("result")
+    .unwrap()
+    .iter()
+    .map(|outcome| outcome.probability)
+    .sum();
+
+  assert!((total - 1.0).abs() < 1e-9);
+}
+
+#[test]
+fn enumerate_refuses_recursive_function() {
+  let script = compile_script(include_str!("./function_recursive.hd")).unwrap();
+
+  match script.derivations("result") {
+    Err(EnumerationError::Recursive { cycle }) => assert_eq!(cycle, vec!["wrap()", "wrap()"]),
+    _ => panic!("expected recursion to be refused"),
+  }
+}
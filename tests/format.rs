@@ -0,0 +1,59 @@
+use std::{fs, path::PathBuf};
+
+use harald::{
+  format::{comments, format_source},
+  parser::{parse_script, without_spans},
+};
+
+fn scripts() -> Vec<PathBuf> {
+  let mut scripts = Vec::new();
+
+  for directory in ["programs", "tests", "tests/imports"] {
+    let directory = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(directory);
+
+    for entry in fs::read_dir(directory).unwrap() {
+      let path = entry.unwrap().path();
+
+      if path.extension().is_some_and(|extension| extension == "hd") {
+        scripts.push(path);
+      }
+    }
+  }
+
+  scripts.sort();
+  scripts
+}
+
+#[test]
+fn format_round_trip() {
+  let scripts = scripts();
+  assert!(scripts.len() > 10);
+
+  for path in scripts {
+    let source = fs::read_to_string(&path).unwrap();
+    let formatted = format_source(&source).unwrap();
+
+    let original = parse_script(&source).unwrap();
+    let reparsed = parse_script(&formatted)
+      .unwrap_or_else(|err| panic!("{} formatted into invalid code: {}", path.display(), err));
+
+    assert_eq!(
+      without_spans(original),
+      without_spans(reparsed),
+      "{} changed when formatted",
+      path.display()
+    );
+    assert_eq!(
+      comments(&source),
+      comments(&formatted),
+      "{} lost comments when formatted",
+      path.display()
+    );
+    assert_eq!(
+      format_source(&formatted).unwrap(),
+      formatted,
+      "formatting {} is not idempotent",
+      path.display()
+    );
+  }
+}
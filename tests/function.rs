@@ -0,0 +1,48 @@
+use harald::{
+  compile_script,
+  eval::{CompilerError, ExecutionError},
+  run_script,
+};
+
+use matches::assert_matches;
+
+#[test]
+fn function_call() {
+  let output = run_script(include_str!("./function.hd")).unwrap();
+  assert_eq!(output, "Peruna ja kastike");
+}
+
+#[test]
+fn function_recursive() {
+  let script = compile_script(include_str!("./function_recursive.hd")).unwrap();
+
+  for _ in 0..100 {
+    let output = script.run().unwrap();
+    let depth = output.chars().take_while(|&ch| ch == '(').count();
+    let expected = format!("{}x{}", "(".repeat(depth), ")".repeat(depth));
+    assert_eq!(output, expected);
+  }
+}
+
+#[test]
+fn function_wrong_number_of_arguments() {
+  let output = run_script(include_str!("./function_arity.hd"));
+  assert_matches!(
+    output,
+    Err(ExecutionError::Compiler(CompilerError::WrongNumberOfArguments {
+      name,
+      expected: 2,
+      was: 1,
+      ..
+    })) if name == "pair"
+  );
+}
+
+#[test]
+fn function_unknown() {
+  let output = run_script(include_str!("./function_unknown.hd"));
+  assert_matches!(
+    output,
+    Err(ExecutionError::Compiler(CompilerError::UnknownFunction { name, .. })) if name == "doesNotExist"
+  );
+}
@@ -0,0 +1,219 @@
+use std::path::Path;
+
+use harald::{
+  compile_script,
+  eval::{CompilerError, ExecutionError},
+  loader::{ImportError, Loader},
+};
+
+fn outputs(path: &str, runs: u64) -> Vec<String> {
+  let script = Loader::new().compile_file(path).unwrap();
+  (0..runs)
+    .map(|seed| script.run_with_seed(seed).unwrap())
+    .collect()
+}
+
+#[test]
+fn namespaced_import() {
+  for output in outputs("tests/imports/namespaced.hd", 20) {
+    let (greeting, rest) = output.split_once(' ').unwrap();
+    assert_eq!(greeting, "Hei");
+
+    let words: Vec<_> = rest.split(' ').collect();
+    assert!(["Aino", "Eino"].contains(&words[0]), "{output}");
+    assert!(["tohtori", "professori"].contains(&words[1]), "{output}");
+    assert!(["AA", "AE", "EA", "EE"].contains(&words[2]), "{output}");
+  }
+}
+
+#[test]
+fn unaliased_import() {
+  for output in outputs("tests/imports/flat.hd", 20) {
+    let (greeting, title) = output.rsplit_once(' ').unwrap();
+    assert!(["Hei Ainon", "Hei Einon"].contains(&greeting), "{output}");
+    assert!(["tohtori", "professori"].contains(&title), "{output}");
+  }
+}
+
+#[test]
+fn redefining_imported_variable() {
+  let mut loader = Loader::new();
+  let error = loader
+    .compile_file("tests/imports/redefined.hd")
+    .unwrap_err();
+
+  assert!(matches!(
+    error,
+    ExecutionError::Compiler(CompilerError::RedefinedVariable { .. })
+  ));
+  let rendered = loader.render(&error);
+  assert!(
+    rendered.starts_with(
+      "tests/imports/redefined.hd:3:1: Compilation error: Variable title is already defined"
+    ),
+    "{rendered}"
+  );
+  assert!(
+    rendered.contains("tests/imports/common/names.hd:10:1: Note: title was first defined here"),
+    "{rendered}"
+  );
+}
+
+#[test]
+fn private_definitions_dont_collide() {
+  for output in outputs("tests/imports/private.hd", 20) {
+    assert!(
+      ["(rouva Aino) [neiti]", "(herra Aino) [neiti]"].contains(&output.as_str()),
+      "{output}"
+    );
+  }
+}
+
+#[test]
+fn private_definitions_are_hidden() {
+  let error = Loader::new()
+    .compile_file("tests/imports/private_access.hd")
+    .unwrap_err();
+
+  let ExecutionError::Compiler(CompilerError::Multiple(problems)) = &error else {
+    panic!("expected two errors, got {error:?}");
+  };
+  assert!(matches!(
+    &problems[..],
+    [
+      CompilerError::UndefinedVariable { name, .. },
+      CompilerError::UnknownFunction { name: function, .. },
+    ] if name == "names.honorific" && function == "names.wrap"
+  ));
+}
+
+#[test]
+fn locals_shadow_namespaces() {
+  assert_eq!(outputs("tests/imports/shadowed.hd", 5), vec!["Ville"; 5]);
+}
+
+#[test]
+fn files_are_loaded_once() {
+  let mut loader = Loader::new();
+  let script = loader.compile_file("tests/imports/diamond.hd").unwrap();
+  script.run_with_seed(0).unwrap();
+
+  let paths: Vec<_> = loader
+    .files()
+    .iter()
+    .map(|file| file.path.clone())
+    .collect();
+  assert_eq!(
+    paths,
+    vec![
+      Path::new("tests/imports/diamond.hd"),
+      Path::new("tests/imports/common/names.hd"),
+      Path::new("tests/imports/common/letters.hd"),
+      Path::new("tests/imports/flat_again.hd"),
+    ]
+  );
+}
+
+#[test]
+fn import_cycle() {
+  let mut loader = Loader::new();
+  let error = loader.compile_file("tests/imports/cycle_a.hd").unwrap_err();
+
+  let ExecutionError::Import(ImportError::Cycle { cycle, .. }) = &error else {
+    panic!("expected an import cycle, got {error:?}");
+  };
+  assert_eq!(
+    cycle,
+    &vec![
+      Path::new("tests/imports/cycle_a.hd"),
+      Path::new("tests/imports/cycle_b.hd"),
+      Path::new("tests/imports/cycle_a.hd"),
+    ]
+  );
+
+  let rendered = loader.render(&error);
+  assert!(
+    rendered.starts_with("tests/imports/cycle_b.hd:1:1:"),
+    "{rendered}"
+  );
+  assert!(rendered.contains("import \"cycle_a.hd\";"), "{rendered}");
+}
+
+#[test]
+fn missing_import() {
+  let mut loader = Loader::new();
+  let error = loader.compile_file("tests/imports/missing.hd").unwrap_err();
+
+  assert!(matches!(
+    error,
+    ExecutionError::Import(ImportError::Unreadable { span: Some(_), .. })
+  ));
+  let rendered = loader.render(&error);
+  assert!(
+    rendered.starts_with("tests/imports/missing.hd:1:1:"),
+    "{rendered}"
+  );
+  assert!(
+    rendered.contains("tests/imports/common/nope.hd"),
+    "{rendered}"
+  );
+}
+
+#[test]
+fn missing_script() {
+  let error = Loader::new()
+    .compile_file("tests/imports/nope.hd")
+    .unwrap_err();
+  assert!(matches!(
+    error,
+    ExecutionError::Import(ImportError::Unreadable { span: None, .. })
+  ));
+}
+
+#[test]
+fn errors_point_at_imported_file() {
+  let mut loader = Loader::new();
+  let error = loader.compile_file("tests/imports/broken.hd").unwrap_err();
+
+  let rendered = loader.render(&error);
+  assert!(
+    rendered.starts_with("tests/imports/common/broken.hd:2:19:"),
+    "{rendered}"
+  );
+  assert!(
+    rendered.contains("broken = bag [\"a\" \"b\"];"),
+    "{rendered}"
+  );
+}
+
+#[test]
+fn checks_imported_files() {
+  let mut loader = Loader::new();
+  assert!(loader
+    .check_file("tests/imports/namespaced.hd", true)
+    .is_empty());
+
+  let mut loader = Loader::new();
+  let diagnostics = loader.check_file("tests/imports/cycle_a.hd", true);
+  assert_eq!(diagnostics.errors.len(), 1);
+}
+
+#[test]
+fn sources_import_relative_to_directory() {
+  let source = r#"import "common/letters.hd" as letters; result = letters.vowel;"#;
+  let script = Loader::new()
+    .compile_source(source, Path::new("tests/imports"), true)
+    .unwrap();
+  assert!(["A", "E"].contains(&script.run_with_seed(0).unwrap().as_str()));
+
+  let script =
+    compile_script(r#"import "tests/imports/common/letters.hd"; result = vowel;"#).unwrap();
+  assert!(["A", "E"].contains(&script.run_with_seed(0).unwrap().as_str()));
+}
+
+#[test]
+fn imports_need_a_loader() {
+  let statements = harald::parser::parse_script(r#"import "names.hd"; result = "x";"#).unwrap();
+  let error = harald::eval::compile_script(statements).unwrap_err();
+  assert!(matches!(error, CompilerError::UnresolvedImport { .. }));
+}
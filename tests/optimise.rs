@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use harald::{compile_script, enumerate::Outcome, eval::CompiledScript};
+use rand::{rngs::StdRng, SeedableRng};
+
+fn assert_same_distribution(original: &CompiledScript, optimised: &CompiledScript) {
+  let original = original.distribution("result").unwrap();
+  let optimised = optimised.distribution("result").unwrap();
+
+  assert_eq!(original.len(), optimised.len());
+
+  for Outcome { text, probability } in original {
+    let optimised = optimised
+      .iter()
+      .find(|outcome| outcome.text == text)
+      .unwrap_or_else(|| panic!("{text:?} is missing from the optimised script"));
+
+    assert!((optimised.probability - probability).abs() < 1e-6);
+  }
+}
+
+#[test]
+fn optimising_keeps_distributions() {
+  for source in [
+    include_str!("../programs/starwars.hd"),
+    include_str!("./hello_pattern.hd"),
+    include_str!("./function.hd"),
+    include_str!("./sticky.hd"),
+    include_str!("./sticky_with.hd"),
+    include_str!("./table_holes.hd"),
+    include_str!("./table_rows.hd"),
+    include_str!("./table_weight.hd"),
+    include_str!("./text_functions.hd"),
+  ] {
+    let original = compile_script(source).unwrap();
+    let mut optimised = compile_script(source).unwrap();
+    optimised.optimise();
+
+    assert_same_distribution(&original, &optimised);
+  }
+}
+
+/// Checks that seeded batches of the optimised script produce every output about as often as the
+/// original script would, and the same outputs for the same seed.
+fn assert_seeded_batches_keep_distribution(source: &str) {
+  let n = 20_000;
+  let original = compile_script(source).unwrap();
+  let mut optimised = compile_script(source).unwrap();
+  optimised.optimise();
+
+  let outputs = optimised.generate_batch_with_seed(n, 2, 11).unwrap();
+  assert_eq!(
+    outputs,
+    optimised.generate_batch_with_seed(n, 1, 11).unwrap()
+  );
+
+  let mut counts = HashMap::new();
+
+  for output in &outputs {
+    *counts.entry(output.as_str()).or_insert(0) += 1;
+  }
+
+  let distribution = original.distribution("result").unwrap();
+  assert_eq!(counts.len(), distribution.len());
+
+  for Outcome { text, probability } in distribution {
+    let frequency = f64::from(counts.get(text.as_str()).copied().unwrap_or(0)) / n as f64;
+
+    assert!(
+      (frequency - probability).abs() < 0.015,
+      "{text:?} has probability {probability} but was generated {frequency} of the time"
+    );
+  }
+}
+
+#[test]
+fn optimised_seeded_batches_keep_distributions() {
+  for source in [
+    r#"result = { bag [ 2 bag ["a", 3 "b"], "c" ] bag ["1", bag ["2", 0 "x", "3"]] };"#,
+    r#"name = bag ["Ada", "Bo"]; alias = name; result = { alias " & " alias };"#,
+    include_str!("./sticky.hd"),
+    include_str!("./table_weight.hd"),
+  ] {
+    assert_seeded_batches_keep_distribution(source);
+  }
+}
+
+#[test]
+fn optimised_menu_keeps_statistics() {
+  let source = include_str!("../programs/menu.hd");
+  let original = compile_script(source).unwrap();
+  let mut optimised = compile_script(source).unwrap();
+  optimised.optimise();
+
+  for ((name, original), (_, optimised)) in original.stats().into_iter().zip(optimised.stats()) {
+    assert_eq!(
+      format!("{original:.6?}"),
+      format!("{optimised:.6?}"),
+      "statistics of {name} changed"
+    );
+  }
+}
+
+#[test]
+fn optimised_scripts_run_on_the_vm() {
+  let mut script = compile_script(include_str!("../programs/menu.hd")).unwrap();
+  script.optimise();
+  let program = script.lower();
+
+  let mut walker_rng = StdRng::seed_from_u64(3);
+  let mut vm_rng = StdRng::seed_from_u64(3);
+
+  for _ in 0..200 {
+    assert_eq!(
+      script.run_with_rng(&mut walker_rng).unwrap(),
+      program.run_with_rng(&mut vm_rng).unwrap()
+    );
+  }
+}
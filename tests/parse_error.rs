@@ -0,0 +1,80 @@
+use harald::{compile_script, eval::ExecutionError, parser::ParseError, source::Position};
+
+fn parse_error(source: &str) -> ParseError {
+  match compile_script(source) {
+    Err(ExecutionError::Parser(err)) => err,
+    otherwise => panic!("Expected a parse error, got {:?}", otherwise),
+  }
+}
+
+#[test]
+fn parse_error_position() {
+  let err = parse_error("a = \"x\";\nresult = bag [\"a\" \"b\"];\n");
+
+  assert_eq!(err.offset, 27);
+  assert_eq!(
+    err.position,
+    Position {
+      line: 2,
+      column: 19
+    }
+  );
+  assert_eq!(err.expected, "']'");
+  assert_eq!(err.context.first(), Some(&"bag"));
+  assert_eq!(
+    err.snippet,
+    "  |\n2 | result = bag [\"a\" \"b\"];\n  |                   ^"
+  );
+}
+
+#[test]
+fn parse_error_missing_semicolon() {
+  let err = parse_error("a = \"x\"\nresult = a;");
+
+  assert_eq!(err.position, Position { line: 2, column: 1 });
+  assert_eq!(err.expected, "';'");
+  assert_eq!(err.context.first(), Some(&"statement"));
+}
+
+#[test]
+fn parse_error_table_row() {
+  let err = parse_error("result = table [\n  [.a, .b],\n  [\"x\" \"y\"]\n];");
+
+  assert_eq!(err.position, Position { line: 3, column: 8 });
+  assert_eq!(err.expected, "']'");
+  assert_eq!(err.context.first(), Some(&"table row"));
+}
+
+#[test]
+fn parse_error_unterminated_block_comment() {
+  let err = parse_error("result = \"a\"; /* never closed");
+
+  assert_eq!(
+    err.position,
+    Position {
+      line: 1,
+      column: 17
+    }
+  );
+  assert_eq!(err.expected, "end of block comment");
+}
+
+#[test]
+fn parse_error_display() {
+  let err = parse_error("result = capitalise(\"a\";");
+
+  assert_eq!(
+    err.to_string(),
+    "1:24: expected ')' in function call\n  |\n1 | result = capitalise(\"a\";\n  |                        ^"
+  );
+}
+
+#[test]
+fn parse_error_never_panics() {
+  // Every prefix of a valid program must produce either a script or an error
+  let source = include_str!("../programs/menu.hd");
+
+  for (i, _) in source.char_indices().step_by(41) {
+    let _ = compile_script(&source[..i]);
+  }
+}
@@ -0,0 +1,52 @@
+use harald::compile_script;
+use rand::{rngs::StdRng, SeedableRng};
+
+#[test]
+fn same_seed_same_output() {
+  let script = compile_script(include_str!("../programs/menu.hd")).unwrap();
+
+  for seed in 0..100 {
+    assert_eq!(
+      script.run_with_seed(seed).unwrap(),
+      script.run_with_seed(seed).unwrap()
+    );
+  }
+}
+
+#[test]
+fn same_rng_same_sequence() {
+  let script = compile_script(include_str!("../programs/menu.hd")).unwrap();
+
+  let mut first = StdRng::seed_from_u64(1234);
+  let mut second = StdRng::seed_from_u64(1234);
+
+  for _ in 0..100 {
+    assert_eq!(
+      script.run_with_rng(&mut first).unwrap(),
+      script.run_with_rng(&mut second).unwrap()
+    );
+  }
+}
+
+#[test]
+fn different_seeds_vary() {
+  let script = compile_script(include_str!("../programs/menu.hd")).unwrap();
+
+  let outputs: std::collections::HashSet<_> = (0..20)
+    .map(|seed| script.run_with_seed(seed).unwrap())
+    .collect();
+
+  assert!(outputs.len() > 1);
+}
+
+#[test]
+fn sticky_variables_are_seeded() {
+  let script = compile_script(include_str!("./sticky.hd")).unwrap();
+
+  for seed in 0..20 {
+    assert_eq!(
+      script.run_with_seed(seed).unwrap(),
+      script.run_with_seed(seed).unwrap()
+    );
+  }
+}
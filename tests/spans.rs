@@ -0,0 +1,88 @@
+use harald::{compile_script, eval::ExecutionError, run_script, source::Position};
+
+fn error_position(source: &str, err: &ExecutionError) -> Position {
+  let span = err.span().expect("Expected the error to have a span");
+  Position::from_offset(source, span.start)
+}
+
+#[test]
+fn span_invalid_table_row() {
+  let source = include_str!("./span_table_row.hd");
+  let err = compile_script(source).unwrap_err();
+
+  assert_eq!(
+    error_position(source, &err),
+    Position { line: 4, column: 5 }
+  );
+}
+
+#[test]
+fn span_unknown_variable() {
+  let source = "noun = bag [\"cat\", \"dog\"];\nresult = { \"a \" nuon };";
+  let err = compile_script(source).unwrap_err();
+
+  assert_eq!(
+    error_position(source, &err),
+    Position {
+      line: 2,
+      column: 17
+    }
+  );
+  assert_eq!(
+    err.render("test.hd", source),
+    "test.hd:2:17: Compilation error: Variable nuon is not defined\n  |\n2 | result = { \"a \" nuon };\n  |                 ^"
+  );
+}
+
+#[test]
+fn span_unknown_variable_in_other_assignment() {
+  let source = "greeting = { \"Hello \" name };\n\nresult = greeting;";
+  let err = compile_script(source).unwrap_err();
+
+  assert_eq!(
+    error_position(source, &err),
+    Position {
+      line: 1,
+      column: 23
+    }
+  );
+}
+
+#[test]
+fn span_coercion_in_pattern() {
+  let source = "animals = table [\n  [.name],\n  [\"cat\"]\n];\nresult = { \"a \" animals };";
+  let err = run_script(source).unwrap_err();
+
+  assert_eq!(
+    error_position(source, &err),
+    Position {
+      line: 5,
+      column: 17
+    }
+  );
+}
+
+#[test]
+fn span_unknown_function() {
+  let source = "a = \"x\";\nresult = { a doesNotExist(a) };";
+  let err = compile_script(source).unwrap_err();
+
+  assert_eq!(
+    error_position(source, &err),
+    Position {
+      line: 2,
+      column: 14
+    }
+  );
+}
+
+#[test]
+fn span_parse_error_render() {
+  let source = "result = bag [\"a\" \"b\"];";
+  let err = compile_script(source).unwrap_err();
+
+  assert_eq!(
+    err.render("test.hd", source),
+    "test.hd:1:19: Parse error: expected ']' in bag\n  |\n1 | result = bag [\"a\" \"b\"];\n  |                   ^"
+  );
+}
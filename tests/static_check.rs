@@ -0,0 +1,157 @@
+use harald::{
+  compile_script,
+  eval::{CompilerError, ExecutionError},
+};
+use matches::assert_matches;
+
+fn compiler_error(source: &str) -> CompilerError {
+  match compile_script(source) {
+    Err(ExecutionError::Compiler(err)) => err,
+    otherwise => panic!("Expected a compiler error, got {:?}", otherwise.err()),
+  }
+}
+
+#[test]
+fn undefined_variable_in_rare_branch() {
+  let err = compiler_error(r#"result = bag [100.0 "common", 0.01 { "rare " typo }];"#);
+
+  assert_matches!(err, CompilerError::UndefinedVariable { name, .. } if name == "typo");
+}
+
+#[test]
+fn undefined_variables_are_all_reported() {
+  let err = compiler_error(
+    r#"
+    a = { first };
+    b = { "x" second };
+    result = { a b third };
+    "#,
+  );
+
+  match err {
+    CompilerError::Multiple(problems) => {
+      let names: Vec<_> = problems
+        .iter()
+        .map(|problem| match problem {
+          CompilerError::UndefinedVariable { name, .. } => name.as_str(),
+          otherwise => panic!("Unexpected problem {:?}", otherwise),
+        })
+        .collect();
+
+      // Problems are reported in source order
+      assert_eq!(names, vec!["first", "second", "third"]);
+    }
+    otherwise => panic!("Expected multiple problems, got {:?}", otherwise),
+  }
+}
+
+#[test]
+fn local_variables_are_defined() {
+  let script = compile_script(
+    r#"
+    wrap(x) = { "(" x ")" };
+    result = with a = "a", b = { a "b" } in wrap(b);
+    "#,
+  );
+
+  assert_eq!(script.unwrap().run().unwrap(), "(ab)");
+}
+
+#[test]
+fn with_binding_not_visible_outside() {
+  let err = compiler_error(r#"result = { with a = "a" in a  a };"#);
+  assert_matches!(err, CompilerError::UndefinedVariable { name, .. } if name == "a");
+}
+
+#[test]
+fn parameter_not_visible_outside_function() {
+  let err = compiler_error(r#"wrap(x) = x; result = { wrap("a") x };"#);
+  assert_matches!(err, CompilerError::UndefinedVariable { name, .. } if name == "x");
+}
+
+#[test]
+fn unknown_column_on_table_variable() {
+  let err = compiler_error(
+    r#"
+    noun = table [
+      [.base, .plural],
+      ["cat", +"s"]
+    ];
+    result = noun.plurl;
+    "#,
+  );
+
+  assert_matches!(
+    err,
+    CompilerError::UnknownColumn { column, columns, .. }
+      if column == "plurl" && columns == vec!["base", "plural"]
+  );
+}
+
+#[test]
+fn unknown_column_on_row() {
+  let err = compiler_error(
+    r#"
+    noun = table [
+      [.base, .plural],
+      ["cat", +"s"]
+    ];
+    result = with r = row(noun) in { r.base r.bsae };
+    "#,
+  );
+
+  assert_matches!(err, CompilerError::UnknownColumn { column, .. } if column == "bsae");
+}
+
+#[test]
+fn known_columns_are_accepted() {
+  let script = compile_script(
+    r#"
+    noun = table [
+      [.base, .plural],
+      ["cat", +"s"]
+    ];
+    sticky n = noun;
+    result = with r = row(n) in { r.base " " r.plural " " noun.base };
+    "#,
+  );
+
+  assert_eq!(script.unwrap().run().unwrap(), "cat cats cat");
+}
+
+#[test]
+fn self_referential_variable_terminates() {
+  let err = compiler_error("a = b; b = a; result = a.x; c = d;");
+  assert_matches!(err, CompilerError::UndefinedVariable { name, .. } if name == "d");
+}
+
+#[test]
+fn redefined_variable() {
+  let source = r#"sauce = "kastike";
+result = { sauce };
+sauce = "liemi";"#;
+  let err = compiler_error(source);
+
+  assert_matches!(
+    &err,
+    CompilerError::RedefinedVariable { name, span, previous }
+      if name == "sauce" && previous.start == 0 && span.start == 39
+  );
+
+  let rendered = ExecutionError::from(err).render("menu.hd", source);
+  assert!(rendered.contains("menu.hd:3:1: Compilation error: Variable sauce is already defined"));
+  assert!(rendered.contains("menu.hd:1:1: Note: sauce was first defined here"));
+}
+
+#[test]
+fn redefined_function() {
+  let err = compiler_error(r#"f(x) = x; f(y) = y; result = f("a");"#);
+
+  assert_matches!(err, CompilerError::RedefinedFunction { name, .. } if name == "f");
+}
+
+#[test]
+fn variable_and_function_may_share_a_name() {
+  let script = compile_script(r#"sauce = "liemi"; sauce(x) = { x sauce }; result = sauce("a");"#);
+  assert_eq!(script.unwrap().run().unwrap(), "aliemi");
+}
@@ -0,0 +1,35 @@
+use std::collections::HashSet;
+
+use harald::compile_script;
+
+#[test]
+fn sticky_variable_is_reused_within_a_run() {
+  let script = compile_script(include_str!("./sticky.hd")).unwrap();
+  let mut heroes = HashSet::new();
+
+  for _ in 0..100 {
+    let output = script.run().unwrap();
+    let (first, rest) = output.split_once(" met ").unwrap();
+    let (_, second) = rest.split_once(". ").unwrap();
+    assert_eq!(second, format!("{} won.", first));
+    heroes.insert(first.to_string());
+  }
+
+  // Each run samples the variable again
+  assert!(heroes.len() > 1);
+}
+
+#[test]
+fn sticky_local_variable() {
+  let script = compile_script(include_str!("./sticky_with.hd")).unwrap();
+  let mut villains = HashSet::new();
+
+  for _ in 0..100 {
+    let output = script.run().unwrap();
+    let (first, second) = output.split_once(" and ").unwrap();
+    assert_eq!(first, second);
+    villains.insert(first.to_string());
+  }
+
+  assert!(villains.len() > 1);
+}
@@ -0,0 +1,116 @@
+use std::fmt;
+
+use harald::{compile_script, eval::InterpreterError};
+use rand::{rngs::StdRng, SeedableRng};
+
+fn assert_streams_same_outputs(source: &str, runs: u64) {
+  let script = compile_script(source).unwrap();
+  let program = script.lower();
+
+  for seed in 0..runs {
+    let expected = script.run_with_seed(seed);
+
+    let mut streamed = String::new();
+    let result =
+      script.run_entry_into_with_rng("result", &mut StdRng::seed_from_u64(seed), &mut streamed);
+
+    let mut vm_streamed = String::new();
+    let vm_result = program.run_into(&mut StdRng::seed_from_u64(seed), &mut vm_streamed);
+
+    match expected {
+      Ok(expected) => {
+        result.unwrap();
+        vm_result.unwrap();
+        assert_eq!(expected, streamed);
+        assert_eq!(expected, vm_streamed);
+      }
+      Err(expected) => {
+        assert_eq!(
+          format!("{expected:?}"),
+          format!("{:?}", result.unwrap_err())
+        );
+        assert_eq!(
+          format!("{expected:?}"),
+          format!("{:?}", vm_result.unwrap_err())
+        );
+      }
+    }
+  }
+}
+
+#[test]
+fn streamed_output_matches_programs() {
+  for source in [
+    include_str!("../programs/menu.hd"),
+    include_str!("../programs/grön.hd"),
+    include_str!("../programs/starwars.hd"),
+  ] {
+    assert_streams_same_outputs(source, 200);
+  }
+}
+
+#[test]
+fn streamed_output_matches_fixtures() {
+  for source in [
+    include_str!("./hello_pattern.hd"),
+    include_str!("./function.hd"),
+    include_str!("./sticky.hd"),
+    include_str!("./sticky_with.hd"),
+    include_str!("./table_holes.hd"),
+    include_str!("./table_rows.hd"),
+    include_str!("./text_functions.hd"),
+    r#"names = table [ [.name], ["Ada"] ]; show(t) = { "a" t }; result = show(names);"#,
+  ] {
+    assert_streams_same_outputs(source, 50);
+  }
+}
+
+#[test]
+fn streamed_errors_match_run_entry() {
+  for source in [
+    r#"result = table [ [.a], ["x"] ];"#,
+    r#"result = { "a" repeat("b", "many") };"#,
+    r#"loop(x) = { x loop(x) }; result = loop("a");"#,
+    r#"names = table [ [.name], ["Ada"] ]; result = { "a" names };"#,
+    r#"names = table [ [.name], ["Ada"] ]; either = bag [names]; result = { "a" either };"#,
+  ] {
+    let script = compile_script(source).unwrap();
+
+    let expected = script.run_entry("result").expect_err("the script fails");
+    let streamed = script
+      .run_into(&mut String::new())
+      .expect_err("the script fails");
+
+    assert!(expected.span().is_some(), "{source}: {expected:?}");
+    assert_eq!(expected.span(), streamed.span(), "{source}");
+    assert_eq!(format!("{expected:?}"), format!("{streamed:?}"), "{source}");
+  }
+}
+
+/// A sink which accepts the given number of writes and then fails.
+struct FailingSink(usize);
+
+impl fmt::Write for FailingSink {
+  fn write_str(&mut self, _: &str) -> fmt::Result {
+    match self.0 {
+      0 => Err(fmt::Error),
+      _ => {
+        self.0 -= 1;
+        Ok(())
+      }
+    }
+  }
+}
+
+#[test]
+fn sink_errors_are_reported() {
+  let script = compile_script(r#"result = { "a" bag ["b", "c"] "d" };"#).unwrap();
+
+  let result = script.run_into(&mut FailingSink(0));
+  assert!(matches!(result, Err(InterpreterError::Write(_))));
+
+  let result = script
+    .lower()
+    .run_into(&mut rand::thread_rng(), &mut FailingSink(0));
+  assert!(matches!(result, Err(InterpreterError::Write(_))));
+}
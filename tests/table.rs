@@ -1,4 +1,5 @@
 use harald::{
+  compile_script,
   eval::{CompilerError, ExecutionError},
   run_script,
 };
@@ -30,7 +31,22 @@ fn empty_table() {
     output,
     Err(ExecutionError::Compiler(CompilerError::EmptyTableColumn {
       column_name: column,
-      in_variable: variable
+      in_variable: variable,
+      ..
     })) if column == "hasNoEntries" && variable == "testTable"
   );
 }
+
+#[test]
+fn table_row_columns_agree() {
+  let script = compile_script(include_str!("./table_rows.hd")).unwrap();
+
+  for _ in 0..100 {
+    let output = script.run().unwrap();
+    assert!(
+      ["aA", "bbB", "c"].contains(&output.as_str()),
+      "unexpected output {:?}",
+      output
+    );
+  }
+}
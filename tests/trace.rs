@@ -0,0 +1,56 @@
+use harald::{compile_script, trace::ChoiceKind};
+
+#[test]
+fn trace_records_nested_choices() {
+  let script = compile_script(
+    r#"
+    name = bag [ "Harald", 3 "Helga" ];
+    result = bag [ { "Hello " name }, 0 "never" ];
+    "#,
+  )
+  .unwrap();
+
+  let trace = script.run_traced().unwrap();
+  assert!(trace.output.starts_with("Hello "));
+
+  let [choice] = trace.choices.as_slice() else {
+    panic!("expected one choice, got {:?}", trace.choices);
+  };
+  assert_eq!(choice.variable, "result");
+  assert_eq!((choice.index, choice.weight), (0, 1.0));
+  assert_eq!(choice.text, trace.output);
+
+  let [name] = choice.children.as_slice() else {
+    panic!("expected one nested choice, got {:?}", choice.children);
+  };
+  assert_eq!(name.variable, "name");
+  assert_eq!(name.weight, [1.0, 3.0][name.index]);
+  assert_eq!(format!("Hello {}", name.text), trace.output);
+}
+
+#[test]
+fn trace_records_rows_and_columns() {
+  let script = compile_script(
+    r#"
+    animals = table [ [.singular, .plural], ["dog", +"s"] ];
+    result = { with animal = row(animals) in animal.plural " and " animals.singular };
+    "#,
+  )
+  .unwrap();
+
+  let trace = script.run_traced().unwrap();
+  assert_eq!(trace.output, "dogs and dog");
+
+  let [row, column] = trace.choices.as_slice() else {
+    panic!("expected two choices, got {:?}", trace.choices);
+  };
+  assert_eq!(row.kind, ChoiceKind::Row);
+  assert_eq!(row.variable, "animals");
+  assert_eq!(column.column.as_deref(), Some("singular"));
+  assert_eq!(column.text, "dog");
+
+  assert_eq!(
+    trace.to_string(),
+    "dogs and dog\n  animals row 0 (1)\n  animals.singular[0] (1) \"dog\""
+  );
+}
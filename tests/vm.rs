@@ -0,0 +1,123 @@
+use harald::{compile_library, compile_script, eval::CompiledScript};
+use rand::{rngs::StdRng, SeedableRng};
+
+/// Runs both the tree-walking interpreter and the VM with the same seeds, and checks that they
+/// produce the same outputs and errors.
+fn assert_same_outputs(script: &CompiledScript, entry: &str, runs: u64) {
+  let program = script.lower();
+
+  let mut walker_rng = StdRng::seed_from_u64(42);
+  let mut vm_rng = StdRng::seed_from_u64(42);
+
+  for _ in 0..runs {
+    let expected = script.run_entry_with_rng(entry, &mut walker_rng);
+    let actual = program.run_entry_with_rng(entry, &mut vm_rng);
+
+    match (expected, actual) {
+      (Ok(expected), Ok(actual)) => assert_eq!(expected, actual),
+      (Err(expected), Err(actual)) => assert_eq!(format!("{expected:?}"), format!("{actual:?}")),
+      (expected, actual) => panic!("interpreter returned {expected:?}, VM returned {actual:?}"),
+    }
+  }
+}
+
+#[test]
+fn vm_matches_interpreter_on_programs() {
+  for source in [
+    include_str!("../programs/menu.hd"),
+    include_str!("../programs/grön.hd"),
+    include_str!("../programs/starwars.hd"),
+  ] {
+    assert_same_outputs(&compile_script(source).unwrap(), "result", 500);
+  }
+}
+
+#[test]
+fn vm_matches_interpreter_on_fixtures() {
+  for source in [
+    include_str!("./hello_world.hd"),
+    include_str!("./hello_pattern.hd"),
+    include_str!("./function.hd"),
+    include_str!("./sticky.hd"),
+    include_str!("./sticky_with.hd"),
+    include_str!("./table_holes.hd"),
+    include_str!("./table_rows.hd"),
+    include_str!("./table_weight.hd"),
+    include_str!("./text_functions.hd"),
+    include_str!("./table_appends.hd"),
+  ] {
+    assert_same_outputs(&compile_script(source).unwrap(), "result", 200);
+  }
+}
+
+#[test]
+fn vm_matches_interpreter_on_errors() {
+  for source in [
+    r#"names = table [ [.name], ["Ada"] ]; title(t) = t.title; result = title(names);"#,
+    r#"title(t) = t.title; result = title("Ada");"#,
+    r#"name(t) = with r = row(t) in r.name; result = name("Ada");"#,
+    r#"names = table [ [.name], ["Ada"] ]; show(t) = { "a" t }; result = show(names);"#,
+    r#"names = table [ [.name], ["Ada"] ]; shout(t) = upper(t); result = shout(row(names));"#,
+    r#"names = table [ [.name], ["Ada"] ]; result = join(" ", bag ["a", "b"], names);"#,
+    r#"result = repeat(bag ["a", "b"], bag ["2", "x"]);"#,
+    r#"result = { "a" upper("b", "c") };"#,
+    r#"result = join();"#,
+    r#"f(x) = f(x); result = f("a");"#,
+    r#"f(x) = { "(" f({ x bag ["a", "b"] }) ")" }; result = f("a");"#,
+    r#"f(x) = with y = g(x) in y; g(x) = upper(f(x)); result = f("a");"#,
+  ] {
+    assert_same_outputs(&compile_script(source).unwrap(), "result", 20);
+  }
+}
+
+#[test]
+fn vm_passes_every_argument_to_text_functions() {
+  let parts = vec![r#"bag ["a", "b"]"#; 300].join(", ");
+  let source = format!(r#"result = join("", {parts}); long = upper({parts});"#);
+  let script = compile_library(&source).unwrap();
+
+  assert_same_outputs(&script, "result", 20);
+  assert_same_outputs(&script, "long", 1);
+}
+
+#[test]
+fn vm_matches_interpreter_on_library_entries() {
+  let script = compile_library(include_str!("./library.hd")).unwrap();
+
+  for entry in ["greeting", "missing"] {
+    assert_same_outputs(&script, entry, 50);
+  }
+}
+
+#[test]
+fn vm_reuses_output_buffer() {
+  let program = compile_script(r#"result = bag ["a", "b"];"#)
+    .unwrap()
+    .lower();
+  let mut rng = StdRng::seed_from_u64(0);
+  let mut output = String::new();
+
+  for _ in 0..10 {
+    program
+      .run_entry_into("result", &mut rng, &mut output)
+      .unwrap();
+  }
+
+  assert_eq!(output.len(), 10);
+}
+
+#[test]
+fn vm_handles_with_and_affixes() {
+  let script = compile_script(
+    r#"
+    names = table [ [.name, .title], ["ada", "Countess"], ["alan", ""] ];
+    result = with person = row(names), sticky x = bag ["p", "q"] in {
+      maybePrepend({ x "-" }, person.title) " " maybeAppend(person.name, "!") x capitalise(x)
+      " " capitalise(person.name)
+    };
+    "#,
+  )
+  .unwrap();
+
+  assert_same_outputs(&script, "result", 200);
+}
@@ -0,0 +1,87 @@
+use harald::{
+  compile_script,
+  eval::{CompilerError, ExecutionError},
+  run_script,
+};
+
+use matches::assert_matches;
+
+fn compiler_error(source: &str) -> CompilerError {
+  match compile_script(source) {
+    Err(ExecutionError::Compiler(err)) => err,
+    otherwise => panic!("Expected a compiler error, got {:?}", otherwise.err()),
+  }
+}
+
+#[test]
+fn table_all_rows_zero_weight() {
+  let err = compiler_error(include_str!("./weight_zero_table.hd"));
+  assert_matches!(
+    err,
+    CompilerError::ZeroWeightTable { in_variable, .. } if in_variable == "testTable"
+  );
+}
+
+#[test]
+fn table_column_zero_weight() {
+  let err = compiler_error(include_str!("./weight_zero_column.hd"));
+  assert_matches!(
+    err,
+    CompilerError::ZeroWeightTableColumn { column_name, in_variable, .. }
+      if column_name == "second" && in_variable == "testTable"
+  );
+}
+
+#[test]
+fn table_negative_row_weight() {
+  let err = compiler_error(include_str!("./weight_negative_row.hd"));
+  assert_matches!(
+    err,
+    CompilerError::InvalidRowWeight { weight, row_number: 1, in_variable, .. }
+      if weight == -1.5 && in_variable == "testTable"
+  );
+}
+
+#[test]
+fn bag_all_entries_zero_weight() {
+  let err = compiler_error(include_str!("./weight_zero_bag.hd"));
+  assert_matches!(
+    err,
+    CompilerError::ZeroWeightBag { in_variable, .. } if in_variable == "adjective"
+  );
+}
+
+#[test]
+fn bag_negative_weight() {
+  let err = compiler_error(include_str!("./weight_invalid_bag.hd"));
+  assert_matches!(
+    err,
+    CompilerError::InvalidBagWeight { weight, entry_number: 1, in_variable, .. }
+      if weight == -2.0 && in_variable == "adjective"
+  );
+}
+
+#[test]
+fn bag_weight_overflow() {
+  let err = compiler_error(include_str!("./weight_overflow.hd"));
+  assert_matches!(
+    err,
+    CompilerError::WeightOverflow { in_variable, .. } if in_variable == "adjective"
+  );
+}
+
+#[test]
+fn some_zero_weights_are_allowed() {
+  let output = run_script(r#"result = bag [0 "never", 1 "always"];"#).unwrap();
+  assert_eq!(output, "always");
+}
+
+#[test]
+fn weight_error_location() {
+  let source = include_str!("./weight_negative_row.hd");
+  let err = compile_script(source).unwrap_err();
+
+  assert!(err.render("weights.hd", source).starts_with(
+    "weights.hd:4:5: Compilation error: Invalid weight -1.5 on row 1 of table testTable"
+  ));
+}